@@ -24,6 +24,7 @@
                 };
                 toolchain = fenix.packages.${system}.complete.withComponents [
                     "cargo"
+                    "clippy"
                     "rustc"
                 ];
             in rec {
@@ -0,0 +1,13 @@
+#![no_main]
+#![feature(generic_const_exprs)]
+#![allow(incomplete_features)]
+
+// The game is a single binary with no library, so the fuzzer builds its source as a module
+#[path = "../../main.rs"]
+#[allow(dead_code)]
+mod game;
+
+// Every input is turned into valid generator parameters, anything check_floor rejects panics
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    game::fuzz_generate(data);
+});
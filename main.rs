@@ -1,24 +1,40 @@
 // Compile with:
-// rustc -C panic=abort -C link-arg=-nostartfiles main.rs
+// cargo build --release
+// or without cargo:
+// rustc -C panic=abort main.rs
+//
+// Tests (cargo test) and the fuzz target in fuzz/ build it with std instead, so the harness can
+// provide the entry point, the panic handler and the memory functions.
 
-#![feature(generic_const_exprs)]
-#![feature(lang_items)]
-#![no_std]
-#![no_main]
+// The fuzz target includes this file as a module, so it turns generic_const_exprs on itself
+#![cfg_attr(not(fuzzing), feature(generic_const_exprs))]
+#![cfg_attr(not(any(test, fuzzing)), feature(lang_items, link_arg_attribute))]
+#![cfg_attr(not(any(test, fuzzing)), no_std)]
+#![cfg_attr(not(any(test, fuzzing)), no_main)]
+#![allow(incomplete_features, internal_features)]
 
 use core::arch::asm;
+#[cfg(not(any(test, fuzzing)))]
 use core::panic::PanicInfo;
 
+// _start below is the entry point, so the C runtime's startup files are left out
+#[cfg(not(any(test, fuzzing)))]
+#[link(kind = "link-arg", name = "-nostartfiles", modifiers = "+verbatim")]
+unsafe extern "C" {}
+
+#[cfg(not(any(test, fuzzing)))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     loop {}
 }
 
+#[cfg(not(any(test, fuzzing)))]
 #[lang = "eh_personality"]
 extern "C" fn rust_eh_personality() {}
 
 const TCGETS: u64 = 0x5401;
 const TCSETS: u64 = 0x5402;
+const TIOCGWINSZ: u64 = 0x5413;
 const ECHO: u32 = 0o0000010;
 const ICANON: u32 = 0o0000002;
 const ISIG: u32 = 0o0000001;
@@ -29,11 +45,35 @@ const F_GETFL: i32 = 3;
 const F_SETFL: i32 = 4;
 const O_NONBLOCK: i32 = 0o4000;
 
+const O_RDONLY: i32 = 0;
+const O_WRONLY: i32 = 1;
+const O_RDWR: i32 = 2;
+const O_CREAT: i32 = 0o100;
+const O_TRUNC: i32 = 0o1000;
+const O_APPEND: i32 = 0o2000;
+const O_DIRECTORY: i32 = 0o200000;
+const ENOENT: i32 = 2;
+const EEXIST: i32 = 17;
+const EFBIG: i32 = 27;
+const ENAMETOOLONG: i32 = 36;
+
 const SYSCALL_READ: u64 = 0;
 const SYSCALL_WRITE: u64 = 1;
+const SYSCALL_OPEN: u64 = 2;
+const SYSCALL_CLOSE: u64 = 3;
+const SYSCALL_RT_SIGACTION: u64 = 13;
+const SYSCALL_RT_SIGRETURN: u64 = 15;
 const SYSCALL_IOCTL: u64 = 16;
+const SYSCALL_ALARM: u64 = 37;
 const SYSCALL_EXIT: u64 = 60;
 const SYSCALL_FCNTL: u64 = 72;
+const SYSCALL_FLOCK: u64 = 73;
+const SYSCALL_FSYNC: u64 = 74;
+const SYSCALL_FTRUNCATE: u64 = 77;
+const SYSCALL_RENAME: u64 = 82;
+const SYSCALL_MKDIR: u64 = 83;
+const SYSCALL_UNLINK: u64 = 87;
+const SYSCALL_GETDENTS64: u64 = 217;
 const SYSCALL_CLOCK_GETTIME: u64 = 228;
 
 const KEY_UP: u8 = 65;
@@ -42,13 +82,76 @@ const KEY_RIGHT: u8 = 67;
 const KEY_LEFT: u8 = 68;
 const KEY_QUIT: u8 = 113;
 const KEY_CONTINUE: u8 = 32;
-const ALL_KEYS: [u8; 6] = [KEY_UP, KEY_DOWN, KEY_RIGHT, KEY_LEFT, KEY_QUIT, KEY_CONTINUE];
+const KEY_PICKUP: u8 = 103;
+const KEY_DROP: u8 = 100;
+const KEY_USE: u8 = 117;
+const KEY_INVENTORY: u8 = 105;
+const KEY_EQUIP: u8 = 101;
+const KEY_REMOVE: u8 = 114;
+const KEY_CHARACTER: u8 = 99;
+const KEY_OPEN: u8 = 111;
+const KEY_CLOSE: u8 = 120;
+const KEY_SEARCH: u8 = 115;
+const KEY_DISARM: u8 = 116;
+const KEY_ABILITY: u8 = 97;
+const KEY_WAIT: u8 = 119;
+const KEY_HISTORY: u8 = 104;
+const KEY_MAP: u8 = 109;
+const KEY_ZOOM: u8 = 122;
+const ALL_KEYS: [u8; 21] = [
+    KEY_UP, KEY_DOWN, KEY_RIGHT, KEY_LEFT, KEY_QUIT, KEY_CONTINUE,
+    KEY_PICKUP, KEY_DROP, KEY_USE, KEY_INVENTORY, KEY_EQUIP, KEY_REMOVE, KEY_CHARACTER,
+    KEY_OPEN, KEY_CLOSE, KEY_SEARCH, KEY_DISARM, KEY_ABILITY, KEY_WAIT, KEY_HISTORY, KEY_MAP
+];
+// Level editor
+const KEY_PAINT: u8 = 32;
+const KEY_ERASE: u8 = 120;
+const KEY_NEXT_BRUSH: u8 = 110;
+const KEY_PREV_BRUSH: u8 = 112;
+const KEY_PEN: u8 = 108;
+const KEY_VALIDATE: u8 = 118;
+const KEY_PLAYTEST: u8 = 116;
+const KEY_SAVE: u8 = 115;
+const KEY_ESCAPE: u8 = 27;
+const KEY_ENTER: u8 = 13;
+const KEY_BACKSPACE: u8 = 127;
 
 const WALL_CHAR: char = '#';
 const PLAYER_CHAR: char = '@';
 const STAIRS_CHAR: char = 'S';
 const KEY_CHAR: char = 'K';
 const FLOOR_CHAR: char = '.';
+const GOLD_CHAR: char = '$';
+const WEAPON_CHAR: char = ')';
+const ARMOUR_CHAR: char = '[';
+const RING_CHAR: char = '=';
+const AMULET_CHAR: char = '"';
+const POTION_CHAR: char = '!';
+const SCROLL_CHAR: char = '?';
+const FOOD_CHAR: char = '%';
+const UNEXPLORED_CHAR: char = ' ';
+const DOOR_CLOSED_CHAR: char = '+';
+const DOOR_OPEN_CHAR: char = '\'';
+// Trapped tiles look like floor until the trap is revealed
+const TRAP_CHAR: char = '^';
+
+const NUM_LOCK_COLOURS: usize = 3;
+// Indexed by lock colour
+const LOCKED_DOOR_CHARS: [char; NUM_LOCK_COLOURS] = ['R', 'G', 'B'];
+const LOCK_COLOUR_NAMES: [&str; NUM_LOCK_COLOURS] = ["red", "green", "blue"];
+
+const SIGALRM: i32 = 14;
+// Required on x86-64, the kernel returns from a handler through sa_restorer
+const SA_RESTORER: u64 = 0x04000000;
+
+// The kernel's struct sigaction, which isn't laid out like libc's
+#[repr(C)]
+struct SigAction {
+    sa_handler: usize,
+    sa_flags: u64,
+    sa_restorer: usize,
+    sa_mask: u64,
+}
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -63,6 +166,15 @@ struct Termios {
     c_ospeed: u32,
 }
 
+// Only the row and column counts are used, the pixel sizes are there to match the kernel's layout
+#[allow(dead_code)]
+struct Winsize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16,
+}
+
 enum Result<T> {
     Ok(T),
     Err(i32), // Store the raw errno
@@ -82,7 +194,7 @@ fn exit(status: i32) -> ! {
     }
 }
 
-unsafe fn ioctl(fd: i32, op: u64, argp: *mut Termios) -> i32 {
+unsafe fn ioctl<T>(fd: i32, op: u64, argp: *mut T) -> i32 {
     let mut ret: i32;
     
     #[cfg(target_arch = "x86_64")]
@@ -166,425 +278,8241 @@ unsafe fn write(fd: i32, buf: *const u8, count: usize) -> i32 {
     ret
 }
 
-fn print(s: &str) {
-    unsafe {
-        write(STDOUT_FILENO, s.as_bytes().as_ptr(), s.len());
+// Paths must be null terminated
+unsafe fn open(path: *const u8, flags: i32, mode: u32) -> i32 {
+    let mut ret: i32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_OPEN,
+            in("rdi") path,
+            in("rsi") flags,
+            in("rdx") mode,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
-}
 
-fn print_char(c: char) {
-    let mut buf = [0u8; 4];
-    let s = c.encode_utf8(&mut buf);
-    print(s);
+    ret
 }
 
-fn set_non_blocking(fd: i32) -> Result<i32> {
-    unsafe {
-        // Get the current flags
-        let flags = fcntl(fd, F_GETFL, 0);
-        if flags < 0 {
-            return Result::Err(-flags);
-        }
-        
-        // Add O_NONBLOCK to current flags
-        let ret = fcntl(fd, F_SETFL, flags | O_NONBLOCK);
-        if ret < 0 {
-            return Result::Err(-ret);
-        }
-        
-        Result::Ok(flags)
-    }
-}
+unsafe fn close(fd: i32) -> i32 {
+    let mut ret: i32;
 
-fn set_blocking(fd: i32, orig_flags: i32) -> Result<()> {
-    unsafe {
-        // Restore the original flags
-        let ret = fcntl(fd, F_SETFL, orig_flags);
-        if ret < 0 {
-            return Result::Err(-ret);
-        }
-        
-        Result::Ok(())
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_CLOSE,
+            in("rdi") fd,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
+
+    ret
 }
 
-fn enable_raw_mode() -> Result<Termios> {
-    unsafe {
-        // Get the current terminal attributes
-        let mut orig_termios: Termios = core::mem::zeroed();
-        let ret = ioctl(STDIN_FILENO, TCGETS, &mut orig_termios);
-        if ret < 0 {
-            return Result::Err(-ret);
-        }
-        
-        let mut raw = orig_termios;
-        
-        // Disable ECHO, ICANON, and ISIG
-        raw.c_lflag &= !(ECHO | ICANON | ISIG);
-        let ret = ioctl(STDIN_FILENO, TCSETS, &mut raw);
-        if ret < 0 {
-            return Result::Err(-ret);
-        }
-        
-        Result::Ok(orig_termios)
+unsafe fn fsync(fd: i32) -> i32 {
+    let mut ret: i32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_FSYNC,
+            in("rdi") fd,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
+
+    ret
 }
 
-fn disable_raw_mode(orig_termios: &mut Termios) -> Result<()> {
-    unsafe {
-        let ret = ioctl(STDIN_FILENO, TCSETS, orig_termios);
-        if ret < 0 {
-            return Result::Err(-ret);
-        }
-        
-        Result::Ok(())
+unsafe fn ftruncate(fd: i32, len: u64) -> i32 {
+    let mut ret: i32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_FTRUNCATE,
+            in("rdi") fd,
+            in("rsi") len,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
+
+    ret
 }
 
-fn get_input() -> u8 {
-    let mut buf = [0u8; 1];
-    unsafe {
-        read(STDIN_FILENO, buf.as_mut_ptr(), 1);
+unsafe fn rename(old_path: *const u8, new_path: *const u8) -> i32 {
+    let mut ret: i32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_RENAME,
+            in("rdi") old_path,
+            in("rsi") new_path,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
 
-    buf[0]
+    ret
 }
 
-struct XorshiftRng {
-    state: u64
-}
+unsafe fn mkdir(path: *const u8, mode: u32) -> i32 {
+    let mut ret: i32;
 
-impl XorshiftRng {
-    fn new(seed: u64) -> Self {
-        XorshiftRng { state: seed }
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_MKDIR,
+            in("rdi") path,
+            in("rsi") mode,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
 
-    fn next(&mut self) -> u64 {
-        let mut x = self.state;
-    	x ^= x << 13;
-	    x ^= x >> 17;
-	    x ^= x << 5;
-        self.state = x;
-        self.state
-    }
+    ret
+}
 
-    fn range(&mut self, max: u64) -> u64 {
-        self.next() % max
-    }
+unsafe fn flock(fd: i32, operation: i32) -> i32 {
+    let mut ret: i32;
 
-    fn range_with_min(&mut self, min: u64, max: u64) -> u64 {
-        if min < max {
-            self.range(max - min) + min
-        } else {
-            self.range(max)
-        }
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_FLOCK,
+            in("rdi") fd,
+            in("rsi") operation,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
-}
 
-fn random_symbol(rng: &mut XorshiftRng) -> char {
-    let symbols = ['@', '#', '%', '&', '*', '+', '=', '_'];
-    let idx = rng.range(symbols.len() as u64) as usize;
-    symbols[idx]
+    ret
 }
 
-fn draw_level_into_window(
-    window: &mut [char], level: &[char], 
-    player_x: usize, player_y: usize, 
-    window_width: usize, window_height: usize,
-    level_width: usize, level_height: usize
-) {
-    let level_x_start: usize = player_x - (window_width / 2);
-    let level_y_start: usize = player_y - (window_height / 2);
-    for y in 0..window_height {
-        let window_row_start: usize = y * window_width;
-        let level_row_start: usize = (level_y_start + y) * level_width;
-        for x in 0..window_width {
-            if level_y_start + y == player_y && level_x_start + x == player_x {
-                window[window_row_start + x] = '@';
-            } else { 
-                window[window_row_start + x] = level[level_row_start + level_x_start + x];
-            }
-        }
+unsafe fn unlink(path: *const u8) -> i32 {
+    let mut ret: i32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_UNLINK,
+            in("rdi") path,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
+
+    ret
 }
 
-fn get_time() -> (usize, usize) {
-    let mut timespec = [0u64; 2];
+unsafe fn rt_sigaction(signal: i32, action: *const SigAction, old_action: *mut SigAction) -> i32 {
+    let mut ret: i32;
 
-    unsafe {
-        #[cfg(target_arch = "x86_64")]
-        {
-            asm!(
-                "mov rdi, 0",
-                "syscall",
-                in("rax") SYSCALL_CLOCK_GETTIME,
-                in("rsi") timespec.as_mut_ptr(),
-                lateout("rax") _,
-                out("rdi") _,
-            );
-        }
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_RT_SIGACTION,
+            in("rdi") signal,
+            in("rsi") action,
+            in("rdx") old_action,
+            in("r10") core::mem::size_of::<u64>(),
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
 
-    (timespec[0] as usize, timespec[1] as usize) 
+    ret
 }
 
-fn ns_to_ms(time_ns: usize) -> usize {
-    time_ns / 1000000
+// Handlers installed with rt_sigaction return through this
+#[unsafe(naked)]
+extern "C" fn signal_restorer() -> ! {
+    core::arch::naked_asm!(
+        "mov rax, {rt_sigreturn}",
+        "syscall",
+        rt_sigreturn = const SYSCALL_RT_SIGRETURN,
+    )
 }
 
-fn get_time_diff_ms(first_sec: usize, first_ns: usize, second_sec: usize, second_ns: usize) -> usize {
-    const MS_PER_SEC: usize = 1000;
-    if second_sec == first_sec && second_ns > first_ns {
-        ns_to_ms(second_ns - first_ns)
-    } else if second_sec > first_sec {
-        (second_sec - first_sec - 1) * MS_PER_SEC + ns_to_ms(second_ns) + (MS_PER_SEC - ns_to_ms(first_ns))
-    } else {
-        0
+// Sends SIGALRM after the given number of seconds, 0 cancels the last alarm
+unsafe fn alarm(seconds: u32) -> u32 {
+    let mut ret: u32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_ALARM,
+            in("rdi") seconds,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
     }
-}
 
-fn clear_screen() {
-    print("\x1B[2J\x1B[1;1H");
+    ret
+}
+
+// Fills buf with linux_dirent64 records, returns the number of bytes used or 0 at the end
+unsafe fn getdents64(fd: i32, buf: *mut u8, count: usize) -> i32 {
+    let mut ret: i32;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        asm!(
+            "syscall",
+            in("rax") SYSCALL_GETDENTS64,
+            in("rdi") fd,
+            in("rsi") buf,
+            in("rdx") count,
+            out("rcx") _,
+            out("r11") _,
+            lateout("rax") ret,
+            options(nostack)
+        );
+    }
+
+    ret
+}
+
+fn print(s: &str) {
+    unsafe {
+        if OUTPUT_MUTED {
+            return;
+        }
+        write(STDOUT_FILENO, s.as_bytes().as_ptr(), s.len());
+    }
+}
+
+fn print_char(c: char) {
+    let mut buf = [0u8; 4];
+    let s = c.encode_utf8(&mut buf);
+    print(s);
+}
+
+fn print_number(n: u64) {
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+    let mut n = n;
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    print(unsafe { core::str::from_utf8_unchecked(&buf[i..]) });
+}
+
+const MESSAGE_LINE_CAPACITY: usize = 256;
+// Room for a long message from every monster in a round, the turn's messages are logged after 
+// the player's action and after every round of monsters so they never fill up
+const TURN_MESSAGES_CAPACITY: usize = MAX_MONSTERS * 128;
+
+// Fixed size text buffer used to build messages that contain numbers or item names
+#[derive(Clone, Copy)]
+struct TextBuffer<const CAPACITY: usize> {
+    buf: [u8; CAPACITY],
+    len: usize,
+}
+
+type MessageLine = TextBuffer<MESSAGE_LINE_CAPACITY>;
+// What happened during a turn, it goes into the message log a sentence at a time
+type TurnMessages = TextBuffer<TURN_MESSAGES_CAPACITY>;
+
+impl<const CAPACITY: usize> TextBuffer<CAPACITY> {
+    fn new() -> Self {
+        TextBuffer { buf: [0u8; CAPACITY], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push_char(&mut self, c: char) {
+        let mut char_buf = [0u8; 4];
+        let encoded = c.encode_utf8(&mut char_buf).as_bytes();
+        // Drop anything that doesn't fit rather than splitting a character
+        if self.len + encoded.len() <= CAPACITY {
+            for b in encoded.iter() {
+                self.buf[self.len] = *b;
+                self.len += 1;
+            }
+        }
+    }
+
+    fn push(&mut self, s: &str) {
+        for c in s.chars() {
+            self.push_char(c);
+        }
+    }
+
+    fn push_number(&mut self, n: u64) {
+        let mut digits = [0u8; 20];
+        let mut i = digits.len();
+        let mut n = n;
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        for d in digits[i..].iter() {
+            self.push_char(*d as char);
+        }
+    }
+
+    // Always prints the sign, used for stat bonuses
+    fn push_signed(&mut self, n: i64) {
+        if n < 0 {
+            self.push_char('-');
+        } else {
+            self.push_char('+');
+        }
+        self.push_number(n.unsigned_abs());
+    }
+
+    fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+const MESSAGE_LOG_CAPACITY: usize = 100;
+const LOG_ENTRY_CAPACITY: usize = 96;
+// Lines reserved for messages below the map
+const MESSAGE_AREA_LINES: usize = 3;
+const HISTORY_PAGE_LINES: usize = 20;
+
+#[derive(Clone, Copy)]
+struct LogEntry {
+    text: [u8; LOG_ENTRY_CAPACITY],
+    len: usize,
+    // Turn of the most recent repeat
+    turn: u64,
+    // How many times in a row the message came up
+    count: u32,
+}
+
+impl LogEntry {
+    fn text(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.text[..self.len]) }
+    }
+
+    // Messages that only differ in their numbers, like damage rolls, count as repeats
+    fn matches(&self, text: &[u8]) -> bool {
+        let ours = &self.text[..self.len];
+        let mut i = 0;
+        let mut j = 0;
+        while i < ours.len() && j < text.len() {
+            if ours[i].is_ascii_digit() && text[j].is_ascii_digit() {
+                while i < ours.len() && ours[i].is_ascii_digit() {
+                    i += 1;
+                }
+                while j < text.len() && text[j].is_ascii_digit() {
+                    j += 1;
+                }
+            } else if ours[i] == text[j] {
+                i += 1;
+                j += 1;
+            } else {
+                return false;
+            }
+        }
+        i == ours.len() && j == text.len()
+    }
+
+    fn set_text(&mut self, text: &[u8]) {
+        self.text[..text.len()].copy_from_slice(text);
+        self.len = text.len();
+    }
+
+    // Writes the message with its repeat count, e.g. "The poison burns. x3". Repeats keep the 
+    // numbers of the latest one.
+    fn format(&self, line: &mut MessageLine) {
+        line.push(self.text());
+        if self.count > 1 {
+            line.push(" x");
+            line.push_number(self.count as u64);
+        }
+    }
+}
+
+// Ring buffer of the most recent messages, the oldest ones are overwritten when it fills up
+struct MessageLog {
+    entries: [LogEntry; MESSAGE_LOG_CAPACITY],
+    // Index of the oldest entry
+    start: usize,
+    len: usize,
+    // Messages logged since the message area was last drawn
+    unseen: usize,
+}
+
+impl MessageLog {
+    fn new() -> Self {
+        let empty = LogEntry { text: [0u8; LOG_ENTRY_CAPACITY], len: 0, turn: 0, count: 0 };
+        MessageLog { entries: [empty; MESSAGE_LOG_CAPACITY], start: 0, len: 0, unseen: 0 }
+    }
+
+    // Entries are numbered from the oldest
+    fn get(&self, index: usize) -> &LogEntry {
+        &self.entries[(self.start + index) % MESSAGE_LOG_CAPACITY]
+    }
+
+    // Adds a single message, repeating the last message bumps its count instead
+    fn add(&mut self, text: &[u8], turn: u64) {
+        if text.is_empty() {
+            return;
+        }
+        let text = &text[..core::cmp::min(text.len(), LOG_ENTRY_CAPACITY)];
+        if self.len > 0 {
+            let last = (self.start + self.len - 1) % MESSAGE_LOG_CAPACITY;
+            if self.entries[last].matches(text) {
+                self.entries[last].set_text(text);
+                self.entries[last].count += 1;
+                self.entries[last].turn = turn;
+                // A repeat of something already seen needs showing again
+                if self.unseen == 0 {
+                    self.unseen = 1;
+                }
+                return;
+            }
+        }
+        let index = if self.len < MESSAGE_LOG_CAPACITY {
+            self.len += 1;
+            (self.start + self.len - 1) % MESSAGE_LOG_CAPACITY
+        } else {
+            let oldest = self.start;
+            self.start = (self.start + 1) % MESSAGE_LOG_CAPACITY;
+            oldest
+        };
+        let entry = &mut self.entries[index];
+        entry.set_text(text);
+        entry.turn = turn;
+        entry.count = 1;
+        self.unseen = core::cmp::min(self.unseen + 1, self.len);
+    }
+
+    // Logs what has happened so far this turn and empties the buffer for the rest of it
+    fn flush(&mut self, messages: &mut TurnMessages, turn: u64) {
+        self.add_sentences(messages.as_str(), turn);
+        messages.clear();
+    }
+
+    // Splits text built up during a turn into sentences and logs each one. Sentences end with 
+    // '.', '!' or '?' followed by a space.
+    fn add_sentences(&mut self, text: &str, turn: u64) {
+        let bytes = text.as_bytes();
+        let mut sentence_start = 0;
+        for i in 0..bytes.len() {
+            let ends_sentence = (bytes[i] == b'.' || bytes[i] == b'!' || bytes[i] == b'?')
+                && (i + 1 == bytes.len() || bytes[i + 1] == b' ');
+            if ends_sentence {
+                self.add(&bytes[sentence_start..i + 1], turn);
+                sentence_start = i + 2;
+            }
+        }
+        if sentence_start < bytes.len() {
+            self.add(&bytes[sentence_start..], turn);
+        }
+    }
+}
+
+fn set_non_blocking(fd: i32) -> Result<i32> {
+    unsafe {
+        // Get the current flags
+        let flags = fcntl(fd, F_GETFL, 0);
+        if flags < 0 {
+            return Result::Err(-flags);
+        }
+        
+        // Add O_NONBLOCK to current flags
+        let ret = fcntl(fd, F_SETFL, flags | O_NONBLOCK);
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        
+        Result::Ok(flags)
+    }
+}
+
+fn set_blocking(fd: i32, orig_flags: i32) -> Result<()> {
+    unsafe {
+        // Restore the original flags
+        let ret = fcntl(fd, F_SETFL, orig_flags);
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        
+        Result::Ok(())
+    }
+}
+
+fn enable_raw_mode() -> Result<Termios> {
+    unsafe {
+        // Get the current terminal attributes
+        let mut orig_termios: Termios = core::mem::zeroed();
+        let ret = ioctl(STDIN_FILENO, TCGETS, &mut orig_termios);
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        
+        let mut raw = orig_termios;
+        
+        // Disable ECHO, ICANON, and ISIG
+        raw.c_lflag &= !(ECHO | ICANON | ISIG);
+        let ret = ioctl(STDIN_FILENO, TCSETS, &mut raw);
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        
+        Result::Ok(orig_termios)
+    }
+}
+
+fn disable_raw_mode(orig_termios: &mut Termios) -> Result<()> {
+    unsafe {
+        let ret = ioctl(STDIN_FILENO, TCSETS, orig_termios);
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        
+        Result::Ok(())
+    }
+}
+
+// Returns the terminal's (columns, rows), falling back to 80x24 when it can't be queried
+fn terminal_size() -> (usize, usize) {
+    let mut winsize = Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    let ret = unsafe { ioctl(STDOUT_FILENO, TIOCGWINSZ, &mut winsize) };
+    if ret < 0 || winsize.ws_col == 0 || winsize.ws_row == 0 {
+        (80, 24)
+    } else {
+        (winsize.ws_col as usize, winsize.ws_row as usize)
+    }
+}
+
+// Command line arguments and environment variables passed in by the kernel
+struct Env {
+    argc: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+}
+
+impl Env {
+    // The stack starts with argc, then the argv pointers, a null, and the envp pointers
+    #[cfg(not(any(test, fuzzing)))]
+    unsafe fn from_stack(stack: *const usize) -> Self {
+        let argc = *stack;
+        let argv = stack.add(1) as *const *const u8;
+        Env { argc, argv, envp: argv.add(argc + 1) }
+    }
+
+    fn arg(&self, index: usize) -> Option<&'static [u8]> {
+        if index >= self.argc {
+            return None;
+        }
+        unsafe { Some(c_str(*self.argv.add(index))) }
+    }
+
+    fn var(&self, name: &str) -> Option<&'static [u8]> {
+        let name = name.as_bytes();
+        let mut i = 0;
+        loop {
+            let entry = unsafe { *self.envp.add(i) };
+            if entry.is_null() {
+                return None;
+            }
+            let entry = unsafe { c_str(entry) };
+            if entry.len() > name.len() && entry[name.len()] == b'=' && bytes_equal(&entry[..name.len()], name) {
+                return Some(&entry[name.len() + 1..]);
+            }
+            i += 1;
+        }
+    }
+}
+
+unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::slice::from_raw_parts(ptr, len)
+}
+
+fn bytes_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    for i in 0..a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+    }
+    true
+}
+
+fn get_input() -> u8 {
+    let input = unsafe { &mut *core::ptr::addr_of_mut!(INPUT) };
+    if input.replay_fd >= 0 {
+        return next_replay_key(input);
+    }
+    if input.bot {
+        return next_bot_key(input);
+    }
+
+    let mut buf = [0u8; 1];
+    unsafe {
+        read(STDIN_FILENO, buf.as_mut_ptr(), 1);
+    }
+    if buf[0] != 0 {
+        record_key(buf[0]);
+    }
+
+    buf[0]
+}
+
+struct XorshiftRng {
+    state: u64
+}
+
+impl XorshiftRng {
+    fn new(seed: u64) -> Self {
+        XorshiftRng { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.state;
+    	x ^= x << 13;
+	    x ^= x >> 17;
+	    x ^= x << 5;
+        self.state = x;
+        self.state
+    }
+
+    fn range(&mut self, max: u64) -> u64 {
+        self.next() % max
+    }
+
+    // An empty range gives min, the generator asks for one when its minimum and maximum match
+    fn range_with_min(&mut self, min: u64, max: u64) -> u64 {
+        if min < max {
+            self.range(max - min) + min
+        } else {
+            min
+        }
+    }
+}
+
+const INVENTORY_CAPACITY: usize = 10;
+const MAX_FLOOR_ITEMS: usize = 64;
+
+const ITEM_FLAG_STACKABLE: u8 = 1 << 0;
+const ITEM_FLAG_QUEST: u8 = 1 << 1;
+// Potions and scrolls are disguised behind a random appearance until identified
+const ITEM_FLAG_POTION: u8 = 1 << 2;
+const ITEM_FLAG_SCROLL: u8 = 1 << 3;
+
+const NUM_EQUIP_SLOTS: usize = 5;
+
+#[derive(Copy, Clone, PartialEq)]
+enum EquipSlot {
+    Weapon,
+    Body,
+    Head,
+    Ring,
+    Amulet,
+}
+
+// Indexed by EquipSlot
+const EQUIP_SLOT_NAMES: [&str; NUM_EQUIP_SLOTS] = ["Weapon", "Body", "Head", "Ring", "Amulet"];
+
+const EQUIP_SLOTS: [EquipSlot; NUM_EQUIP_SLOTS] = [
+    EquipSlot::Weapon, EquipSlot::Body, EquipSlot::Head, EquipSlot::Ring, EquipSlot::Amulet
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum ItemKind {
+    StairsKey,
+    Gold,
+    Dagger,
+    Sword,
+    Axe,
+    LeatherArmour,
+    ChainMail,
+    Helmet,
+    Ring,
+    Amulet,
+    PotionHealing,
+    PotionStrength,
+    PotionToughness,
+    ScrollMapping,
+    ScrollTeleport,
+    ScrollIdentify,
+    Ration,
+    PotionHaste,
+    PotionRegeneration,
+    PotionConfusion,
+    PotionBlindness,
+    ScrollConfuseMonsters,
+    RedKey,
+    GreenKey,
+    BlueKey,
+}
+
+const NUM_ITEM_KINDS: usize = 25;
+const ITEM_KINDS: [ItemKind; NUM_ITEM_KINDS] = [
+    ItemKind::StairsKey, ItemKind::Gold, ItemKind::Dagger, ItemKind::Sword, ItemKind::Axe,
+    ItemKind::LeatherArmour, ItemKind::ChainMail, ItemKind::Helmet, ItemKind::Ring, ItemKind::Amulet,
+    ItemKind::PotionHealing, ItemKind::PotionStrength, ItemKind::PotionToughness,
+    ItemKind::ScrollMapping, ItemKind::ScrollTeleport, ItemKind::ScrollIdentify, ItemKind::Ration,
+    ItemKind::PotionHaste, ItemKind::PotionRegeneration, ItemKind::PotionConfusion, ItemKind::PotionBlindness,
+    ItemKind::ScrollConfuseMonsters, ItemKind::RedKey, ItemKind::GreenKey, ItemKind::BlueKey,
+];
+
+// Indexed by lock colour
+const COLOURED_KEY_KINDS: [ItemKind; NUM_LOCK_COLOURS] = [ItemKind::RedKey, ItemKind::GreenKey, ItemKind::BlueKey];
+
+const CONSUMABLE_KINDS: [ItemKind; 12] = [
+    ItemKind::PotionHealing, ItemKind::PotionStrength, ItemKind::PotionToughness,
+    ItemKind::PotionHaste, ItemKind::PotionRegeneration, ItemKind::PotionConfusion,
+    ItemKind::PotionBlindness, ItemKind::ScrollMapping, ItemKind::ScrollTeleport,
+    ItemKind::ScrollIdentify, ItemKind::ScrollConfuseMonsters, ItemKind::Ration
+];
+
+const GEAR_KINDS: [ItemKind; 8] = [
+    ItemKind::Dagger, ItemKind::Sword, ItemKind::Axe,
+    ItemKind::LeatherArmour, ItemKind::ChainMail, ItemKind::Helmet,
+    ItemKind::Ring, ItemKind::Amulet
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum Colour {
+    Default,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    Grey,
+}
+
+const COLOURS: [Colour; 9] = [
+    Colour::Default, Colour::Red, Colour::Green, Colour::Yellow, Colour::Blue,
+    Colour::Magenta, Colour::Cyan, Colour::White, Colour::Grey
+];
+
+// Indexed by Colour
+const COLOUR_NAMES: [&str; 9] = ["default", "red", "green", "yellow", "blue", "magenta", "cyan", "white", "grey"];
+const COLOUR_ESCAPES: [&str; 9] = [
+    "\x1B[0m", "\x1B[31m", "\x1B[32m", "\x1B[33m", "\x1B[34m", "\x1B[35m", "\x1B[36m", "\x1B[37m", "\x1B[90m"
+];
+
+// Read from defs.txt and mods at startup, see load_defs
+struct ItemDef {
+    name: &'static str,
+    glyph: char,
+    colour: Colour,
+    flags: u8,
+    slot: Option<EquipSlot>,
+    attack: i32,
+    defense: i32,
+    // Floors the item can be generated on, a max_depth of 0 means there's no limit
+    min_depth: u64,
+    max_depth: u64,
+    // How often random loot picks it over the other gear or consumables
+    weight: u32,
+    // Scripts run after the item is used or picked up
+    on_use: Option<usize>,
+    on_pickup: Option<usize>,
+}
+
+const NO_ITEM_DEF: ItemDef = ItemDef {
+    name: "", glyph: UNEXPLORED_CHAR, colour: Colour::Default, flags: 0, slot: None,
+    attack: 0, defense: 0, min_depth: 1, max_depth: 0, weight: DEFAULT_WEIGHT, on_use: None, on_pickup: None
+};
+
+// Section ids in definition files, indexed by ItemKind
+const ITEM_IDS: [&str; NUM_ITEM_KINDS] = [
+    "stairs_key", "gold", "dagger", "sword", "axe", "leather_armour", "chain_mail", "helmet", "ring", "amulet",
+    "potion_healing", "potion_strength", "potion_toughness", "scroll_mapping", "scroll_teleport",
+    "scroll_identify", "ration", "potion_haste", "potion_regeneration", "potion_confusion",
+    "potion_blindness", "scroll_confuse_monsters", "red_key", "green_key", "blue_key",
+];
+
+fn item_def(kind: ItemKind) -> &'static ItemDef {
+    &defs().items[kind as usize]
+}
+
+fn lock_colour(tile: char) -> Option<usize> {
+    LOCKED_DOOR_CHARS.iter().position(|door| *door == tile)
+}
+
+const POTION_COLOURS: [&str; 7] = ["red", "blue", "green", "murky", "golden", "violet", "pink"];
+const SCROLL_LABELS: [&str; 6] = ["ZELGO MER", "FOOBIE BLETCH", "XIXAXA XOXAXA", "ELAM EBOW", "VERR YED HORRE", "KIRJE"];
+
+// What the player knows about potions and scrolls. Appearances are shuffled once per run so the
+// same colour means something different every game.
+struct Identification {
+    // Index into POTION_COLOURS or SCROLL_LABELS, indexed by ItemKind
+    appearance: [u8; NUM_ITEM_KINDS],
+    known: [bool; NUM_ITEM_KINDS],
+}
+
+impl Identification {
+    fn new(seed: u64) -> Self {
+        let mut rng = XorshiftRng::new(seed ^ 0xD1B54A32D192ED03);
+        let mut potion_order = [0u8; POTION_COLOURS.len()];
+        for (i, order) in potion_order.iter_mut().enumerate() {
+            *order = i as u8;
+        }
+        let mut scroll_order = [0u8; SCROLL_LABELS.len()];
+        for (i, order) in scroll_order.iter_mut().enumerate() {
+            *order = i as u8;
+        }
+        shuffle(&mut rng, &mut potion_order);
+        shuffle(&mut rng, &mut scroll_order);
+
+        let mut appearance = [0u8; NUM_ITEM_KINDS];
+        let mut next_potion = 0;
+        let mut next_scroll = 0;
+        for (i, def) in defs().items.iter().enumerate() {
+            if def.flags & ITEM_FLAG_POTION != 0 {
+                appearance[i] = potion_order[next_potion];
+                next_potion += 1;
+            } else if def.flags & ITEM_FLAG_SCROLL != 0 {
+                appearance[i] = scroll_order[next_scroll];
+                next_scroll += 1;
+            }
+        }
+        Identification { appearance, known: [false; NUM_ITEM_KINDS] }
+    }
+
+    fn is_known(&self, kind: ItemKind) -> bool {
+        let flags = item_def(kind).flags;
+        flags & (ITEM_FLAG_POTION | ITEM_FLAG_SCROLL) == 0 || self.known[kind as usize]
+    }
+
+    fn identify(&mut self, kind: ItemKind) {
+        self.known[kind as usize] = true;
+    }
+}
+
+fn shuffle(rng: &mut XorshiftRng, values: &mut [u8]) {
+    let mut i = values.len();
+    while i > 1 {
+        let j = rng.range(i as u64) as usize;
+        i -= 1;
+        values.swap(i, j);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Affix {
+    None,
+    Might,
+    Protection,
+    Vitality,
+}
+
+const AFFIXES: [Affix; 3] = [Affix::Might, Affix::Protection, Affix::Vitality];
+
+// Indexed by Affix
+const AFFIX_NAMES: [&str; 4] = ["", "might", "protection", "vitality"];
+
+const MAX_ENCHANT: u64 = 9;
+const VITALITY_HP_PER_POWER: i32 = 3;
+
+#[derive(Copy, Clone)]
+struct StatBonus {
+    attack: i32,
+    defense: i32,
+    max_hp: i32,
+}
+
+impl StatBonus {
+    fn none() -> Self {
+        StatBonus { attack: 0, defense: 0, max_hp: 0 }
+    }
+
+    fn add(&mut self, other: StatBonus) {
+        self.attack += other.attack;
+        self.defense += other.defense;
+        self.max_hp += other.max_hp;
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Item {
+    kind: ItemKind,
+    count: u16,
+    enchant: u8,
+    affix: Affix,
+    affix_power: u8,
+}
+
+impl Item {
+    fn new(kind: ItemKind, count: u16) -> Self {
+        Item { kind, count, enchant: 0, affix: Affix::None, affix_power: 0 }
+    }
+
+    fn def(&self) -> &'static ItemDef {
+        item_def(self.kind)
+    }
+
+    fn name(&self) -> &'static str {
+        self.def().name
+    }
+
+    fn glyph(&self) -> char {
+        self.def().glyph
+    }
+
+    fn has_flag(&self, flag: u8) -> bool {
+        self.def().flags & flag != 0
+    }
+
+    fn slot(&self) -> Option<EquipSlot> {
+        self.def().slot
+    }
+
+    // Stats the item grants while equipped
+    fn bonus(&self) -> StatBonus {
+        let def = self.def();
+        let mut bonus = StatBonus { attack: def.attack, defense: def.defense, max_hp: 0 };
+        match def.slot {
+            Some(EquipSlot::Weapon) => bonus.attack += self.enchant as i32,
+            Some(EquipSlot::Body) | Some(EquipSlot::Head) => bonus.defense += self.enchant as i32,
+            _ => (),
+        }
+        let power = self.affix_power as i32;
+        match self.affix {
+            Affix::Might => bonus.attack += power,
+            Affix::Protection => bonus.defense += power,
+            Affix::Vitality => bonus.max_hp += power * VITALITY_HP_PER_POWER,
+            Affix::None => (),
+        }
+        bonus
+    }
+}
+
+// Weight of something that can only be generated between min_depth and max_depth
+fn depth_weight(min_depth: u64, max_depth: u64, weight: u32, depth: u64) -> u32 {
+    if depth < min_depth || (max_depth != 0 && depth > max_depth) {
+        0
+    } else {
+        weight
+    }
+}
+
+// Picks an index with a chance proportional to its weight, or None if every weight is 0. Picks are
+// uniform and then kept with a chance of weight / max weight, so when the weights are all equal it
+// uses the same random numbers a plain uniform pick would.
+fn pick_weighted(rng: &mut XorshiftRng, weights: &[u32]) -> Option<usize> {
+    let max_weight = weights.iter().copied().max().unwrap_or(0);
+    if max_weight == 0 {
+        return None;
+    }
+    loop {
+        let i = rng.range(weights.len() as u64) as usize;
+        let weight = weights[i];
+        if weight == max_weight || (weight > 0 && rng.range(max_weight as u64) < weight as u64) {
+            return Some(i);
+        }
+    }
+}
+
+fn roll_item_kind(rng: &mut XorshiftRng, kinds: &[ItemKind], depth: u64) -> Option<ItemKind> {
+    let mut weights = [0u32; NUM_ITEM_KINDS];
+    for (i, kind) in kinds.iter().enumerate() {
+        let def = item_def(*kind);
+        weights[i] = depth_weight(def.min_depth, def.max_depth, def.weight, depth);
+    }
+    let index = pick_weighted(rng, &weights[..kinds.len()])?;
+    Some(kinds[index])
+}
+
+fn roll_consumable(rng: &mut XorshiftRng, depth: u64) -> Option<Item> {
+    let kind = roll_item_kind(rng, &CONSUMABLE_KINDS, depth)?;
+    Some(Item::new(kind, 1))
+}
+
+// Picks a random item drawn with the glyph for hand drawn maps. The designer chose to put it 
+// there, so unlike random loot it ignores how deep the floor is. Quest items are never picked.
+fn random_item_with_glyph(rng: &mut XorshiftRng, glyph: char) -> Option<Item> {
+    let mut matches = [ItemKind::Gold; NUM_ITEM_KINDS];
+    let mut num_matches = 0;
+    for kind in ITEM_KINDS.iter() {
+        let def = item_def(*kind);
+        if def.glyph == glyph && def.flags & ITEM_FLAG_QUEST == 0 {
+            matches[num_matches] = *kind;
+            num_matches += 1;
+        }
+    }
+    if num_matches == 0 {
+        return None;
+    }
+    let kind = matches[rng.range(num_matches as u64) as usize];
+    let count = if kind == ItemKind::Gold { rng.range_with_min(5, 30) as u16 } else { 1 };
+    Some(Item::new(kind, count))
+}
+
+// Rolls a random piece of gear, deeper floors give better enchantments and affixes
+fn roll_gear(rng: &mut XorshiftRng, depth: u64) -> Option<Item> {
+    let kind = roll_item_kind(rng, &GEAR_KINDS, depth)?;
+    let mut item = Item::new(kind, 1);
+    let is_jewellery = kind == ItemKind::Ring || kind == ItemKind::Amulet;
+    if !is_jewellery {
+        let max_enchant = core::cmp::min(depth / 2, MAX_ENCHANT);
+        item.enchant = rng.range(max_enchant + 1) as u8;
+    }
+    // Jewellery is useless without an affix so it always gets one
+    if is_jewellery || rng.range(4) == 0 {
+        item.affix = AFFIXES[rng.range(AFFIXES.len() as u64) as usize];
+        item.affix_power = (1 + rng.range(core::cmp::min(depth / 2, MAX_ENCHANT) + 1)) as u8;
+    }
+    Some(item)
+}
+
+fn format_item<const CAPACITY: usize>(item: &Item, identification: &Identification, line: &mut TextBuffer<CAPACITY>) {
+    if item.enchant > 0 {
+        line.push_signed(item.enchant as i64);
+        line.push(" ");
+    }
+    if identification.is_known(item.kind) {
+        line.push(item.name());
+    } else if item.has_flag(ITEM_FLAG_POTION) {
+        line.push(POTION_COLOURS[identification.appearance[item.kind as usize] as usize]);
+        line.push(" potion");
+    } else {
+        line.push("scroll labelled ");
+        line.push(SCROLL_LABELS[identification.appearance[item.kind as usize] as usize]);
+    }
+    if item.affix != Affix::None {
+        line.push(" of ");
+        line.push(AFFIX_NAMES[item.affix as usize]);
+    }
+    if item.count > 1 {
+        line.push(" (x");
+        line.push_number(item.count as u64);
+        line.push(")");
+    }
+}
+
+fn format_bonus(bonus: &StatBonus, line: &mut MessageLine) {
+    let mut first = true;
+    let stats = [("atk", bonus.attack), ("def", bonus.defense), ("hp", bonus.max_hp)];
+    for (name, value) in stats.iter() {
+        if *value != 0 {
+            if !first {
+                line.push(" ");
+            }
+            line.push(name);
+            line.push(" ");
+            line.push_signed(*value as i64);
+            first = false;
+        }
+    }
+}
+
+fn print_item(item: &Item, identification: &Identification) {
+    let mut line = MessageLine::new();
+    format_item(item, identification, &mut line);
+    print(line.as_str());
+}
+
+#[derive(Copy, Clone)]
+struct FloorItem {
+    x: u64,
+    y: u64,
+    item: Item,
+}
+
+struct Inventory {
+    slots: [Option<Item>; INVENTORY_CAPACITY],
+}
+
+impl Inventory {
+    fn new() -> Self {
+        Inventory { slots: [None; INVENTORY_CAPACITY] }
+    }
+
+    fn len(&self) -> usize {
+        let mut len = 0;
+        for slot in self.slots.iter() {
+            if slot.is_some() {
+                len += 1;
+            }
+        }
+        len
+    }
+
+    // Returns false if there is no room for the item
+    fn add(&mut self, item: Item) -> bool {
+        if item.has_flag(ITEM_FLAG_STACKABLE) {
+            for existing in self.slots.iter_mut().flatten() {
+                if existing.kind == item.kind {
+                    existing.count = existing.count.saturating_add(item.count);
+                    return true;
+                }
+            }
+        }
+        for slot in self.slots.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(item);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn take(&mut self, slot: usize) -> Option<Item> {
+        if slot < INVENTORY_CAPACITY {
+            self.slots[slot].take()
+        } else {
+            None
+        }
+    }
+
+    fn find(&self, kind: ItemKind) -> Option<usize> {
+        for i in 0..INVENTORY_CAPACITY {
+            if let Some(item) = self.slots[i] {
+                if item.kind == kind {
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    fn remove_kind(&mut self, kind: ItemKind) {
+        for slot in self.slots.iter_mut() {
+            if let Some(item) = slot {
+                if item.kind == kind {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    // Removes a single unit of the item in the given slot
+    fn consume(&mut self, slot: usize) {
+        if let Some(item) = &mut self.slots[slot] {
+            if item.count > 1 {
+                item.count -= 1;
+            } else {
+                self.slots[slot] = None;
+            }
+        }
+    }
+}
+
+// Total experience needed to reach each level, the player starts at level 1
+const XP_THRESHOLDS: [u32; 10] = [0, 0, 20, 50, 100, 170, 260, 380, 530, 720];
+const MAX_LEVEL: u32 = 9;
+const LEVEL_HP_GAIN: i32 = 5;
+// Newly explored tiles needed for one point of experience
+const TILES_PER_XP: u32 = 50;
+const DESCEND_XP_PER_DEPTH: u32 = 5;
+const DISARM_XP: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Perk {
+    Toughness,
+    Might,
+    Guard,
+    KeenEyes,
+}
+const NUM_PERKS: usize = 4;
+const PERKS: [Perk; NUM_PERKS] = [Perk::Toughness, Perk::Might, Perk::Guard, Perk::KeenEyes];
+
+struct PerkDef {
+    name: &'static str,
+    description: &'static str,
+}
+
+// Indexed by Perk
+const PERK_DEFS: [PerkDef; NUM_PERKS] = [
+    PerkDef { name: "Toughness", description: "+8 max HP" },
+    PerkDef { name: "Might", description: "+2 attack" },
+    PerkDef { name: "Guard", description: "+2 defense" },
+    PerkDef { name: "Keen eyes", description: "notice hidden traps more often" },
+];
+const KEEN_EYES_PERCENT: u64 = 20;
+
+#[derive(Clone, Copy, PartialEq)]
+enum DeathCause {
+    Monster(MonsterKind),
+    Poison,
+    Starvation,
+    Spikes,
+    Pit,
+    // Damage from a mod script
+    Script,
+}
+
+// Writes e.g. "killed by an orc", it follows the player's name in the run summary
+fn format_death_cause(cause: DeathCause, line: &mut MessageLine) {
+    match cause {
+        DeathCause::Monster(kind) => {
+            // High scores outlive mods, so the monster might not be defined any more
+            let name = monster_defs().get(kind.0 as usize).map_or("monster", |def| def.name);
+            line.push("killed by ");
+            line.push(if name.starts_with(|c| "aeiou".contains(c)) { "an " } else { "a " });
+            line.push(name);
+        },
+        DeathCause::Poison => line.push("poisoned"),
+        DeathCause::Starvation => line.push("starved"),
+        DeathCause::Spikes => line.push("impaled by a spike trap"),
+        DeathCause::Pit => line.push("fell into a pit"),
+        DeathCause::Script => line.push("struck down by magic"),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Fighter,
+    Rogue,
+    Mage,
+}
+const NUM_CLASSES: usize = 3;
+const CLASSES: [Class; NUM_CLASSES] = [Class::Fighter, Class::Rogue, Class::Mage];
+
+struct ClassDef {
+    name: &'static str,
+    description: &'static str,
+    max_hp: i32,
+    attack: i32,
+    defense: i32,
+    speed: i32,
+    // Gear is equipped straight away, everything else starts in the inventory
+    starting_items: &'static [(ItemKind, u16)],
+    ability_name: &'static str,
+    // Turns before the ability can be used again
+    ability_cooldown: u16,
+    // Every potion and scroll is identified from the start
+    knows_consumables: bool,
+}
+
+// Indexed by Class
+const CLASS_DEFS: [ClassDef; NUM_CLASSES] = [
+    ClassDef {
+        name: "Fighter", description: "tough and well armoured, can catch a second wind",
+        max_hp: 36, attack: 4, defense: 2, speed: NORMAL_SPEED,
+        starting_items: &[(ItemKind::Sword, 1), (ItemKind::LeatherArmour, 1), (ItemKind::Ration, 1)],
+        ability_name: "Second wind", ability_cooldown: 60, knows_consumables: false,
+    },
+    ClassDef {
+        name: "Rogue", description: "quick on their feet, can sense every trap nearby",
+        max_hp: 28, attack: 3, defense: 1, speed: 110,
+        starting_items: &[(ItemKind::Dagger, 1), (ItemKind::PotionHaste, 1), (ItemKind::Ration, 1)],
+        ability_name: "Sense traps", ability_cooldown: 25, knows_consumables: false,
+    },
+    ClassDef {
+        name: "Mage", description: "frail but knows potions and scrolls, can cast a firebolt",
+        max_hp: 22, attack: 2, defense: 0, speed: NORMAL_SPEED,
+        starting_items: &[(ItemKind::Dagger, 1), (ItemKind::PotionHealing, 2), (ItemKind::ScrollTeleport, 1)],
+        ability_name: "Firebolt", ability_cooldown: 8, knows_consumables: true,
+    },
+];
+const SECOND_WIND_DIVISOR: i32 = 3;
+const SENSE_TRAPS_RADIUS: i64 = 6;
+const FIREBOLT_RANGE: i64 = 6;
+const FIREBOLT_MIN_DAMAGE: i32 = 5;
+const FIREBOLT_EXTRA_DAMAGE: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Race {
+    Human,
+    Dwarf,
+    Elf,
+}
+const NUM_RACES: usize = 3;
+const RACES: [Race; NUM_RACES] = [Race::Human, Race::Dwarf, Race::Elf];
+
+// Modifiers on top of the class stats
+struct RaceDef {
+    name: &'static str,
+    description: &'static str,
+    max_hp: i32,
+    attack: i32,
+    defense: i32,
+    perception_percent: u64,
+}
+
+// Indexed by Race
+const RACE_DEFS: [RaceDef; NUM_RACES] = [
+    RaceDef { name: "Human", description: "no strengths or weaknesses", max_hp: 0, attack: 0, defense: 0, perception_percent: 0 },
+    RaceDef { name: "Dwarf", description: "+6 max HP, +1 defense, -1 attack", max_hp: 6, attack: -1, defense: 1, perception_percent: 0 },
+    RaceDef { name: "Elf", description: "-4 max HP, +1 attack, sharper eyes", max_hp: -4, attack: 1, defense: 0, perception_percent: 15 },
+];
+
+const MAX_NAME_LEN: usize = 16;
+const DEFAULT_NAME: &str = "Adventurer";
+
+struct Player {
+    x: usize,
+    y: usize,
+    hp: i32,
+    base_max_hp: i32,
+    base_attack: i32,
+    base_defense: i32,
+    inventory: Inventory,
+    // Indexed by EquipSlot
+    equipment: [Option<Item>; NUM_EQUIP_SLOTS],
+    identification: Identification,
+    effects: StatusEffects,
+    level: u32,
+    xp: u32,
+    // Explored tiles that haven't been turned into experience yet
+    explored_tiles: u32,
+    // How many times each perk was taken, indexed by Perk
+    perks: [u8; NUM_PERKS],
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    class: Class,
+    race: Race,
+    // Turns until the class ability can be used again
+    ability_cooldown: u16,
+    nutrition: i32,
+    // The player acts whenever they have at least ACTION_ENERGY
+    energy: i32,
+    // Whatever hurt the player last, so it's what killed them if they died
+    death_cause: Option<DeathCause>,
+}
+
+impl Player {
+    fn new(seed: u64, class: Class, race: Race, name: &[u8]) -> Self {
+        let class_def = &CLASS_DEFS[class as usize];
+        let race_def = &RACE_DEFS[race as usize];
+        let max_hp = class_def.max_hp + race_def.max_hp;
+        let mut player = Player {
+            x: 0,
+            y: 0,
+            hp: max_hp,
+            base_max_hp: max_hp,
+            base_attack: class_def.attack + race_def.attack,
+            base_defense: class_def.defense + race_def.defense,
+            inventory: Inventory::new(),
+            equipment: [None; NUM_EQUIP_SLOTS],
+            identification: Identification::new(seed),
+            effects: StatusEffects::new(),
+            level: 1,
+            xp: 0,
+            explored_tiles: 0,
+            perks: [0; NUM_PERKS],
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            class,
+            race,
+            ability_cooldown: 0,
+            nutrition: STARTING_NUTRITION,
+            energy: ACTION_ENERGY,
+            death_cause: None,
+        };
+        let name = if name.is_empty() { DEFAULT_NAME.as_bytes() } else { name };
+        player.name_len = core::cmp::min(name.len(), MAX_NAME_LEN);
+        player.name[..player.name_len].copy_from_slice(&name[..player.name_len]);
+        for (kind, count) in class_def.starting_items.iter() {
+            let item = Item::new(*kind, *count);
+            match item.slot() {
+                Some(slot) => player.equipment[slot as usize] = Some(item),
+                None => {
+                    player.inventory.add(item);
+                },
+            }
+            // Everyone knows what they packed
+            player.identification.identify(*kind);
+        }
+        if class_def.knows_consumables {
+            for kind in ITEM_KINDS.iter() {
+                if item_def(*kind).flags & (ITEM_FLAG_POTION | ITEM_FLAG_SCROLL) != 0 {
+                    player.identification.identify(*kind);
+                }
+            }
+        }
+        player
+    }
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or(DEFAULT_NAME)
+    }
+
+    fn class_def(&self) -> &'static ClassDef {
+        &CLASS_DEFS[self.class as usize]
+    }
+
+    fn speed(&self) -> i32 {
+        effective_speed(self.class_def().speed, &self.effects)
+    }
+
+    // Adds experience and raises the player's level for every threshold crossed. Returns how 
+    // many levels were gained so the caller can offer a perk for each.
+    fn gain_xp(&mut self, amount: u32) -> u32 {
+        self.xp += amount;
+        let mut levels_gained = 0;
+        while self.level < MAX_LEVEL && self.xp >= XP_THRESHOLDS[(self.level + 1) as usize] {
+            self.level += 1;
+            levels_gained += 1;
+            self.base_max_hp += LEVEL_HP_GAIN;
+            self.hp += LEVEL_HP_GAIN;
+            // Alternate between offense and defense so neither falls behind
+            if self.level.is_multiple_of(2) {
+                self.base_attack += 1;
+            } else {
+                self.base_defense += 1;
+            }
+        }
+        levels_gained
+    }
+
+    // Exploration is worth a point of experience every TILES_PER_XP tiles
+    fn gain_exploration_xp(&mut self, newly_explored: u32) -> u32 {
+        self.explored_tiles += newly_explored;
+        let amount = self.explored_tiles / TILES_PER_XP;
+        self.explored_tiles %= TILES_PER_XP;
+        if amount == 0 {
+            return 0;
+        }
+        self.gain_xp(amount)
+    }
+
+    // Experience needed for the next level, or None at the maximum level
+    fn next_level_xp(&self) -> Option<u32> {
+        if self.level >= MAX_LEVEL {
+            None
+        } else {
+            Some(XP_THRESHOLDS[(self.level + 1) as usize])
+        }
+    }
+
+    fn take_perk(&mut self, perk: Perk) {
+        self.perks[perk as usize] += 1;
+        match perk {
+            Perk::Toughness => {
+                self.base_max_hp += 8;
+                self.hp += 8;
+            },
+            Perk::Might => self.base_attack += 2,
+            Perk::Guard => self.base_defense += 2,
+            Perk::KeenEyes => (),
+        }
+    }
+
+    fn perception_percent(&self) -> u64 {
+        PERCEPTION_PERCENT
+            + RACE_DEFS[self.race as usize].perception_percent
+            + KEEN_EYES_PERCENT * self.perks[Perk::KeenEyes as usize] as u64
+    }
+
+    fn equipment_bonus(&self) -> StatBonus {
+        let mut bonus = StatBonus::none();
+        for item in self.equipment.iter().flatten() {
+            bonus.add(item.bonus());
+        }
+        bonus
+    }
+
+    fn max_hp(&self) -> i32 {
+        self.base_max_hp + self.equipment_bonus().max_hp + self.effects.potency(StatusKind::Toughness) as i32
+    }
+
+    fn attack(&self) -> i32 {
+        self.base_attack + self.equipment_bonus().attack + self.effects.potency(StatusKind::Strength) as i32
+    }
+
+    fn defense(&self) -> i32 {
+        self.base_defense + self.equipment_bonus().defense
+    }
+
+    // Moves the item in the inventory slot into its equipment slot, swapping out whatever was 
+    // there before. Returns false if the item can't be equipped.
+    fn equip(&mut self, inventory_slot: usize) -> bool {
+        let item = match self.inventory.slots[inventory_slot] {
+            Some(item) => item,
+            None => return false,
+        };
+        let equip_slot = match item.slot() {
+            Some(equip_slot) => equip_slot as usize,
+            None => return false,
+        };
+        self.inventory.slots[inventory_slot] = self.equipment[equip_slot].take();
+        self.equipment[equip_slot] = Some(item);
+        // The item swapped out may have had a bigger vitality bonus than the new one
+        self.hp = core::cmp::min(self.hp, self.max_hp());
+        true
+    }
+
+    // Returns false if there is no room in the inventory for the item
+    fn unequip(&mut self, equip_slot: usize) -> bool {
+        if let Some(item) = self.equipment[equip_slot] {
+            if !self.inventory.add(item) {
+                return false;
+            }
+            self.equipment[equip_slot] = None;
+            // Losing a vitality bonus can leave the player above their new maximum
+            self.hp = core::cmp::min(self.hp, self.max_hp());
+        }
+        true
+    }
+}
+
+const NUM_STATUS_KINDS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq)]
+enum StatusKind {
+    Poison,
+    Regeneration,
+    Haste,
+    Slow,
+    Confusion,
+    Blindness,
+    // Potency is the bonus to attack
+    Strength,
+    // Potency is the bonus to max HP
+    Toughness,
+}
+
+// How a status combines with another application of itself
+#[derive(Copy, Clone, PartialEq)]
+enum Stacking {
+    // Keep whichever duration is longer
+    Refresh,
+    // Add the durations together
+    Extend,
+    // Keep the longer duration and add the potencies together
+    Intensify,
+}
+
+struct StatusDef {
+    indicator: &'static str,
+    stacking: Stacking,
+    // Applying this status cancels out the opposite one instead
+    opposite: Option<StatusKind>,
+    max_turns: u16,
+    max_potency: u8,
+    expire_message: &'static str,
+}
+
+// Indexed by StatusKind
+const STATUS_DEFS: [StatusDef; NUM_STATUS_KINDS] = [
+    StatusDef { indicator: "Poisoned", stacking: Stacking::Intensify, opposite: None, max_turns: 30, max_potency: 5, expire_message: "You feel less sick. " },
+    StatusDef { indicator: "Regen", stacking: Stacking::Refresh, opposite: None, max_turns: 1000, max_potency: 3, expire_message: "Your skin stops tingling. " },
+    StatusDef { indicator: "Hasted", stacking: Stacking::Refresh, opposite: Some(StatusKind::Slow), max_turns: 50, max_potency: 1, expire_message: "You feel yourself slow down. " },
+    StatusDef { indicator: "Slowed", stacking: Stacking::Refresh, opposite: Some(StatusKind::Haste), max_turns: 50, max_potency: 1, expire_message: "You feel yourself speed up. " },
+    StatusDef { indicator: "Confused", stacking: Stacking::Extend, opposite: None, max_turns: 40, max_potency: 1, expire_message: "You feel less confused. " },
+    StatusDef { indicator: "Blind", stacking: Stacking::Extend, opposite: None, max_turns: 40, max_potency: 1, expire_message: "You can see again. " },
+    StatusDef { indicator: "Strong", stacking: Stacking::Refresh, opposite: None, max_turns: 100, max_potency: 5, expire_message: "You feel weaker. " },
+    StatusDef { indicator: "Tough", stacking: Stacking::Refresh, opposite: None, max_turns: 100, max_potency: 20, expire_message: "You feel less sturdy. " },
+];
+
+const STATUS_KINDS: [StatusKind; NUM_STATUS_KINDS] = [
+    StatusKind::Poison, StatusKind::Regeneration, StatusKind::Haste,
+    StatusKind::Slow, StatusKind::Confusion, StatusKind::Blindness,
+    StatusKind::Strength, StatusKind::Toughness
+];
+
+// Remaining turns and potency of every status, a status is active while it has turns left
+#[derive(Copy, Clone)]
+struct StatusEffects {
+    turns: [u16; NUM_STATUS_KINDS],
+    potency: [u8; NUM_STATUS_KINDS],
+}
+
+impl StatusEffects {
+    const fn new() -> Self {
+        StatusEffects { turns: [0; NUM_STATUS_KINDS], potency: [0; NUM_STATUS_KINDS] }
+    }
+
+    fn has(&self, kind: StatusKind) -> bool {
+        self.turns[kind as usize] > 0
+    }
+
+    fn potency(&self, kind: StatusKind) -> u8 {
+        self.potency[kind as usize]
+    }
+
+    fn apply(&mut self, kind: StatusKind, turns: u16, potency: u8) {
+        let def = &STATUS_DEFS[kind as usize];
+        if let Some(opposite) = def.opposite {
+            if self.has(opposite) {
+                self.turns[opposite as usize] = 0;
+                return;
+            }
+        }
+        let i = kind as usize;
+        let was_active = self.has(kind);
+        match def.stacking {
+            Stacking::Refresh => {
+                self.turns[i] = core::cmp::max(self.turns[i], turns);
+                self.potency[i] = core::cmp::max(self.potency[i], potency);
+            },
+            Stacking::Extend => {
+                self.turns[i] = self.turns[i].saturating_add(turns);
+                self.potency[i] = core::cmp::max(self.potency[i], potency);
+            },
+            Stacking::Intensify => {
+                self.turns[i] = core::cmp::max(self.turns[i], turns);
+                self.potency[i] = if was_active { self.potency[i].saturating_add(potency) } else { potency };
+            },
+        }
+        self.turns[i] = core::cmp::min(self.turns[i], def.max_turns);
+        self.potency[i] = core::cmp::min(self.potency[i], def.max_potency);
+    }
+
+    // Counts every active status down by a turn, returns a bitmask of the ones that ran out
+    fn tick(&mut self) -> u8 {
+        let mut expired = 0;
+        for i in 0..NUM_STATUS_KINDS {
+            if self.turns[i] > 0 {
+                self.turns[i] -= 1;
+                if self.turns[i] == 0 {
+                    self.potency[i] = 0;
+                    expired |= 1 << i;
+                }
+            }
+        }
+        expired
+    }
+}
+
+fn format_statuses(effects: &StatusEffects, line: &mut MessageLine) {
+    for kind in STATUS_KINDS.iter() {
+        if effects.has(*kind) {
+            line.push(" ");
+            line.push(STATUS_DEFS[*kind as usize].indicator);
+            line.push("(");
+            line.push_number(effects.turns[*kind as usize] as u64);
+            line.push(")");
+        }
+    }
+}
+
+// Per turn effects of the player's statuses
+fn process_player_statuses(player: &mut Player, messages: &mut TurnMessages) {
+    if player.effects.has(StatusKind::Poison) {
+        player.hp -= player.effects.potency(StatusKind::Poison) as i32;
+        player.death_cause = Some(DeathCause::Poison);
+        messages.push("The poison burns. ");
+    }
+    if player.effects.has(StatusKind::Regeneration) {
+        player.hp = core::cmp::min(player.hp + player.effects.potency(StatusKind::Regeneration) as i32, player.max_hp());
+    }
+    let expired = player.effects.tick();
+    // Toughness running out can leave the player above their new maximum
+    player.hp = core::cmp::min(player.hp, player.max_hp());
+    for (i, def) in STATUS_DEFS.iter().enumerate() {
+        if expired & (1 << i) != 0 {
+            messages.push(def.expire_message);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Difficulty {
+    Casual,
+    Normal,
+    Hard,
+}
+const NUM_DIFFICULTIES: usize = 3;
+const DIFFICULTIES: [Difficulty; NUM_DIFFICULTIES] = [Difficulty::Casual, Difficulty::Normal, Difficulty::Hard];
+
+struct DifficultyDef {
+    name: &'static str,
+    description: &'static str,
+    // Nutrition lost every turn, zero turns the hunger clock off
+    hunger_per_turn: i32,
+    food_per_floor: u64,
+}
+
+// Indexed by Difficulty
+const DIFFICULTY_DEFS: [DifficultyDef; NUM_DIFFICULTIES] = [
+    DifficultyDef { name: "Casual", description: "no hunger clock", hunger_per_turn: 0, food_per_floor: 0 },
+    DifficultyDef { name: "Normal", description: "you have to eat to keep going", hunger_per_turn: 1, food_per_floor: 2 },
+    DifficultyDef { name: "Hard", description: "hunger sets in twice as fast and food is scarce", hunger_per_turn: 2, food_per_floor: 1 },
+];
+
+const MAX_NUTRITION: i32 = 2000;
+const STARTING_NUTRITION: i32 = 1500;
+const RATION_NUTRITION: i32 = 800;
+// Random tiles tried for each ration before it's left out
+const FOOD_PLACE_ATTEMPTS: usize = 64;
+// Turns between each point of starvation damage
+const STARVATION_INTERVAL: u64 = 5;
+
+struct HungerStage {
+    // The stage starts once nutrition drops below this
+    threshold: i32,
+    indicator: &'static str,
+    warning: &'static str,
+}
+
+// Ordered from least to most severe
+const HUNGER_STAGES: [HungerStage; 4] = [
+    HungerStage { threshold: 400, indicator: "Hungry", warning: "You are getting hungry. " },
+    HungerStage { threshold: 200, indicator: "Weak", warning: "You feel weak from hunger. " },
+    HungerStage { threshold: 75, indicator: "Faint", warning: "You are fainting from hunger! Eat something! " },
+    HungerStage { threshold: 1, indicator: "Starving", warning: "You are starving to death! " },
+];
+
+// Number of hunger stages the player has reached, zero when well fed
+fn hunger_stage(nutrition: i32) -> usize {
+    HUNGER_STAGES.iter().filter(|stage| nutrition < stage.threshold).count()
+}
+
+// Per turn hunger, warns the player whenever they get hungrier and starves them once they run out
+fn process_hunger(player: &mut Player, difficulty: Difficulty, turn: u64, messages: &mut TurnMessages) {
+    let hunger_per_turn = DIFFICULTY_DEFS[difficulty as usize].hunger_per_turn;
+    if hunger_per_turn == 0 {
+        return;
+    }
+    let stage_before = hunger_stage(player.nutrition);
+    player.nutrition = core::cmp::max(player.nutrition - hunger_per_turn, 0);
+    let stage = hunger_stage(player.nutrition);
+    if stage > stage_before {
+        messages.push(HUNGER_STAGES[stage - 1].warning);
+    }
+    if player.nutrition == 0 && turn.is_multiple_of(STARVATION_INTERVAL) {
+        player.hp -= 1;
+        player.death_cause = Some(DeathCause::Starvation);
+        messages.push("Hunger gnaws at you. ");
+    }
+}
+
+// Everything that acts gains its speed in energy every turn and spends ACTION_ENERGY per action,
+// so a speed of 200 acts twice a turn and a speed of 50 every other turn
+const ACTION_ENERGY: i32 = 100;
+const NORMAL_SPEED: i32 = 100;
+
+fn effective_speed(base_speed: i32, effects: &StatusEffects) -> i32 {
+    if effects.has(StatusKind::Haste) {
+        base_speed * 2
+    } else if effects.has(StatusKind::Slow) {
+        base_speed / 2
+    } else {
+        base_speed
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TimeMode {
+    // The world only moves when the player acts
+    TurnBased,
+    // Input is read on a fixed tick and standing still for too long passes a turn
+    RealTime,
+}
+const NUM_TIME_MODES: usize = 2;
+const TIME_MODES: [TimeMode; NUM_TIME_MODES] = [TimeMode::TurnBased, TimeMode::RealTime];
+// Indexed by TimeMode
+const TIME_MODE_NAMES: [&str; NUM_TIME_MODES] = ["Turn based", "Real time"];
+const TIME_MODE_DESCRIPTIONS: [&str; NUM_TIME_MODES] = [
+    "the world waits for you to act",
+    "monsters keep coming if you stand still",
+];
+const REALTIME_TICK_MS: usize = 30;
+
+// Indexed by whether permadeath is on
+const DEATH_MODE_NAMES: [&str; 2] = ["Standard", "Permadeath"];
+const DEATH_MODE_DESCRIPTIONS: [&str; 2] = [
+    "your save stays put, so you can go back to where you last quit",
+    "your save is deleted when loaded and only written when you quit, death is final",
+];
+// Ticks without input before the player automatically waits a turn in real time mode
+const REALTIME_IDLE_TICKS: u32 = 20;
+
+// The first screen, picking the high scores shows them and comes back here
+const MAIN_MENU_NAMES: [&str; 2] = ["New game", "High scores"];
+const MAIN_MENU_DESCRIPTIONS: [&str; 2] = ["start a fresh run", "the best runs so far"];
+
+fn confused_direction(rng: &mut XorshiftRng) -> (i64, i64) {
+    match rng.range(4) {
+        0 => (1, 0),
+        1 => (-1, 0),
+        2 => (0, 1),
+        _ => (0, -1),
+    }
+}
+
+const FOV_RADIUS: i64 = 8;
+
+// Work queue for flood fills over the map, kept out of the stack because it's as big as the
+// largest floor. Nothing that uses it calls back into another flood fill.
+static mut FLOOD_QUEUE: [u32; LEVEL_WIDTH * LEVEL_HEIGHT] = [0; LEVEL_WIDTH * LEVEL_HEIGHT];
+// Steps from the start of a path search to each tile, u32::MAX for tiles it hasn't reached
+static mut FLOOD_DISTANCE: [u32; LEVEL_WIDTH * LEVEL_HEIGHT] = [0; LEVEL_WIDTH * LEVEL_HEIGHT];
+
+const MAX_DOORS: usize = 256;
+const MAX_LOCK_ATTEMPTS: usize = 64;
+// Random picks for a special tile in a room before the first free one is taken instead
+const ROOM_TILE_ATTEMPTS: usize = 64;
+
+const MAX_TRAPS: usize = 32;
+// Random tiles tried for each trap before it's left out
+const TRAP_PLACE_ATTEMPTS: usize = 64;
+// Percent chance per turn to notice each hidden trap next to the player, about one in six
+const PERCEPTION_PERCENT: u64 = 17;
+const SEARCH_RADIUS: i64 = 2;
+// Percent chance for a search to find each trap in range
+const SEARCH_PERCENT: u64 = 75;
+// One in this many chance for a disarm to fail
+const DISARM_MISS_CHANCE: u64 = 3;
+// One in this many chance for a failed disarm to set the trap off
+const DISARM_FAIL_CHANCE: u64 = 3;
+
+#[derive(Copy, Clone, PartialEq)]
+enum TrapKind {
+    Spike,
+    Teleport,
+    Alarm,
+    Pit,
+}
+
+const TRAP_KINDS: [TrapKind; 4] = [TrapKind::Spike, TrapKind::Teleport, TrapKind::Alarm, TrapKind::Pit];
+
+// Indexed by TrapKind
+const TRAP_NAMES: [&str; 4] = ["spike trap", "teleport trap", "alarm trap", "pit"];
+
+#[derive(Copy, Clone)]
+struct Trap {
+    x: u64,
+    y: u64,
+    kind: TrapKind,
+    revealed: bool,
+}
+
+// Rooms past this many are still carved out, they just aren't listed
+const MAX_ROOMS: usize = 64;
+
+// Top left corner and size of a room's floor
+#[derive(Copy, Clone)]
+struct Room {
+    x: u64,
+    y: u64,
+    width: u64,
+    height: u64,
+}
+
+const MAX_MONSTERS: usize = 48;
+// Sleeping monsters further away than this in either axis don't notice the player
+const MONSTER_SIGHT: i64 = 8;
+
+// Index into the monster definitions, which come from defs.txt and mods rather than code
+#[derive(Copy, Clone, PartialEq)]
+struct MonsterKind(u8);
+
+const MAX_MONSTER_KINDS: usize = 32;
+
+// Spawns already chasing the player instead of asleep
+const MONSTER_FLAG_AWAKE: u8 = 1 << 0;
+// Never moves, it only attacks a player next to it
+const MONSTER_FLAG_STATIONARY: u8 = 1 << 1;
+
+// Read from defs.txt and mods at startup, see load_defs
+struct MonsterDef {
+    // Section id in definition files
+    id: &'static str,
+    name: &'static str,
+    glyph: char,
+    colour: Colour,
+    max_hp: i32,
+    attack: i32,
+    defense: i32,
+    xp: u32,
+    speed: i32,
+    // Status that a hit has a chance to inflict, with its duration
+    inflicts: Option<(StatusKind, u16)>,
+    // Status the monster spawns with
+    innate: Option<StatusKind>,
+    flags: u8,
+    // Floors the monster can be generated on, a max_depth of 0 means there's no limit
+    min_depth: u64,
+    max_depth: u64,
+    // How often it's picked over the other monsters
+    weight: u32,
+}
+
+// What a monster added by a mod starts as, it needs at least a glyph and hp on top
+const NEW_MONSTER_DEF: MonsterDef = MonsterDef {
+    id: "", name: "", glyph: UNEXPLORED_CHAR, colour: Colour::Default, max_hp: 0, attack: 0, defense: 0,
+    xp: 0, speed: NORMAL_SPEED, inflicts: None, innate: None, flags: 0, min_depth: 1, max_depth: 0, weight: DEFAULT_WEIGHT
+};
+
+// Weight of anything a definition doesn't give one
+const DEFAULT_WEIGHT: u32 = 10;
+
+// Monster, item and script definitions. Monsters and scripts can be added by mods, items keep 
+// their behaviour in code so every ItemKind has exactly one definition.
+struct Defs {
+    monsters: [MonsterDef; MAX_MONSTER_KINDS],
+    num_monsters: usize,
+    items: [ItemDef; NUM_ITEM_KINDS],
+    scripts: [ScriptDef; MAX_SCRIPTS],
+    num_scripts: usize,
+    // Compiled instructions of every script
+    ops: [Op; MAX_SCRIPT_OPS],
+    num_ops: usize,
+}
+
+const NO_DEFS: Defs = Defs {
+    monsters: [NEW_MONSTER_DEF; MAX_MONSTER_KINDS],
+    num_monsters: 0,
+    items: [NO_ITEM_DEF; NUM_ITEM_KINDS],
+    scripts: [NO_SCRIPT_DEF; MAX_SCRIPTS],
+    num_scripts: 0,
+    ops: [Op::Number(0); MAX_SCRIPT_OPS],
+    num_ops: 0,
+};
+
+// Filled in once by load_defs before anything else runs
+static mut DEFS: Defs = NO_DEFS;
+
+fn defs() -> &'static Defs {
+    unsafe { &*core::ptr::addr_of!(DEFS) }
+}
+
+fn monster_defs() -> &'static [MonsterDef] {
+    &defs().monsters[..defs().num_monsters]
+}
+
+#[derive(Copy, Clone)]
+struct Monster {
+    kind: MonsterKind,
+    x: u64,
+    y: u64,
+    hp: i32,
+    effects: StatusEffects,
+    // Monsters sleep until they spot the player, get hit, or hear an alarm
+    awake: bool,
+    energy: i32,
+}
+
+// Fills the monster slots past num_monsters. It's never read, so it doesn't look up a definition
+// and works before any are loaded.
+const EMPTY_MONSTER_SLOT: Monster = Monster {
+    kind: MonsterKind(0), x: 0, y: 0, hp: 0, effects: StatusEffects::new(), awake: false, energy: 0
+};
+
+impl Monster {
+    fn new(kind: MonsterKind, x: u64, y: u64) -> Self {
+        let def = &monster_defs()[kind.0 as usize];
+        let mut effects = StatusEffects::new();
+        if let Some(innate) = def.innate {
+            effects.apply(innate, STATUS_DEFS[innate as usize].max_turns, 1);
+        }
+        let awake = def.flags & MONSTER_FLAG_AWAKE != 0;
+        let mut monster = Monster { kind, x, y, hp: 0, effects, awake, energy: 0 };
+        monster.hp = monster.max_hp();
+        monster
+    }
+
+    fn def(&self) -> &'static MonsterDef {
+        &monster_defs()[self.kind.0 as usize]
+    }
+
+    fn max_hp(&self) -> i32 {
+        self.def().max_hp + self.effects.potency(StatusKind::Toughness) as i32
+    }
+
+    fn attack(&self) -> i32 {
+        self.def().attack + self.effects.potency(StatusKind::Strength) as i32
+    }
+}
+
+fn roll_damage(rng: &mut XorshiftRng, attack: i32, defense: i32) -> i32 {
+    let roll = attack / 2 + rng.range((attack as u64) / 2 + 1) as i32;
+    core::cmp::max(1, roll - defense / 2)
+}
+
+fn slot_letter(slot: usize) -> char {
+    (b'a' + slot as u8) as char
+}
+
+// Tiles that hide what they are are drawn as something else
+fn tile_glyph(tile: char) -> char {
+    match tile {
+        TRAP_CHAR => FLOOR_CHAR,
+        _ => tile,
+    }
+}
+
+// The part of the level the window shows, it's centred on the player
+#[derive(Clone, Copy)]
+struct WindowView {
+    player_x: usize,
+    player_y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl WindowView {
+    fn level_x_start(&self) -> usize {
+        self.player_x - (self.width / 2)
+    }
+
+    fn level_y_start(&self) -> usize {
+        self.player_y - (self.height / 2)
+    }
+}
+
+// Tiles are drawn in the default colour, only monsters and items have their own
+fn draw_level_into_window(
+    window: &mut [char], colours: &mut [Colour], level: &[char], explored: &[bool],
+    view: &WindowView, level_width: usize
+) {
+    let level_x_start: usize = view.level_x_start();
+    let level_y_start: usize = view.level_y_start();
+    for y in 0..view.height {
+        let window_row_start: usize = y * view.width;
+        let level_row_start: usize = (level_y_start + y) * level_width;
+        for x in 0..view.width {
+            colours[window_row_start + x] = Colour::Default;
+            if level_y_start + y == view.player_y && level_x_start + x == view.player_x {
+                window[window_row_start + x] = '@';
+            } else if explored[level_row_start + level_x_start + x] { 
+                window[window_row_start + x] = tile_glyph(level[level_row_start + level_x_start + x]);
+            } else {
+                window[window_row_start + x] = UNEXPLORED_CHAR;
+            }
+        }
+    }
+}
+
+// Draws a glyph at level coordinates if it falls inside the window, the player is always on top
+fn draw_glyph_into_window(
+    window: &mut [char], colours: &mut [Colour], glyph: char, colour: Colour, x: usize, y: usize,
+    view: &WindowView
+) {
+    let level_x_start: usize = view.level_x_start();
+    let level_y_start: usize = view.level_y_start();
+    if x >= level_x_start && x < level_x_start + view.width 
+        && y >= level_y_start && y < level_y_start + view.height
+        && (x != view.player_x || y != view.player_y)
+    {
+        let index = (y - level_y_start) * view.width + (x - level_x_start);
+        window[index] = glyph;
+        colours[index] = colour;
+    }
+}
+
+fn get_time() -> (usize, usize) {
+    let mut timespec = [0u64; 2];
+
+    unsafe {
+        #[cfg(target_arch = "x86_64")]
+        {
+            asm!(
+                "mov rdi, 0",
+                "syscall",
+                in("rax") SYSCALL_CLOCK_GETTIME,
+                in("rsi") timespec.as_mut_ptr(),
+                lateout("rax") _,
+                out("rdi") _,
+            );
+        }
+    }
+
+    (timespec[0] as usize, timespec[1] as usize) 
+}
+
+fn ns_to_ms(time_ns: usize) -> usize {
+    time_ns / 1000000
+}
+
+fn get_time_diff_ms(first_sec: usize, first_ns: usize, second_sec: usize, second_ns: usize) -> usize {
+    const MS_PER_SEC: usize = 1000;
+    if second_sec == first_sec && second_ns > first_ns {
+        ns_to_ms(second_ns - first_ns)
+    } else if second_sec > first_sec {
+        (second_sec - first_sec - 1) * MS_PER_SEC + ns_to_ms(second_ns) + (MS_PER_SEC - ns_to_ms(first_ns))
+    } else {
+        0
+    }
+}
+
+fn clear_screen() {
+    print("\x1B[2J\x1B[1;1H");
 }
 
 struct Dungeon<const MAP_WIDTH: usize, const MAP_HEIGHT: usize> 
 where
     [(); MAP_WIDTH * MAP_HEIGHT]: Sized
 {
-    rng: XorshiftRng,
-    map: [char; MAP_WIDTH * MAP_HEIGHT],
+    rng: XorshiftRng,
+    // Separate stream so item rolls don't shift the layout of later rooms
+    loot_rng: XorshiftRng,
+    map: [char; MAP_WIDTH * MAP_HEIGHT],
+    // Tiles the player has seen at some point on this floor
+    explored: [bool; MAP_WIDTH * MAP_HEIGHT],
+    // Tiles in the player's field of view this turn
+    visible: [bool; MAP_WIDTH * MAP_HEIGHT],
+    min_rooms: u64,
+    max_rooms: u64,
+    min_room_dim: u64,
+    max_room_dim: u64,
+    spawn_x: u64,
+    spawn_y: u64,
+    stairs_x: u64,
+    stairs_y: u64,
+    key_x: u64,
+    key_y: u64,
+    window_width: u64,
+    window_height: u64,
+    depth: u64,
+    items: [FloorItem; MAX_FLOOR_ITEMS],
+    num_items: usize,
+    monsters: [Monster; MAX_MONSTERS],
+    num_monsters: usize,
+    traps: [Trap; MAX_TRAPS],
+    num_traps: usize,
+    // Rations guaranteed on every floor on top of the random loot
+    food_per_floor: u64,
+    // Only known for freshly generated floors, saves don't keep them since the game never needs them
+    rooms: [Room; MAX_ROOMS],
+    num_rooms: usize,
+}
+
+impl<const MAP_WIDTH: usize, const MAP_HEIGHT: usize> Dungeon<MAP_WIDTH, MAP_HEIGHT> 
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    fn new(
+        seed: u64, 
+        min_rooms: u64, 
+        max_rooms: u64, 
+        min_room_dim: u64, 
+        max_room_dim: u64, 
+        window_width: u64, 
+        window_height: u64
+    ) -> Self {
+        Dungeon {
+            rng: XorshiftRng::new(seed),
+            loot_rng: XorshiftRng::new(seed ^ 0x9E3779B97F4A7C15),
+            map: ['#'; MAP_WIDTH * MAP_HEIGHT],
+            explored: [false; MAP_WIDTH * MAP_HEIGHT],
+            visible: [false; MAP_WIDTH * MAP_HEIGHT],
+            min_rooms,
+            max_rooms,
+            min_room_dim,
+            max_room_dim,
+            spawn_x: 0,
+            spawn_y: 0,
+            stairs_x: 0,
+            stairs_y: 0,
+            key_x: 0,
+            key_y: 0,
+            window_width,
+            window_height,
+            depth: 1,
+            items: [FloorItem { x: 0, y: 0, item: Item::new(ItemKind::Gold, 0) }; MAX_FLOOR_ITEMS],
+            num_items: 0,
+            monsters: [EMPTY_MONSTER_SLOT; MAX_MONSTERS],
+            num_monsters: 0,
+            traps: [Trap { x: 0, y: 0, kind: TrapKind::Spike, revealed: false }; MAX_TRAPS],
+            num_traps: 0,
+            food_per_floor: 0,
+            rooms: [Room { x: 0, y: 0, width: 0, height: 0 }; MAX_ROOMS],
+            num_rooms: 0,
+        }
+    }
+
+    // Rooms and halls stay out of the margin around the map, so the window never leaves it
+    fn in_play_area(&self, x: u64, y: u64) -> bool {
+        x > self.window_width && x < MAP_WIDTH as u64 - self.window_width
+            && y > self.window_height && y < MAP_HEIGHT as u64 - self.window_height
+    }
+
+    fn map_size(&self) -> usize {
+        MAP_WIDTH * MAP_HEIGHT 
+    }
+
+    fn floor_items(&self) -> &[FloorItem] {
+        &self.items[..self.num_items]
+    }
+
+    fn item_index_at(&self, x: u64, y: u64) -> Option<usize> {
+        self.floor_items().iter().position(|floor_item| floor_item.x == x && floor_item.y == y)
+    }
+
+    // Only one item can lie on a tile, returns false if the tile is taken or the floor is full
+    fn add_floor_item(&mut self, x: u64, y: u64, item: Item) -> bool {
+        if self.num_items >= MAX_FLOOR_ITEMS || self.item_index_at(x, y).is_some() {
+            return false;
+        }
+        self.items[self.num_items] = FloorItem { x, y, item };
+        self.num_items += 1;
+        true
+    }
+
+    fn take_floor_item(&mut self, index: usize) -> Item {
+        let item = self.items[index].item;
+        self.num_items -= 1;
+        self.items[index] = self.items[self.num_items];
+        item
+    }
+
+    fn monster_index_at(&self, x: u64, y: u64) -> Option<usize> {
+        self.monsters[..self.num_monsters].iter().position(|monster| monster.x == x && monster.y == y)
+    }
+
+    fn add_monster(&mut self, monster: Monster) -> bool {
+        if self.num_monsters >= MAX_MONSTERS || self.monster_index_at(monster.x, monster.y).is_some() {
+            return false;
+        }
+        self.monsters[self.num_monsters] = monster;
+        self.num_monsters += 1;
+        true
+    }
+
+    fn remove_monster(&mut self, index: usize) {
+        self.num_monsters -= 1;
+        self.monsters[index] = self.monsters[self.num_monsters];
+    }
+
+    // None when no monster can be generated this deep
+    fn random_monster_kind(&mut self) -> Option<MonsterKind> {
+        let mut weights = [0u32; MAX_MONSTER_KINDS];
+        for (i, def) in monster_defs().iter().enumerate() {
+            weights[i] = depth_weight(def.min_depth, def.max_depth, def.weight, self.depth);
+        }
+        let index = pick_weighted(&mut self.rng, &weights[..monster_defs().len()])?;
+        Some(MonsterKind(index as u8))
+    }
+
+    fn generate(&mut self) {
+        for i in 0..self.map_size() {
+            self.map[i] = '#';
+            self.explored[i] = false;
+            self.visible[i] = false;
+        }
+        self.num_items = 0;
+        self.num_monsters = 0;
+        self.num_traps = 0;
+        self.num_rooms = 0;
+
+        // num_rooms must be at least 3 to accomodate special tiles
+        let num_rooms = {
+            let num_rooms_rng = self.rng.range_with_min(self.min_rooms, self.max_rooms);
+            if num_rooms_rng >= 3 {
+                num_rooms_rng
+            } else {
+                3
+            }
+        };
+
+        let mut stairs_x: u64 = 0;
+        let mut stairs_y: u64 = 0;
+        let mut key_x: u64 = 0;
+        let mut key_y: u64 = 0;
+
+
+        let mut prev_room_x: u64 = 0;
+        let mut prev_room_y: u64 = 0;
+        let mut in_room = [false; MAP_WIDTH * MAP_HEIGHT];
+
+        for i in 0..num_rooms {
+            // Rooms are at least 2x2 so the spawn, stairs and key always fit
+            let room_half_width = core::cmp::max(self.rng.range_with_min(self.min_room_dim, self.max_room_dim) / 2, 1);
+            let room_half_height = core::cmp::max(self.rng.range_with_min(self.min_room_dim, self.max_room_dim) / 2, 1);
+            // window_width and window_height are used to create a buffer zone around the edges of
+            // edges of the map so that all rooms can be accessed with the window inbounds and the 
+            // player centered in the window
+            let room_x = self.rng.range_with_min(
+                self.window_width + room_half_width + 1, 
+                (MAP_WIDTH as u64) - room_half_width - self.window_width
+            );
+            let room_y = self.rng.range_with_min(
+                self.window_height + room_half_height + 1, 
+                (MAP_HEIGHT as u64) - room_half_height - self.window_height
+            );
+            
+            if self.num_rooms < MAX_ROOMS {
+                self.rooms[self.num_rooms] = Room {
+                    x: room_x - room_half_width,
+                    y: room_y - room_half_height,
+                    width: room_half_width * 2,
+                    height: room_half_height * 2,
+                };
+                self.num_rooms += 1;
+            }
+
+            // Fill in room with walkable tiles
+            for y in (room_y - room_half_height)..(room_y + room_half_height) {
+                for x in (room_x - room_half_width)..(room_x + room_half_width) {
+                    self.map[(y as usize) * MAP_WIDTH + (x as usize)] = '.';
+                    in_room[(y as usize) * MAP_WIDTH + (x as usize)] = true;
+                }
+            }
+            
+            // Connect current room to previous room
+            if prev_room_x != 0 && prev_room_y != 0 {
+                let hall_x;
+                if prev_room_x < room_x {
+                    hall_x = room_x;
+                    for x in prev_room_x..(room_x+1) {
+                        self.map[(prev_room_y as usize) * MAP_WIDTH + (x as usize)] = '.';
+                    }
+                } else {
+                    hall_x = prev_room_x;
+                    for x in room_x..(prev_room_x+1) {
+                        self.map[(room_y as usize) * MAP_WIDTH + (x as usize)] = '.';
+                    }
+                }
+                if prev_room_y < room_y {
+                    for y in prev_room_y..(room_y+1) {
+                        self.map[(y as usize) * MAP_WIDTH + (hall_x as usize)] = '.';
+                    }
+                } else {
+                    for y in room_y..(prev_room_y+1) {
+                        self.map[(y as usize) * MAP_WIDTH + (hall_x as usize)] = '.';
+                    }
+                }
+            }
+            
+            // Determine coordinates of special tiles
+            match i {
+                0 => {
+                    // Set spawn to the center of the first room
+                    self.spawn_x = room_x;
+                    self.spawn_y = room_y;
+                },
+                1 => {
+                    // Put stairs in the second room, making sure they don't overlap the spawn
+                    let taken = [(self.spawn_x, self.spawn_y)];
+                    let (x, y) = self.random_room_tile(room_x, room_y, room_half_width, room_half_height, &taken);
+                    stairs_x = x;
+                    stairs_y = y;
+                },
+                2 => {
+                    // Put key in the third room, making sure it doesn't overlap the spawn or the stairs
+                    let taken = [(self.spawn_x, self.spawn_y), (stairs_x, stairs_y)];
+                    let (x, y) = self.random_room_tile(room_x, room_y, room_half_width, room_half_height, &taken);
+                    key_x = x;
+                    key_y = y;
+                },
+                _ => {
+                    // Scatter some loot in the remaining rooms
+                    if self.rng.range(3) == 0 {
+                        let item_y = self.rng.range_with_min(room_y - room_half_height, room_y + room_half_height);
+                        let item_x = self.rng.range_with_min(room_x - room_half_width, room_x + room_half_width);
+                        let on_special_tile = (item_y == stairs_y && item_x == stairs_x) 
+                            || (item_y == self.spawn_y && item_x == self.spawn_x);
+                        if !on_special_tile {
+                            let item = match self.loot_rng.range(3) {
+                                0 => roll_gear(&mut self.loot_rng, self.depth),
+                                1 => roll_consumable(&mut self.loot_rng, self.depth),
+                                _ => Some(Item::new(ItemKind::Gold, self.loot_rng.range_with_min(5, 30) as u16)),
+                            };
+                            if let Some(item) = item {
+                                self.add_floor_item(item_x, item_y, item);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Populate every room except the spawn room with monsters
+            if i > 0 && self.rng.range(2) == 0 {
+                let monster_y = self.rng.range_with_min(room_y - room_half_height, room_y + room_half_height);
+                let monster_x = self.rng.range_with_min(room_x - room_half_width, room_x + room_half_width);
+                let kind = self.random_monster_kind();
+                let on_special_tile = (monster_y == stairs_y && monster_x == stairs_x) 
+                    || (monster_y == self.spawn_y && monster_x == self.spawn_x);
+                if let (Some(kind), false) = (kind, on_special_tile) {
+                    self.add_monster(Monster::new(kind, monster_x, monster_y));
+                }
+            }
+
+            prev_room_x = room_x;
+            prev_room_y = room_y;
+        }
+        
+        // Set special tiles, the key is an item so it replaces anything already lying there
+        self.map[(stairs_y as usize) * MAP_WIDTH + (stairs_x as usize)] = STAIRS_CHAR;
+        if let Some(index) = self.item_index_at(key_x, key_y) {
+            self.take_floor_item(index);
+        }
+        self.add_floor_item(key_x, key_y, Item::new(ItemKind::StairsKey, 1));
+        self.stairs_x = stairs_x;
+        self.stairs_y = stairs_y;
+        self.key_x = key_x;
+        self.key_y = key_y;
+
+        self.place_doors(&in_room);
+        self.place_locks();
+        self.place_food();
+        self.place_traps();
+    }
+
+    // Picks a tile in the room that isn't taken. Random picks give up after a while and the first 
+    // free tile is used instead, so a small room full of special tiles can't stall generation
+    fn random_room_tile(&mut self, room_x: u64, room_y: u64, half_width: u64, half_height: u64, taken: &[(u64, u64)]) -> (u64, u64) {
+        for _ in 0..ROOM_TILE_ATTEMPTS {
+            let y = self.rng.range_with_min(room_y - half_height, room_y + half_height);
+            let x = self.rng.range_with_min(room_x - half_width, room_x + half_width);
+            if !taken.contains(&(x, y)) {
+                return (x, y);
+            }
+        }
+        for y in (room_y - half_height)..(room_y + half_height) {
+            for x in (room_x - half_width)..(room_x + half_width) {
+                if !taken.contains(&(x, y)) {
+                    return (x, y);
+                }
+            }
+        }
+        (room_x - half_width, room_y - half_height)
+    }
+
+    fn place_food(&mut self) {
+        for _ in 0..self.food_per_floor {
+            for _ in 0..FOOD_PLACE_ATTEMPTS {
+                let index = self.loot_rng.range(self.map_size() as u64) as usize;
+                let x = (index % MAP_WIDTH) as u64;
+                let y = (index / MAP_WIDTH) as u64;
+                let is_free = self.is_empty_floor(index)
+                    && (x, y) != (self.spawn_x, self.spawn_y)
+                    && self.item_index_at(x, y).is_none();
+                if is_free {
+                    self.add_floor_item(x, y, Item::new(ItemKind::Ration, 1));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn place_traps(&mut self) {
+        let num_traps = core::cmp::min(2 + self.depth as usize * 2, MAX_TRAPS);
+        for _ in 0..num_traps {
+            for _ in 0..TRAP_PLACE_ATTEMPTS {
+                let index = self.rng.range(self.map_size() as u64) as usize;
+                let x = (index % MAP_WIDTH) as u64;
+                let y = (index / MAP_WIDTH) as u64;
+                let is_free = self.map[index] == FLOOR_CHAR
+                    && (x, y) != (self.spawn_x, self.spawn_y)
+                    && self.item_index_at(x, y).is_none()
+                    && self.monster_index_at(x, y).is_none();
+                if is_free {
+                    let kind = TRAP_KINDS[self.rng.range(TRAP_KINDS.len() as u64) as usize];
+                    self.map[index] = TRAP_CHAR;
+                    self.traps[self.num_traps] = Trap { x, y, kind, revealed: false };
+                    self.num_traps += 1;
+                    break;
+                }
+            }
+        }
+    }
+
+    // Builds the floor from a hand drawn map instead of generating one. Walls around the drawing 
+    // are trimmed so the drawing is centred in the map's margin, and spaces count as walls.
+    fn load_map(&mut self, text: &[u8]) -> core::result::Result<(), MapError> {
+        // Find the drawing's bounds first so it can be placed before anything is parsed
+        let mut top = usize::MAX;
+        let mut bottom = 0;
+        let mut left = usize::MAX;
+        let mut right = 0;
+        for (line_index, line) in text.split(|c| *c == b'\n').enumerate() {
+            for (column, c) in line.iter().enumerate() {
+                if *c != WALL_CHAR as u8 && *c != UNEXPLORED_CHAR as u8 && *c != b'\r' {
+                    top = core::cmp::min(top, line_index);
+                    bottom = line_index;
+                    left = core::cmp::min(left, column);
+                    right = core::cmp::max(right, column);
+                }
+            }
+        }
+        if top == usize::MAX {
+            return Err(MapError::Empty);
+        }
+        let max_width = MAP_WIDTH - 2 * (self.window_width as usize + 1);
+        let max_height = MAP_HEIGHT - 2 * (self.window_height as usize + 1);
+        if right - left + 1 > max_width || bottom - top + 1 > max_height {
+            return Err(MapError::TooBig { max_width, max_height });
+        }
+        let offset_x = self.window_width as usize + 1;
+        let offset_y = self.window_height as usize + 1;
+
+        for i in 0..self.map_size() {
+            self.map[i] = WALL_CHAR;
+            self.explored[i] = false;
+            self.visible[i] = false;
+        }
+        self.num_items = 0;
+        self.num_monsters = 0;
+        self.num_traps = 0;
+        self.num_rooms = 0;
+
+        let mut spawn_line = 0;
+        let mut key_line = 0;
+        let mut stairs_line = 0;
+        for (line_index, line) in text.split(|c| *c == b'\n').enumerate().skip(top).take(bottom - top + 1) {
+            for (column, c) in line.iter().enumerate() {
+                let glyph = *c as char;
+                if glyph == '\r' || column < left || column > right {
+                    continue;
+                }
+                let x = (offset_x + column - left) as u64;
+                let y = (offset_y + line_index - top) as u64;
+                let line_number = line_index + 1;
+                let duplicate = |first_line: usize| {
+                    if first_line != 0 {
+                        Err(MapError::Duplicate { line: line_number, column: column + 1, glyph, first_line })
+                    } else {
+                        Ok(())
+                    }
+                };
+                let tile = match glyph {
+                    WALL_CHAR | UNEXPLORED_CHAR => WALL_CHAR,
+                    FLOOR_CHAR | DOOR_CLOSED_CHAR | DOOR_OPEN_CHAR => glyph,
+                    PLAYER_CHAR => {
+                        duplicate(spawn_line)?;
+                        spawn_line = line_number;
+                        self.spawn_x = x;
+                        self.spawn_y = y;
+                        FLOOR_CHAR
+                    },
+                    KEY_CHAR => {
+                        duplicate(key_line)?;
+                        key_line = line_number;
+                        self.key_x = x;
+                        self.key_y = y;
+                        self.add_floor_item(x, y, Item::new(ItemKind::StairsKey, 1));
+                        FLOOR_CHAR
+                    },
+                    STAIRS_CHAR => {
+                        duplicate(stairs_line)?;
+                        stairs_line = line_number;
+                        self.stairs_x = x;
+                        self.stairs_y = y;
+                        STAIRS_CHAR
+                    },
+                    _ if lock_colour(glyph).is_some() => glyph,
+                    TRAP_CHAR => {
+                        if self.num_traps >= MAX_TRAPS {
+                            return Err(MapError::TooMany { line: line_number, column: column + 1, glyph });
+                        }
+                        let kind = TRAP_KINDS[self.rng.range(TRAP_KINDS.len() as u64) as usize];
+                        self.traps[self.num_traps] = Trap { x, y, kind, revealed: false };
+                        self.num_traps += 1;
+                        TRAP_CHAR
+                    },
+                    _ => {
+                        let monster = monster_defs().iter().position(|def| def.glyph == glyph);
+                        let placed = if let Some(index) = monster {
+                            self.add_monster(Monster::new(MonsterKind(index as u8), x, y))
+                        } else if let Some(item) = random_item_with_glyph(&mut self.loot_rng, glyph) {
+                            self.add_floor_item(x, y, item)
+                        } else {
+                            return Err(MapError::UnknownGlyph { line: line_number, column: column + 1, glyph });
+                        };
+                        if !placed {
+                            return Err(MapError::TooMany { line: line_number, column: column + 1, glyph });
+                        }
+                        FLOOR_CHAR
+                    },
+                };
+                self.map[y as usize * MAP_WIDTH + x as usize] = tile;
+            }
+        }
+        if spawn_line == 0 {
+            return Err(MapError::Missing(PLAYER_CHAR));
+        }
+        if key_line == 0 {
+            return Err(MapError::Missing(KEY_CHAR));
+        }
+        if stairs_line == 0 {
+            return Err(MapError::Missing(STAIRS_CHAR));
+        }
+        // Each lock's key goes somewhere reachable with the keys for the locks before it, the same 
+        // order the generator uses
+        let mut reachable = [false; MAP_WIDTH * MAP_HEIGHT];
+        for colour in 0..NUM_LOCK_COLOURS {
+            if !self.map.contains(&LOCKED_DOOR_CHARS[colour]) {
+                continue;
+            }
+            self.find_reachable(self.spawn_x, self.spawn_y, (1 << colour) - 1, &mut reachable);
+            if let Some((x, y)) = self.random_key_spot(&reachable) {
+                self.add_floor_item(x, y, Item::new(COLOURED_KEY_KINDS[colour], 1));
+            }
+        }
+        if !self.is_solvable() {
+            return Err(MapError::Unreachable);
+        }
+        self.place_food();
+        Ok(())
+    }
+
+    fn trap_index_at(&self, x: u64, y: u64) -> Option<usize> {
+        self.traps[..self.num_traps].iter().position(|trap| trap.x == x && trap.y == y)
+    }
+
+    fn remove_trap(&mut self, index: usize) {
+        let trap = self.traps[index];
+        self.map[(trap.y as usize) * MAP_WIDTH + (trap.x as usize)] = FLOOR_CHAR;
+        self.num_traps -= 1;
+        self.traps[index] = self.traps[self.num_traps];
+    }
+
+    // Looks for hidden traps around the player, each one in range is found with the given percent 
+    // chance. Returns how many traps were found.
+    fn reveal_traps_near(&mut self, x: u64, y: u64, radius: i64, rng: &mut XorshiftRng, percent: u64, messages: &mut TurnMessages) -> usize {
+        let mut found = 0;
+        for i in 0..self.num_traps {
+            let trap = self.traps[i];
+            let in_range = (trap.x as i64 - x as i64).abs() <= radius && (trap.y as i64 - y as i64).abs() <= radius;
+            if !trap.revealed && in_range && rng.range(100) < percent {
+                self.traps[i].revealed = true;
+                messages.push("You notice a ");
+                messages.push(TRAP_NAMES[trap.kind as usize]);
+                messages.push(". ");
+                found += 1;
+            }
+        }
+        found
+    }
+
+    // Springs the trap on the player, returns true if they fell through to the next floor
+    fn trigger_trap(&mut self, index: usize, player: &mut Player, rng: &mut XorshiftRng, messages: &mut TurnMessages) -> bool {
+        let trap = self.traps[index];
+        self.traps[index].revealed = true;
+        match trap.kind {
+            TrapKind::Spike => {
+                let damage = 2 + rng.range(self.depth + 2) as i32;
+                player.hp -= damage;
+                player.death_cause = Some(DeathCause::Spikes);
+                messages.push("Spikes shoot out of the floor! You take ");
+                messages.push_number(damage as u64);
+                messages.push(" damage. ");
+            },
+            TrapKind::Teleport => {
+                if let Some((x, y)) = self.random_reachable_floor(player.x as u64, player.y as u64, rng) {
+                    player.x = x as usize;
+                    player.y = y as usize;
+                }
+                messages.push("A flash of light and you are somewhere else. ");
+            },
+            TrapKind::Alarm => {
+                for i in 0..self.num_monsters {
+                    self.monsters[i].awake = true;
+                }
+                messages.push("A loud alarm blares! ");
+            },
+            TrapKind::Pit => {
+                player.hp -= 1 + rng.range(3) as i32;
+                player.death_cause = Some(DeathCause::Pit);
+                messages.push("You fall through a pit to the floor below! ");
+                return true;
+            },
+        }
+        false
+    }
+
+    // Puts a door wherever a corridor enters a room through a one tile wide gap
+    fn place_doors(&mut self, in_room: &[bool; MAP_WIDTH * MAP_HEIGHT]) {
+        for y in 1..(MAP_HEIGHT - 1) {
+            for x in 1..(MAP_WIDTH - 1) {
+                let index = y * MAP_WIDTH + x;
+                if self.map[index] != FLOOR_CHAR || in_room[index] {
+                    continue;
+                }
+                let directions: [(usize, usize, usize, usize); 4] = [
+                    // Room side, corridor side, and the two tiles that must be walls
+                    (index + 1, index - 1, index - MAP_WIDTH, index + MAP_WIDTH),
+                    (index - 1, index + 1, index - MAP_WIDTH, index + MAP_WIDTH),
+                    (index + MAP_WIDTH, index - MAP_WIDTH, index - 1, index + 1),
+                    (index - MAP_WIDTH, index + MAP_WIDTH, index - 1, index + 1),
+                ];
+                for (room_side, corridor_side, wall_a, wall_b) in directions.iter() {
+                    let is_doorway = in_room[*room_side]
+                        && self.map[*corridor_side] == FLOOR_CHAR && !in_room[*corridor_side]
+                        && self.map[*wall_a] == WALL_CHAR && self.map[*wall_b] == WALL_CHAR;
+                    let next_to_door = self.map[index - 1] == DOOR_CLOSED_CHAR 
+                        || self.map[index - MAP_WIDTH] == DOOR_CLOSED_CHAR;
+                    if is_doorway && !next_to_door {
+                        self.map[index] = DOOR_CLOSED_CHAR;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Locks some doors and hides the matching keys. Key n is always placed somewhere reachable 
+    // using only keys 0..n, so the floor can be finished in colour order.
+    fn place_locks(&mut self) {
+        let mut doors = [0u32; MAX_DOORS];
+        let mut num_doors = 0;
+        for i in 0..self.map_size() {
+            if self.map[i] == DOOR_CLOSED_CHAR && num_doors < MAX_DOORS {
+                doors[num_doors] = i as u32;
+                num_doors += 1;
+            }
+        }
+        if num_doors == 0 {
+            return;
+        }
+
+        let mut reachable = [false; MAP_WIDTH * MAP_HEIGHT];
+        let num_locks = core::cmp::min(self.depth as usize, NUM_LOCK_COLOURS);
+        for colour in 0..num_locks {
+            let unlocked: u8 = (1 << colour) - 1;
+            let reachable_before = self.find_reachable(self.spawn_x, self.spawn_y, unlocked, &mut reachable);
+            for _ in 0..MAX_LOCK_ATTEMPTS {
+                let door = doors[self.rng.range(num_doors as u64) as usize] as usize;
+                if self.map[door] != DOOR_CLOSED_CHAR {
+                    continue;
+                }
+                self.map[door] = LOCKED_DOOR_CHARS[colour];
+                let reachable_after = self.find_reachable(self.spawn_x, self.spawn_y, unlocked, &mut reachable);
+                // A lock that doesn't cut anything off isn't a puzzle
+                if reachable_after == reachable_before {
+                    self.map[door] = DOOR_CLOSED_CHAR;
+                    continue;
+                }
+                match self.random_key_spot(&reachable) {
+                    Some((x, y)) => {
+                        self.add_floor_item(x, y, Item::new(COLOURED_KEY_KINDS[colour], 1));
+                    },
+                    None => self.map[door] = DOOR_CLOSED_CHAR,
+                }
+                break;
+            }
+        }
+
+        if !self.is_solvable() {
+            self.remove_locks();
+        }
+    }
+
+    fn random_key_spot(&mut self, reachable: &[bool; MAP_WIDTH * MAP_HEIGHT]) -> Option<(u64, u64)> {
+        for _ in 0..MAX_LOCK_ATTEMPTS {
+            let index = self.rng.range(self.map_size() as u64) as usize;
+            let x = (index % MAP_WIDTH) as u64;
+            let y = (index / MAP_WIDTH) as u64;
+            let is_free = reachable[index] && self.map[index] == FLOOR_CHAR
+                && (x, y) != (self.spawn_x, self.spawn_y)
+                && self.item_index_at(x, y).is_none()
+                && self.monster_index_at(x, y).is_none();
+            if is_free {
+                return Some((x, y));
+            }
+        }
+        None
+    }
+
+    fn remove_locks(&mut self) {
+        for i in 0..self.map_size() {
+            if lock_colour(self.map[i]).is_some() {
+                self.map[i] = DOOR_CLOSED_CHAR;
+            }
+        }
+        let mut i = 0;
+        while i < self.num_items {
+            if COLOURED_KEY_KINDS.contains(&self.items[i].item.kind) {
+                self.take_floor_item(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // Plays the floor out by repeatedly collecting every key that can be reached, the floor is 
+    // solvable if the stairs key and the stairs both end up reachable
+    fn is_solvable(&self) -> bool {
+        let mut reachable = [false; MAP_WIDTH * MAP_HEIGHT];
+        let mut keys: u8 = 0;
+        loop {
+            self.find_reachable(self.spawn_x, self.spawn_y, keys, &mut reachable);
+            let mut new_keys = keys;
+            let mut has_stairs_key = false;
+            for floor_item in self.floor_items().iter() {
+                if !reachable[(floor_item.y as usize) * MAP_WIDTH + (floor_item.x as usize)] {
+                    continue;
+                }
+                if floor_item.item.kind == ItemKind::StairsKey {
+                    has_stairs_key = true;
+                }
+                if let Some(colour) = COLOURED_KEY_KINDS.iter().position(|kind| *kind == floor_item.item.kind) {
+                    new_keys |= 1 << colour;
+                }
+            }
+            if has_stairs_key && reachable[(self.stairs_y as usize) * MAP_WIDTH + (self.stairs_x as usize)] {
+                return true;
+            }
+            if new_keys == keys {
+                return false;
+            }
+            keys = new_keys;
+        }
+    }
+
+    fn check_collision(&self, x: u64, y: u64) -> char {
+        self.map[(y as usize) * MAP_WIDTH + (x as usize)]
+    }
+
+    fn set_tile(&mut self, x: u64, y: u64, tile: char) {
+        self.map[(y as usize) * MAP_WIDTH + (x as usize)] = tile;
+    }
+
+    // Locked doors count as passable if their colour bit is set in unlocked
+    fn is_passable(&self, x: u64, y: u64, unlocked: u8) -> bool {
+        let tile = self.check_collision(x, y);
+        match tile {
+            FLOOR_CHAR | STAIRS_CHAR | DOOR_OPEN_CHAR | DOOR_CLOSED_CHAR | TRAP_CHAR => true,
+            _ => match lock_colour(tile) {
+                Some(colour) => unlocked & (1 << colour) != 0,
+                None => false,
+            }
+        }
+    }
+
+    // Monsters can't open doors, they know where the traps are and step over them safely
+    fn is_walkable_for_monster(&self, x: u64, y: u64) -> bool {
+        let tile = self.check_collision(x, y);
+        tile == FLOOR_CHAR || tile == DOOR_OPEN_CHAR || tile == TRAP_CHAR
+    }
+
+    fn blocks_sight(&self, x: u64, y: u64) -> bool {
+        let tile = self.check_collision(x, y);
+        tile == WALL_CHAR || tile == DOOR_CLOSED_CHAR || lock_colour(tile).is_some()
+    }
+
+    // Walks a Bresenham line between the two points, the end points themselves never block
+    fn has_line_of_sight(&self, from_x: u64, from_y: u64, to_x: u64, to_y: u64) -> bool {
+        let dx = (to_x as i64 - from_x as i64).abs();
+        let dy = -(to_y as i64 - from_y as i64).abs();
+        let step_x: i64 = if from_x < to_x { 1 } else { -1 };
+        let step_y: i64 = if from_y < to_y { 1 } else { -1 };
+        let mut x = from_x as i64;
+        let mut y = from_y as i64;
+        let mut err = dx + dy;
+        loop {
+            if x == to_x as i64 && y == to_y as i64 {
+                return true;
+            }
+            if (x != from_x as i64 || y != from_y as i64) && self.blocks_sight(x as u64, y as u64) {
+                return false;
+            }
+            let err2 = 2 * err;
+            if err2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if err2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    // Returns how many tiles were seen for the first time
+    fn update_fov(&mut self, player_x: u64, player_y: u64, radius: i64) -> u32 {
+        let mut newly_explored = 0;
+        for i in 0..self.map_size() {
+            self.visible[i] = false;
+        }
+        let player_index = (player_y as usize) * MAP_WIDTH + (player_x as usize);
+        self.visible[player_index] = true;
+        self.explored[player_index] = true;
+        for dy in -radius..(radius + 1) {
+            for dx in -radius..(radius + 1) {
+                let x = player_x as i64 + dx;
+                let y = player_y as i64 + dy;
+                let in_bounds = x >= 0 && y >= 0 && (x as usize) < MAP_WIDTH && (y as usize) < MAP_HEIGHT;
+                if !in_bounds || dx * dx + dy * dy > radius * radius {
+                    continue;
+                }
+                if self.has_line_of_sight(player_x, player_y, x as u64, y as u64) {
+                    let index = (y as usize) * MAP_WIDTH + (x as usize);
+                    self.visible[index] = true;
+                    if !self.explored[index] {
+                        self.explored[index] = true;
+                        newly_explored += 1;
+                    }
+                }
+            }
+        }
+        newly_explored
+    }
+
+    fn is_visible(&self, x: u64, y: u64) -> bool {
+        self.visible[(y as usize) * MAP_WIDTH + (x as usize)]
+    }
+
+    fn is_explored(&self, x: u64, y: u64) -> bool {
+        self.explored[(y as usize) * MAP_WIDTH + (x as usize)]
+    }
+
+    // Marks every walkable tile and the walls bordering them as explored
+    fn reveal_map(&mut self) {
+        for y in 1..(MAP_HEIGHT - 1) {
+            for x in 1..(MAP_WIDTH - 1) {
+                if self.map[y * MAP_WIDTH + x] == WALL_CHAR {
+                    continue;
+                }
+                for ny in (y - 1)..(y + 2) {
+                    for nx in (x - 1)..(x + 2) {
+                        self.explored[ny * MAP_WIDTH + nx] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Flood fills passable tiles starting at the given position, returns how many were reached
+    fn find_reachable(
+        &self, start_x: u64, start_y: u64, unlocked: u8, 
+        reachable: &mut [bool; MAP_WIDTH * MAP_HEIGHT]
+    ) -> usize {
+        let queue = unsafe { &mut *core::ptr::addr_of_mut!(FLOOD_QUEUE) };
+        let mut head = 0;
+        let mut tail = 0;
+        reachable.fill(false);
+        let start = (start_y as usize) * MAP_WIDTH + (start_x as usize);
+        reachable[start] = true;
+        queue[tail] = start as u32;
+        tail += 1;
+        while head < tail {
+            let index = queue[head] as usize;
+            head += 1;
+            let x = index % MAP_WIDTH;
+            let y = index / MAP_WIDTH;
+            let neighbours = [(x + 1, y), (x.wrapping_sub(1), y), (x, y + 1), (x, y.wrapping_sub(1))];
+            for (nx, ny) in neighbours.iter() {
+                if *nx >= MAP_WIDTH || *ny >= MAP_HEIGHT {
+                    continue;
+                }
+                let neighbour = ny * MAP_WIDTH + nx;
+                if !reachable[neighbour] && self.is_passable(*nx as u64, *ny as u64, unlocked) {
+                    reachable[neighbour] = true;
+                    queue[tail] = neighbour as u32;
+                    tail += 1;
+                }
+            }
+        }
+        tail
+    }
+
+    // Shortest walk between two tiles, None if there isn't one
+    fn path_length(&self, from_x: u64, from_y: u64, to_x: u64, to_y: u64, unlocked: u8) -> Option<u64> {
+        let distance = unsafe { &mut *core::ptr::addr_of_mut!(FLOOD_DISTANCE) };
+        let queue = unsafe { &mut *core::ptr::addr_of_mut!(FLOOD_QUEUE) };
+        let mut head = 0;
+        distance[..self.map_size()].fill(u32::MAX);
+        let mut tail = 0;
+        let start = (from_y as usize) * MAP_WIDTH + (from_x as usize);
+        let goal = (to_y as usize) * MAP_WIDTH + (to_x as usize);
+        distance[start] = 0;
+        queue[tail] = start as u32;
+        tail += 1;
+        while head < tail {
+            let index = queue[head] as usize;
+            head += 1;
+            if index == goal {
+                return Some(distance[index] as u64);
+            }
+            let x = index % MAP_WIDTH;
+            let y = index / MAP_WIDTH;
+            let neighbours = [(x + 1, y), (x.wrapping_sub(1), y), (x, y + 1), (x, y.wrapping_sub(1))];
+            for (nx, ny) in neighbours.iter() {
+                if *nx >= MAP_WIDTH || *ny >= MAP_HEIGHT {
+                    continue;
+                }
+                let neighbour = ny * MAP_WIDTH + nx;
+                if distance[neighbour] == u32::MAX && self.is_passable(*nx as u64, *ny as u64, unlocked) {
+                    distance[neighbour] = distance[index] + 1;
+                    queue[tail] = neighbour as u32;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+
+    // Picks a random empty floor tile the player could walk to from where they stand
+    fn random_reachable_floor(&self, from_x: u64, from_y: u64, rng: &mut XorshiftRng) -> Option<(u64, u64)> {
+        let mut reachable = [false; MAP_WIDTH * MAP_HEIGHT];
+        self.find_reachable(from_x, from_y, 0, &mut reachable);
+        let from = (from_y as usize) * MAP_WIDTH + (from_x as usize);
+        let is_choice = |i: usize| reachable[i] && self.is_empty_floor(i) && i != from;
+        let count = (0..self.map_size()).filter(|i| is_choice(*i)).count() as u64;
+        if count == 0 {
+            return None;
+        }
+        let choice = rng.range(count) as usize;
+        let i = (0..self.map_size()).filter(|i| is_choice(*i)).nth(choice)?;
+        Some(((i % MAP_WIDTH) as u64, (i / MAP_WIDTH) as u64))
+    }
+
+    fn is_empty_floor(&self, index: usize) -> bool {
+        let x = (index % MAP_WIDTH) as u64;
+        let y = (index / MAP_WIDTH) as u64;
+        self.map[index] == FLOOR_CHAR && self.monster_index_at(x, y).is_none()
+    }
+
+    // Returns the experience the monster was worth
+    fn kill_monster(&mut self, index: usize) -> u32 {
+        let monster = self.monsters[index];
+        self.remove_monster(index);
+        if self.loot_rng.range(4) == 0 {
+            if let Some(loot) = roll_gear(&mut self.loot_rng, self.depth) {
+                self.add_floor_item(monster.x, monster.y, loot);
+            }
+        }
+        monster.def().xp
+    }
+
+    // Per turn effects of every monster's statuses. Returns the experience from monsters that died.
+    fn process_monster_statuses(&mut self, messages: &mut TurnMessages) -> u32 {
+        let mut xp = 0;
+        let mut i = 0;
+        while i < self.num_monsters {
+            let monster = &mut self.monsters[i];
+            if monster.effects.has(StatusKind::Poison) {
+                monster.hp -= monster.effects.potency(StatusKind::Poison) as i32;
+            }
+            if monster.effects.has(StatusKind::Regeneration) {
+                let healed = monster.hp + monster.effects.potency(StatusKind::Regeneration) as i32;
+                monster.hp = core::cmp::min(healed, monster.max_hp());
+            }
+            monster.effects.tick();
+            // Innate statuses never wear off
+            if let Some(innate) = monster.def().innate {
+                monster.effects.apply(innate, STATUS_DEFS[innate as usize].max_turns, 1);
+            }
+            if monster.hp <= 0 {
+                messages.push("The ");
+                messages.push(monster.def().name);
+                messages.push(" succumbs to poison. ");
+                xp += self.kill_monster(i);
+            } else {
+                i += 1;
+            }
+        }
+        xp
+    }
+
+    // Monsters close enough to the player chase them and attack when adjacent
+    fn monsters_take_turn(&mut self, player: &mut Player, rng: &mut XorshiftRng, messages: &mut TurnMessages) {
+        for i in 0..self.num_monsters {
+            let monster = &mut self.monsters[i];
+            monster.energy += effective_speed(monster.def().speed, &monster.effects);
+            while self.monsters[i].energy >= ACTION_ENERGY {
+                self.monsters[i].energy -= ACTION_ENERGY;
+                self.monster_act(i, player, rng, messages);
+            }
+        }
+    }
+
+    fn monster_act(&mut self, index: usize, player: &mut Player, rng: &mut XorshiftRng, messages: &mut TurnMessages) {
+        let monster = self.monsters[index];
+        let mut dx = player.x as i64 - monster.x as i64;
+        let mut dy = player.y as i64 - monster.y as i64;
+        let adjacent = dx.abs() + dy.abs() == 1;
+        if !monster.awake {
+            let notices_player = adjacent || (self.is_visible(monster.x, monster.y) 
+                && dx.abs() <= MONSTER_SIGHT && dy.abs() <= MONSTER_SIGHT);
+            if notices_player && !monster.effects.has(StatusKind::Blindness) {
+                self.monsters[index].awake = true;
+            }
+            return;
+        }
+        if monster.effects.has(StatusKind::Confusion) {
+            let (random_dx, random_dy) = confused_direction(rng);
+            dx = random_dx;
+            dy = random_dy;
+        } else if !adjacent && monster.effects.has(StatusKind::Blindness) {
+            return;
+        }
+
+        // Step along the longer axis first and fall back to the other one if blocked
+        let step_x = (monster.x as i64 + dx.signum()) as u64;
+        let step_y = (monster.y as i64 + dy.signum()) as u64;
+        let options = if dx.abs() >= dy.abs() {
+            [(step_x, monster.y), (monster.x, step_y)]
+        } else {
+            [(monster.x, step_y), (step_x, monster.y)]
+        };
+
+        if (options[0].0 as usize, options[0].1 as usize) == (player.x, player.y) {
+            let damage = roll_damage(rng, monster.attack(), player.defense());
+            player.hp -= damage;
+            player.death_cause = Some(DeathCause::Monster(monster.kind));
+            messages.push("The ");
+            messages.push(monster.def().name);
+            messages.push(" hits you for ");
+            messages.push_number(damage as u64);
+            messages.push(". ");
+            if let Some((status, turns)) = monster.def().inflicts {
+                if rng.range(3) == 0 {
+                    player.effects.apply(status, turns, 1);
+                }
+            }
+            return;
+        }
+        if monster.def().flags & MONSTER_FLAG_STATIONARY != 0 {
+            return;
+        }
+
+        for (x, y) in options.iter() {
+            let is_free = (*x, *y) != (monster.x, monster.y)
+                && (*x as usize, *y as usize) != (player.x, player.y)
+                && self.is_walkable_for_monster(*x, *y)
+                && self.monster_index_at(*x, *y).is_none();
+            if is_free {
+                self.monsters[index].x = *x;
+                self.monsters[index].y = *y;
+                break;
+            }
+        }
+    }
+
+    // Applies a status to every monster the player can currently see, returns how many were hit
+    fn apply_status_in_view(&mut self, kind: StatusKind, turns: u16) -> usize {
+        let mut count = 0;
+        for i in 0..self.num_monsters {
+            if self.is_visible(self.monsters[i].x, self.monsters[i].y) {
+                self.monsters[i].effects.apply(kind, turns, 1);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+fn loop_until_continue() {
+    loop {
+        let cur_key = get_input();
+        if cur_key == KEY_CONTINUE {
+            break;
+        }
+    }
+}
+
+// Draws the fixed message area with the latest messages. If more came in than the area can 
+// hold they are shown a page at a time with a --more-- prompt first.
+fn draw_message_area(log: &mut MessageLog) {
+    let mut line = MessageLine::new();
+    while log.unseen > MESSAGE_AREA_LINES {
+        // Save the cursor so each page is drawn over the last one
+        print("\x1b[s");
+        let first = log.len - log.unseen;
+        for i in first..(first + MESSAGE_AREA_LINES - 1) {
+            line.clear();
+            log.get(i).format(&mut line);
+            line.push("\n");
+            print(line.as_str());
+        }
+        print("--more--");
+        loop_until_continue();
+        print("\x1b[u\x1b[J");
+        log.unseen -= MESSAGE_AREA_LINES - 1;
+    }
+    log.unseen = 0;
+    for i in 0..MESSAGE_AREA_LINES {
+        line.clear();
+        if log.len + i >= MESSAGE_AREA_LINES {
+            log.get(log.len + i - MESSAGE_AREA_LINES).format(&mut line);
+        }
+        line.push("\n");
+        print(line.as_str());
+    }
+}
+
+// Scrollable list of every logged message, newest at the bottom
+fn show_history(log: &MessageLog) {
+    let mut top = log.len.saturating_sub(HISTORY_PAGE_LINES);
+    let mut line = MessageLine::new();
+    loop {
+        clear_screen();
+        print("Message history\n\n");
+        for i in top..core::cmp::min(top + HISTORY_PAGE_LINES, log.len) {
+            let entry = log.get(i);
+            line.clear();
+            line.push("[turn ");
+            line.push_number(entry.turn);
+            line.push("] ");
+            entry.format(&mut line);
+            line.push("\n");
+            print(line.as_str());
+        }
+        print("\n[up/down to scroll, left/right for pages, space to close]");
+        let last_top = log.len.saturating_sub(HISTORY_PAGE_LINES);
+        loop {
+            match wait_for_key() {
+                KEY_UP => top = top.saturating_sub(1),
+                KEY_DOWN => top = core::cmp::min(top + 1, last_top),
+                KEY_LEFT => top = top.saturating_sub(HISTORY_PAGE_LINES),
+                KEY_RIGHT => top = core::cmp::min(top + HISTORY_PAGE_LINES, last_top),
+                KEY_CONTINUE => return,
+                _ => continue,
+            }
+            break;
+        }
+    }
+}
+
+// The HUD is built from widgets, each one a short piece of text. They are laid out in a panel 
+// beside the map when the terminal is wide enough and packed into lines below it otherwise.
+#[derive(Clone, Copy, PartialEq)]
+enum Widget {
+    Depth,
+    Mode,
+    Health,
+    Level,
+    Turn,
+    Seed,
+    Keys,
+    Pack,
+    Ability,
+    Statuses,
+}
+const HUD_WIDGETS: [Widget; 10] = [
+    Widget::Depth, Widget::Mode, Widget::Health, Widget::Level, Widget::Turn, Widget::Seed,
+    Widget::Keys, Widget::Pack, Widget::Ability, Widget::Statuses,
+];
+const HUD_PANEL_WIDTH: usize = 30;
+const HUD_PANEL_GAP: usize = 2;
+const HUD_MAX_LINES: usize = 20;
+const HP_BAR_WIDTH: u64 = 10;
+
+struct HudInfo<'a> {
+    player: &'a Player,
+    depth: u64,
+    turn: u64,
+    seed: u64,
+    permadeath: bool,
+}
+
+// Writes the widget's text into the line, widgets with nothing to show leave it empty
+fn format_widget(widget: Widget, hud: &HudInfo, line: &mut MessageLine) {
+    let player = hud.player;
+    match widget {
+        Widget::Depth => {
+            line.push("Depth ");
+            line.push_number(hud.depth);
+        },
+        Widget::Mode => {
+            if hud.permadeath {
+                line.push("Permadeath");
+            }
+        },
+        Widget::Health => {
+            let hp = core::cmp::max(player.hp, 0) as u64;
+            let max_hp = core::cmp::max(player.max_hp(), 1) as u64;
+            let filled = core::cmp::min((hp * HP_BAR_WIDTH).div_ceil(max_hp), HP_BAR_WIDTH);
+            line.push("HP [");
+            for i in 0..HP_BAR_WIDTH {
+                line.push_char(if i < filled { '=' } else { '-' });
+            }
+            line.push("] ");
+            line.push_number(hp);
+            line.push("/");
+            line.push_number(max_hp);
+        },
+        Widget::Level => {
+            line.push("Lvl ");
+            line.push_number(player.level as u64);
+            line.push(" XP ");
+            line.push_number(player.xp as u64);
+            if let Some(next) = player.next_level_xp() {
+                line.push("/");
+                line.push_number(next as u64);
+            }
+        },
+        Widget::Turn => {
+            line.push("Turn ");
+            line.push_number(hud.turn);
+        },
+        Widget::Seed => {
+            line.push("Seed ");
+            line.push_number(hud.seed);
+        },
+        Widget::Keys => {
+            line.push("Keys");
+            let mut has_key = false;
+            if player.inventory.find(ItemKind::StairsKey).is_some() {
+                line.push(" ");
+                line.push_char(KEY_CHAR);
+                has_key = true;
+            }
+            for colour in 0..NUM_LOCK_COLOURS {
+                if player.inventory.find(COLOURED_KEY_KINDS[colour]).is_some() {
+                    line.push(" ");
+                    line.push_char(LOCKED_DOOR_CHARS[colour]);
+                    has_key = true;
+                }
+            }
+            if !has_key {
+                line.push(" -");
+            }
+        },
+        Widget::Pack => {
+            line.push("Pack ");
+            line.push_number(player.inventory.len() as u64);
+            line.push("/");
+            line.push_number(INVENTORY_CAPACITY as u64);
+        },
+        Widget::Ability => {
+            line.push(player.class_def().ability_name);
+            if player.ability_cooldown > 0 {
+                line.push(" in ");
+                line.push_number(player.ability_cooldown as u64);
+            } else {
+                line.push(" ready");
+            }
+        },
+        Widget::Statuses => {
+            let mut statuses = MessageLine::new();
+            format_statuses(&player.effects, &mut statuses);
+            let stage = hunger_stage(player.nutrition);
+            if stage > 0 {
+                statuses.push(" ");
+                statuses.push(HUNGER_STAGES[stage - 1].indicator);
+            }
+            if !statuses.is_empty() {
+                line.push("Status");
+                line.push(statuses.as_str());
+            }
+        },
+    }
+}
+
+// Fills lines with the HUD widgets wrapped to the given width. With one_per_line every widget 
+// starts on its own line, otherwise they are packed side by side. Returns the number of lines.
+fn layout_hud(hud: &HudInfo, width: usize, one_per_line: bool, lines: &mut [MessageLine; HUD_MAX_LINES]) -> usize {
+    let mut num_lines = 0;
+    let mut widget_text = MessageLine::new();
+    for widget in HUD_WIDGETS.iter() {
+        widget_text.clear();
+        format_widget(*widget, hud, &mut widget_text);
+        if widget_text.is_empty() {
+            continue;
+        }
+        // Wrap word by word so long widgets like the status list still fit narrow layouts
+        let mut separator = if one_per_line || num_lines == 0 { 0 } else { 2 };
+        if one_per_line || num_lines == 0 || lines[num_lines - 1].len + separator + widget_text.len > width {
+            if num_lines == HUD_MAX_LINES {
+                break;
+            }
+            lines[num_lines].clear();
+            num_lines += 1;
+            separator = 0;
+        }
+        for word in widget_text.as_str().split(' ') {
+            let line = &mut lines[num_lines - 1];
+            if line.len > 0 && line.len + separator + word.len() > width {
+                if num_lines == HUD_MAX_LINES {
+                    break;
+                }
+                lines[num_lines].clear();
+                num_lines += 1;
+                separator = 0;
+            }
+            let line = &mut lines[num_lines - 1];
+            for _ in 0..separator {
+                line.push_char(' ');
+            }
+            line.push(word);
+            separator = 1;
+        }
+    }
+    num_lines
+}
+
+// Draws the map window with the HUD beside it or below it depending on the terminal width
+fn draw_screen(window: &[char], colours: &[Colour], window_width: usize, window_height: usize, hud: &HudInfo) {
+    let (columns, _) = terminal_size();
+    let mut lines = [MessageLine::new(); HUD_MAX_LINES];
+    let side_panel = columns >= window_width + HUD_PANEL_GAP + HUD_PANEL_WIDTH;
+    let num_lines = if side_panel {
+        layout_hud(hud, HUD_PANEL_WIDTH, true, &mut lines)
+    } else {
+        layout_hud(hud, core::cmp::max(columns, window_width), false, &mut lines)
+    };
+    let mut row = MessageLine::new();
+    // Longest thing pushed for one cell, a colour escape and the glyph
+    const CELL_MAX_LEN: usize = 10;
+    for h in 0..window_height {
+        row.clear();
+        let mut colour = Colour::Default;
+        for w in 0..window_width {
+            // Lots of coloured glyphs in a row could overflow the line, print what's there first
+            if row.len + CELL_MAX_LEN * 2 > MESSAGE_LINE_CAPACITY {
+                print(row.as_str());
+                row.clear();
+            }
+            let cell_colour = colours[h * window_width + w];
+            if cell_colour != colour {
+                row.push(COLOUR_ESCAPES[cell_colour as usize]);
+                colour = cell_colour;
+            }
+            row.push_char(window[h * window_width + w]);
+        }
+        if colour != Colour::Default {
+            row.push(COLOUR_ESCAPES[Colour::Default as usize]);
+        }
+        if side_panel && h < num_lines {
+            if row.len + HUD_PANEL_GAP + lines[h].len + 1 > MESSAGE_LINE_CAPACITY {
+                print(row.as_str());
+                row.clear();
+            }
+            for _ in 0..HUD_PANEL_GAP {
+                row.push_char(' ');
+            }
+            row.push(lines[h].as_str());
+        }
+        row.push("\n");
+        print(row.as_str());
+    }
+    if !side_panel {
+        for line in lines[..num_lines].iter() {
+            print(line.as_str());
+            print("\n");
+        }
+    }
+}
+
+// Each braille character packs a 2x4 block of dots
+const BRAILLE_BASE: u32 = 0x2800;
+const BRAILLE_CELL_WIDTH: usize = 2;
+const BRAILLE_CELL_HEIGHT: usize = 4;
+// Indexed by [dot row][dot column]
+const BRAILLE_DOT_BITS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+// Whole floor overview drawn with braille dots for explored walkable tiles. At full detail every 
+// tile is one dot and the view pans with the arrow keys, zooming out shrinks the floor to fit.
+fn show_map_overview<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, player: &Player
+) 
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let mut zoomed_out = false;
+    // Top left of the view in tiles
+    let mut view_x: usize = 0;
+    let mut view_y: usize = 0;
+    let mut centred = false;
+    let mut line = MessageLine::new();
+    loop {
+        let (columns, rows) = terminal_size();
+        // Leave room for the title and the controls
+        let view_columns = core::cmp::max(columns, 1);
+        let view_rows = core::cmp::max(rows.saturating_sub(3), 1);
+        let scale = if zoomed_out {
+            let scale_x = MAP_WIDTH.div_ceil(view_columns * BRAILLE_CELL_WIDTH);
+            let scale_y = MAP_HEIGHT.div_ceil(view_rows * BRAILLE_CELL_HEIGHT);
+            core::cmp::max(core::cmp::max(scale_x, scale_y), 1)
+        } else {
+            1
+        };
+        let cell_width = BRAILLE_CELL_WIDTH * scale;
+        let cell_height = BRAILLE_CELL_HEIGHT * scale;
+        let view_width = view_columns * cell_width;
+        let view_height = view_rows * cell_height;
+        let max_view_x = MAP_WIDTH.saturating_sub(view_width);
+        let max_view_y = MAP_HEIGHT.saturating_sub(view_height);
+        if zoomed_out {
+            view_x = 0;
+            view_y = 0;
+        } else if !centred {
+            view_x = player.x.saturating_sub(view_width / 2);
+            view_y = player.y.saturating_sub(view_height / 2);
+            centred = true;
+        }
+        view_x = core::cmp::min(view_x, max_view_x);
+        view_y = core::cmp::min(view_y, max_view_y);
+
+        let key_found = dungeon.is_explored(dungeon.key_x, dungeon.key_y)
+            && dungeon.item_index_at(dungeon.key_x, dungeon.key_y)
+                .is_some_and(|index| dungeon.items[index].item.kind == ItemKind::StairsKey);
+        clear_screen();
+        print("Map of depth ");
+        print_number(dungeon.depth);
+        print("\n");
+        for row in 0..view_rows {
+            let cell_y = view_y + row * cell_height;
+            if cell_y >= MAP_HEIGHT {
+                break;
+            }
+            line.clear();
+            for column in 0..view_columns {
+                let cell_x = view_x + column * cell_width;
+                if cell_x >= MAP_WIDTH {
+                    break;
+                }
+                let in_cell = |x: u64, y: u64| {
+                    (x as usize) >= cell_x && (x as usize) < cell_x + cell_width
+                        && (y as usize) >= cell_y && (y as usize) < cell_y + cell_height
+                };
+                let glyph = if in_cell(player.x as u64, player.y as u64) {
+                    PLAYER_CHAR
+                } else if key_found && in_cell(dungeon.key_x, dungeon.key_y) {
+                    KEY_CHAR
+                } else if dungeon.is_explored(dungeon.stairs_x, dungeon.stairs_y) && in_cell(dungeon.stairs_x, dungeon.stairs_y) {
+                    STAIRS_CHAR
+                } else {
+                    let mut bits = 0;
+                    for (dot_y, dot_row) in BRAILLE_DOT_BITS.iter().enumerate() {
+                        for (dot_x, dot_bit) in dot_row.iter().enumerate() {
+                            // A dot is lit if any tile it covers is explored and walkable
+                            let mut lit = false;
+                            for y in (cell_y + dot_y * scale)..core::cmp::min(cell_y + (dot_y + 1) * scale, MAP_HEIGHT) {
+                                for x in (cell_x + dot_x * scale)..core::cmp::min(cell_x + (dot_x + 1) * scale, MAP_WIDTH) {
+                                    let index = y * MAP_WIDTH + x;
+                                    lit |= dungeon.explored[index] && dungeon.map[index] != WALL_CHAR;
+                                }
+                            }
+                            if lit {
+                                bits |= dot_bit;
+                            }
+                        }
+                    }
+                    if bits == 0 { ' ' } else { char::from_u32(BRAILLE_BASE + bits).unwrap_or(' ') }
+                };
+                line.push_char(glyph);
+                // Flush before the buffer fills up, braille characters take three bytes each
+                if line.len + 4 > MESSAGE_LINE_CAPACITY {
+                    print(line.as_str());
+                    line.clear();
+                }
+            }
+            line.push("\n");
+            print(line.as_str());
+        }
+        if zoomed_out {
+            print("[z to zoom in, space to close]");
+        } else {
+            print("[arrow keys to pan, z to zoom out, space to close]");
+        }
+        let pan_x = core::cmp::max(view_width / 4, 1);
+        let pan_y = core::cmp::max(view_height / 4, 1);
+        loop {
+            match wait_for_key() {
+                KEY_UP => view_y = view_y.saturating_sub(pan_y),
+                KEY_DOWN => view_y = core::cmp::min(view_y + pan_y, max_view_y),
+                KEY_LEFT => view_x = view_x.saturating_sub(pan_x),
+                KEY_RIGHT => view_x = core::cmp::min(view_x + pan_x, max_view_x),
+                KEY_ZOOM => {
+                    zoomed_out = !zoomed_out;
+                    centred = false;
+                },
+                KEY_CONTINUE => return,
+                _ => continue,
+            }
+            break;
+        }
+    }
+}
+
+const HEALING_AMOUNT: i32 = 15;
+const RATION_HEALING: i32 = 5;
+
+fn use_item<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    player: &mut Player, slot: usize, 
+    dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>, rng: &mut XorshiftRng, messages: &mut TurnMessages
+) 
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let item = match player.inventory.slots[slot] {
+        Some(item) => item,
+        None => return,
+    };
+    if item.slot().is_some() {
+        equip_item(player, slot, messages);
+        return;
+    }
+    let was_known = player.identification.is_known(item.kind);
+    match item.kind {
+        ItemKind::StairsKey => {
+            messages.push("The key will unlock the stairs once you reach them. ");
+            return;
+        },
+        ItemKind::PotionHealing => {
+            player.hp = core::cmp::min(player.hp + HEALING_AMOUNT, player.max_hp());
+            messages.push("You feel much better. ");
+        },
+        ItemKind::PotionStrength => {
+            player.effects.apply(StatusKind::Strength, 40, 3);
+            messages.push("You feel stronger. ");
+        },
+        ItemKind::PotionToughness => {
+            // The extra HP comes with the extra maximum, drinking another only tops up the turns
+            let max_hp = player.max_hp();
+            player.effects.apply(StatusKind::Toughness, 40, 10);
+            player.hp += player.max_hp() - max_hp;
+            messages.push("You feel tougher. ");
+        },
+        ItemKind::PotionHaste => {
+            player.effects.apply(StatusKind::Haste, 20, 1);
+            messages.push("You feel quick. ");
+        },
+        ItemKind::PotionRegeneration => {
+            player.effects.apply(StatusKind::Regeneration, 20, 1);
+            messages.push("Your skin tingles. ");
+        },
+        ItemKind::PotionConfusion => {
+            player.effects.apply(StatusKind::Confusion, 10, 1);
+            messages.push("Huh? The room is spinning. ");
+        },
+        ItemKind::PotionBlindness => {
+            player.effects.apply(StatusKind::Blindness, 15, 1);
+            messages.push("Everything goes dark. ");
+        },
+        ItemKind::ScrollConfuseMonsters => {
+            if dungeon.apply_status_in_view(StatusKind::Confusion, 10) > 0 {
+                messages.push("The monsters around you stagger about. ");
+            } else {
+                messages.push("You hear distant, confused shuffling. ");
+            }
+        },
+        ItemKind::ScrollMapping => {
+            dungeon.reveal_map();
+            messages.push("A map of the floor forms in your mind. ");
+        },
+        ItemKind::ScrollTeleport => {
+            match dungeon.random_reachable_floor(player.x as u64, player.y as u64, rng) {
+                Some((x, y)) => {
+                    player.x = x as usize;
+                    player.y = y as usize;
+                    messages.push("The world spins around you. ");
+                },
+                None => messages.push("You feel a brief tug. "),
+            }
+        },
+        ItemKind::ScrollIdentify => {
+            // Identify the scroll first so it doesn't show up in its own prompt
+            player.identification.identify(item.kind);
+            player.inventory.consume(slot);
+            identify_prompt(player, messages);
+            if let Some(script) = item.def().on_use {
+                run_script(script, player, dungeon, rng, messages);
+            }
+            return;
+        },
+        ItemKind::Ration => {
+            player.hp = core::cmp::min(player.hp + RATION_HEALING, player.max_hp());
+            player.nutrition = core::cmp::min(player.nutrition + RATION_NUTRITION, MAX_NUTRITION);
+            messages.push("That food really hit the spot. ");
+        },
+        // Items that do nothing themselves can be given a use by a script, they aren't used up
+        _ => {
+            match item.def().on_use {
+                Some(script) => run_script(script, player, dungeon, rng, messages),
+                None => messages.push("You can't use that. "),
+            }
+            return;
+        },
+    }
+    player.inventory.consume(slot);
+    player.identification.identify(item.kind);
+    if !was_known {
+        messages.push("It was a ");
+        messages.push(item.name());
+        messages.push(". ");
+    }
+    if let Some(script) = item.def().on_use {
+        run_script(script, player, dungeon, rng, messages);
+    }
+}
+
+fn identify_prompt(player: &mut Player, messages: &mut TurnMessages) {
+    let mut has_unknown = false;
+    for item in player.inventory.slots.iter().flatten() {
+        if !player.identification.is_known(item.kind) {
+            has_unknown = true;
+        }
+    }
+    if !has_unknown {
+        messages.push("This is a scroll of identify. You have nothing left to identify. ");
+        return;
+    }
+    loop {
+        let slot = match prompt_inventory_slot(player, "This is a scroll of identify. Identify which item?") {
+            Some(slot) => slot,
+            None => {
+                messages.push("You roll up the scroll unread. ");
+                return;
+            },
+        };
+        if let Some(item) = player.inventory.slots[slot] {
+            if !player.identification.is_known(item.kind) {
+                player.identification.identify(item.kind);
+                messages.push("It is a ");
+                format_item(&item, &player.identification, messages);
+                messages.push(". ");
+                return;
+            }
+        }
+    }
+}
+
+fn equip_item(player: &mut Player, slot: usize, messages: &mut TurnMessages) {
+    let item = match player.inventory.slots[slot] {
+        Some(item) => item,
+        None => return,
+    };
+    if player.equip(slot) {
+        messages.push("You equip the ");
+        format_item(&item, &player.identification, messages);
+        messages.push(". ");
+    } else {
+        messages.push("You can't equip that. ");
+    }
+}
+
+// Returns the direction picked with the arrow keys, or None if the player cancelled
+fn prompt_direction(prompt: &str) -> Option<(i64, i64)> {
+    print(prompt);
+    print(" [arrow keys, space to cancel]");
+    loop {
+        match wait_for_key() {
+            KEY_UP => return Some((0, -1)),
+            KEY_DOWN => return Some((0, 1)),
+            KEY_LEFT => return Some((-1, 0)),
+            KEY_RIGHT => return Some((1, 0)),
+            KEY_CONTINUE => return None,
+            _ => (),
+        }
+    }
+}
+
+fn wait_for_key() -> u8 {
+    loop {
+        let cur_key = get_input();
+        if cur_key != 0 {
+            return cur_key;
+        }
+    }
+}
+
+// Line editor for text entry, printable characters are typed and backspace deletes. Arrow keys 
+// and other escape sequences are swallowed. Returns the length of the text once enter is pressed.
+fn read_text(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        match wait_for_key() {
+            KEY_ENTER | b'\n' => return len,
+            KEY_BACKSPACE | 8 => {
+                if len > 0 {
+                    len -= 1;
+                    print("\x08 \x08");
+                }
+            },
+            KEY_ESCAPE => {
+                if get_input() == b'[' {
+                    wait_for_key();
+                }
+            },
+            key if (32..127).contains(&key) && len < buf.len() => {
+                buf[len] = key;
+                len += 1;
+                print_char(key as char);
+            },
+            _ => (),
+        }
+    }
+}
+
+// Shows a lettered list and returns the index of the option picked
+fn choose_option(title: &str, names: &[&str], descriptions: &[&str]) -> usize {
+    clear_screen();
+    print(title);
+    print("\n\n");
+    let mut line = MessageLine::new();
+    for i in 0..names.len() {
+        line.clear();
+        line.push_char(slot_letter(i));
+        line.push(") ");
+        line.push(names[i]);
+        line.push(" - ");
+        line.push(descriptions[i]);
+        line.push("\n");
+        print(line.as_str());
+    }
+    loop {
+        let cur_key = wait_for_key();
+        if cur_key >= b'a' && ((cur_key - b'a') as usize) < names.len() {
+            return (cur_key - b'a') as usize;
+        }
+    }
+}
+
+// New game menu, the player picks a class, a race and a name
+fn create_player(seed: u64) -> Player {
+    let mut names = [""; NUM_CLASSES];
+    let mut descriptions = [""; NUM_CLASSES];
+    for i in 0..NUM_CLASSES {
+        names[i] = CLASS_DEFS[i].name;
+        descriptions[i] = CLASS_DEFS[i].description;
+    }
+    let class = CLASSES[choose_option("Rusty Dungeon\n\nChoose a class:", &names, &descriptions)];
+
+    let mut names = [""; NUM_RACES];
+    let mut descriptions = [""; NUM_RACES];
+    for i in 0..NUM_RACES {
+        names[i] = RACE_DEFS[i].name;
+        descriptions[i] = RACE_DEFS[i].description;
+    }
+    let race = RACES[choose_option("Choose a race:", &names, &descriptions)];
+
+    clear_screen();
+    print("What is your name? ");
+    let mut name = [0u8; MAX_NAME_LEN];
+    let name_len = read_text(&mut name);
+    Player::new(seed, class, race, &name[..name_len])
+}
+
+fn choose_difficulty() -> Difficulty {
+    let mut names = [""; NUM_DIFFICULTIES];
+    let mut descriptions = [""; NUM_DIFFICULTIES];
+    for i in 0..NUM_DIFFICULTIES {
+        names[i] = DIFFICULTY_DEFS[i].name;
+        descriptions[i] = DIFFICULTY_DEFS[i].description;
+    }
+    DIFFICULTIES[choose_option("Choose a difficulty:", &names, &descriptions)]
+}
+
+fn prompt_yes_no(question: &str) -> bool {
+    print(question);
+    print(" [y/n]");
+    loop {
+        match wait_for_key() {
+            b'y' => return true,
+            b'n' => return false,
+            _ => (),
+        }
+    }
+}
+
+// Writes e.g. "Mira the Elf Mage" into the line
+fn format_title(player: &Player, line: &mut MessageLine) {
+    line.push(player.name());
+    line.push(" the ");
+    line.push(RACE_DEFS[player.race as usize].name);
+    line.push(" ");
+    line.push(player.class_def().name);
+}
+
+fn print_run_summary(player: &Player, depth: u64, turn: u64) {
+    let mut line = MessageLine::new();
+    format_title(player, &mut line);
+    line.push(", level ");
+    line.push_number(player.level as u64);
+    if player.hp <= 0 {
+        match player.death_cause {
+            Some(cause) => {
+                line.push(", ");
+                format_death_cause(cause, &mut line);
+                line.push(" on depth ");
+            },
+            None => line.push(", died on depth "),
+        }
+    } else {
+        line.push(", left the dungeon from depth ");
+    }
+    line.push_number(depth);
+    line.push(" after ");
+    line.push_number(turn);
+    line.push(" turns.\n");
+    print(line.as_str());
+}
+
+fn print_inventory(player: &Player) {
+    let inventory = &player.inventory;
+    print("Inventory (");
+    print_number(inventory.len() as u64);
+    print("/");
+    print_number(INVENTORY_CAPACITY as u64);
+    print(")\n\n");
+    for i in 0..INVENTORY_CAPACITY {
+        if let Some(item) = &inventory.slots[i] {
+            print_char(slot_letter(i));
+            print(") ");
+            print_char(item.glyph());
+            print(" ");
+            print_item(item, &player.identification);
+            if item.has_flag(ITEM_FLAG_QUEST) {
+                print(" [quest]");
+            }
+            print("\n");
+        }
+    }
+    if inventory.len() == 0 {
+        print("You are not carrying anything.\n");
+    }
+}
+
+fn show_character_screen(player: &Player, depth: u64) {
+    clear_screen();
+    let mut line = MessageLine::new();
+    format_title(player, &mut line);
+    line.push("\n\nHP ");
+    line.push_number(player.hp as u64);
+    line.push("/");
+    line.push_number(player.max_hp() as u64);
+    line.push("   Attack ");
+    line.push_number(player.attack() as u64);
+    line.push("   Defense ");
+    line.push_number(player.defense() as u64);
+    line.push("   Depth ");
+    line.push_number(depth);
+    line.push("\nLevel ");
+    line.push_number(player.level as u64);
+    line.push("   XP ");
+    line.push_number(player.xp as u64);
+    if let Some(next) = player.next_level_xp() {
+        line.push(" (next level at ");
+        line.push_number(next as u64);
+        line.push(")");
+    }
+    print(line.as_str());
+    line.clear();
+    line.push("\nPerks:");
+    let mut has_perk = false;
+    for (def, taken) in PERK_DEFS.iter().zip(player.perks.iter()) {
+        if *taken > 0 {
+            line.push(" ");
+            line.push(def.name);
+            if *taken > 1 {
+                line.push(" x");
+                line.push_number(*taken as u64);
+            }
+            has_perk = true;
+        }
+    }
+    if !has_perk {
+        line.push(" none");
+    }
+    line.push("\nAbility: ");
+    line.push(player.class_def().ability_name);
+    if player.ability_cooldown > 0 {
+        line.push(" (ready in ");
+        line.push_number(player.ability_cooldown as u64);
+        line.push(" turns)");
+    } else {
+        line.push(" (ready)");
+    }
+    line.push("\n\nEquipment\n");
+    print(line.as_str());
+    for (i, slot_name) in EQUIP_SLOT_NAMES.iter().enumerate() {
+        line.clear();
+        line.push_char(slot_letter(i));
+        line.push(") ");
+        line.push(slot_name);
+        line.push(": ");
+        match &player.equipment[i] {
+            Some(item) => {
+                format_item(item, &player.identification, &mut line);
+                line.push(" [");
+                format_bonus(&item.bonus(), &mut line);
+                line.push("]");
+            },
+            None => line.push("-"),
+        }
+        line.push("\n");
+        print(line.as_str());
+    }
+    let bonus = player.equipment_bonus();
+    line.clear();
+    line.push("\nGear bonuses: attack ");
+    line.push_signed(bonus.attack as i64);
+    line.push(", defense ");
+    line.push_signed(bonus.defense as i64);
+    line.push(", max HP ");
+    line.push_signed(bonus.max_hp as i64);
+    line.push("\n");
+    print(line.as_str());
+}
+
+// Level up screen, the player has to pick one of the perks
+fn choose_perk(player: &mut Player) {
+    clear_screen();
+    let mut line = MessageLine::new();
+    line.push("Welcome to level ");
+    line.push_number(player.level as u64);
+    line.push("!\n\nChoose a perk:\n\n");
+    print(line.as_str());
+    for (i, def) in PERK_DEFS.iter().enumerate() {
+        line.clear();
+        line.push_char(slot_letter(i));
+        line.push(") ");
+        line.push(def.name);
+        line.push(" - ");
+        line.push(def.description);
+        if player.perks[i] > 0 {
+            line.push(" (taken ");
+            line.push_number(player.perks[i] as u64);
+            line.push(")");
+        }
+        line.push("\n");
+        print(line.as_str());
+    }
+    loop {
+        let cur_key = wait_for_key();
+        if cur_key >= b'a' && ((cur_key - b'a') as usize) < NUM_PERKS {
+            player.take_perk(PERKS[(cur_key - b'a') as usize]);
+            return;
+        }
+    }
+}
+
+// Returns the selected equipment slot, or None if the player cancelled
+fn prompt_equipment_slot(player: &Player, depth: u64) -> Option<usize> {
+    show_character_screen(player, depth);
+    print("\nRemove which item? [a-");
+    print_char(slot_letter(NUM_EQUIP_SLOTS - 1));
+    print(", space to cancel]");
+    loop {
+        let cur_key = wait_for_key();
+        if cur_key == KEY_CONTINUE {
+            return None;
+        }
+        if cur_key >= b'a' && ((cur_key - b'a') as usize) < NUM_EQUIP_SLOTS {
+            let slot = (cur_key - b'a') as usize;
+            if player.equipment[slot].is_some() {
+                return Some(slot);
+            }
+        }
+    }
+}
+
+fn show_inventory_screen(player: &Player) {
+    clear_screen();
+    print_inventory(player);
+    print("\nPress space to continue");
+    loop_until_continue();
+}
+
+// Returns the selected slot, or None if the player cancelled
+fn prompt_inventory_slot(player: &Player, prompt: &str) -> Option<usize> {
+    clear_screen();
+    print_inventory(player);
+    print("\n");
+    print(prompt);
+    print(" [a-");
+    print_char(slot_letter(INVENTORY_CAPACITY - 1));
+    print(", space to cancel]");
+    loop {
+        let cur_key = wait_for_key();
+        if cur_key == KEY_CONTINUE {
+            return None;
+        }
+        if cur_key >= b'a' && ((cur_key - b'a') as usize) < INVENTORY_CAPACITY {
+            let slot = (cur_key - b'a') as usize;
+            if player.inventory.slots[slot].is_some() {
+                return Some(slot);
+            }
+        }
+    }
+}
+
+const PATH_CAPACITY: usize = 512;
+
+// Null terminated path for passing to syscalls
+#[derive(Clone, Copy)]
+struct PathBuf {
+    buf: [u8; PATH_CAPACITY],
+    len: usize,
+}
+
+impl PathBuf {
+    fn new() -> Self {
+        PathBuf { buf: [0u8; PATH_CAPACITY], len: 0 }
+    }
+
+    // Returns false if the path would get too long
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        // Keep room for the null terminator
+        if self.len + bytes.len() >= PATH_CAPACITY {
+            return false;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        self.buf[self.len] = 0;
+        true
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.buf.as_ptr()
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("?")
+    }
+}
+
+// $XDG_DATA_HOME/rusty-dungeon, or ~/.local/share/rusty-dungeon when it isn't set
+fn data_dir(env: &Env) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    match env.var("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => {
+            path.push(dir);
+        },
+        _ => {
+            let home = env.var("HOME")?;
+            if home.is_empty() {
+                return None;
+            }
+            path.push(home);
+            path.push(b"/.local/share");
+        },
+    }
+    if path.push(b"/rusty-dungeon") {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn data_file(env: &Env, name: &str) -> Option<PathBuf> {
+    let mut path = data_dir(env)?;
+    path.push(b"/");
+    if path.push(name.as_bytes()) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+// Creates the directory and any missing parents, like mkdir -p. Returns the errno on failure.
+fn create_dir_all(path: &PathBuf) -> Result<()> {
+    let mut partial = PathBuf::new();
+    for i in 0..path.len {
+        if path.buf[i] == b'/' && i > 0 {
+            partial.len = 0;
+            partial.push(&path.buf[..i]);
+            let ret = unsafe { mkdir(partial.as_ptr(), 0o755) };
+            if ret < 0 && -ret != EEXIST {
+                return Result::Err(-ret);
+            }
+        }
+    }
+    let ret = unsafe { mkdir(path.as_ptr(), 0o755) };
+    if ret < 0 && -ret != EEXIST {
+        return Result::Err(-ret);
+    }
+    Result::Ok(())
+}
+
+// Reads the whole file into buf, returns the number of bytes read
+fn read_file(path: &PathBuf, buf: &mut [u8]) -> Result<usize> {
+    let fd = unsafe { open(path.as_ptr(), O_RDONLY, 0) };
+    if fd < 0 {
+        return Result::Err(-fd);
+    }
+    let mut len = 0;
+    loop {
+        if len == buf.len() {
+            // Too big to be one of our files
+            unsafe { close(fd) };
+            return Result::Err(EFBIG);
+        }
+        let ret = unsafe { read(fd, buf.as_mut_ptr().add(len), buf.len() - len) };
+        if ret < 0 {
+            unsafe { close(fd) };
+            return Result::Err(-ret);
+        }
+        if ret == 0 {
+            break;
+        }
+        len += ret as usize;
+    }
+    unsafe { close(fd) };
+    Result::Ok(len)
+}
+
+fn write_all(fd: i32, bytes: &[u8]) -> Result<()> {
+    let mut written = 0;
+    while written < bytes.len() {
+        let ret = unsafe { write(fd, bytes.as_ptr().add(written), bytes.len() - written) };
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        written += ret as usize;
+    }
+    Result::Ok(())
+}
+
+// Writes to a temporary file next to the destination and renames it into place, so a crash 
+// half way through never leaves a truncated file behind
+fn write_file_atomic(path: &PathBuf, bytes: &[u8]) -> Result<()> {
+    let mut temp_path = PathBuf::new();
+    temp_path.push(&path.buf[..path.len]);
+    if !temp_path.push(b".tmp") {
+        return Result::Err(ENAMETOOLONG);
+    }
+    let fd = unsafe { open(temp_path.as_ptr(), O_WRONLY | O_CREAT | O_TRUNC, 0o644) };
+    if fd < 0 {
+        return Result::Err(-fd);
+    }
+    if let Result::Err(err) = write_all(fd, bytes) {
+        unsafe { close(fd) };
+        return Result::Err(err);
+    }
+    let ret = unsafe { fsync(fd) };
+    unsafe { close(fd) };
+    if ret < 0 {
+        return Result::Err(-ret);
+    }
+    let ret = unsafe { rename(temp_path.as_ptr(), path.as_ptr()) };
+    if ret < 0 {
+        return Result::Err(-ret);
+    }
+    Result::Ok(())
+}
+
+// 64 bit FNV-1a
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes.iter() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Little endian encoder for save files, anything past the end of the buffer sets overflowed
+struct ByteWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<'a> ByteWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        ByteWriter { buf, len: 0, overflowed: false }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buf.len() {
+            self.overflowed = true;
+            return;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+// Decoder matching ByteWriter, every read returns None once the data runs out
+struct ByteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        ByteReader { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.buf.len() {
+            return None;
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.bytes(2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.bytes(4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(self.u32()? as i32)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let b = self.bytes(8)?;
+        Some(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    // Reads an index into values, used to decode enums
+    fn pick<T: Copy>(&mut self, values: &[T]) -> Option<T> {
+        values.get(self.u8()? as usize).copied()
+    }
+}
+
+const SAVE_FILE_NAME: &str = "save.bin";
+const SAVE_MAGIC: &[u8; 4] = b"RDSV";
+// Bump whenever the layout below changes, older saves are then rejected
+const SAVE_VERSION: u32 = 3;
+// Magic, version, flags, payload length and payload checksum
+const SAVE_HEADER_SIZE: usize = 4 + 4 + 4 + 8 + 8;
+const SAVE_FLAG_PERMADEATH: u32 = 1;
+const SAVE_BUFFER_CAPACITY: usize = 160 * 1024;
+const AFFIX_VALUES: [Affix; 4] = [Affix::None, Affix::Might, Affix::Protection, Affix::Vitality];
+
+// Scratch space for encoding and decoding a save, too big for the stack. Only one save is read or
+// written at a time.
+static mut SAVE_BUFFER: [u8; SAVE_BUFFER_CAPACITY] = [0; SAVE_BUFFER_CAPACITY];
+
+enum SaveError {
+    // Neither $XDG_DATA_HOME nor $HOME are set
+    NoDataDir,
+    NotFound,
+    Io(i32),
+    NotASave,
+    Version(u32),
+    Checksum,
+    Corrupt,
+}
+
+fn print_save_error(err: &SaveError) {
+    match err {
+        SaveError::NoDataDir => print("Set $XDG_DATA_HOME or $HOME to choose where saves go."),
+        SaveError::NotFound => print("There is no saved game."),
+        SaveError::Io(errno) => {
+            print("The save file couldn't be accessed (errno ");
+            print_number(*errno as u64);
+            print(").");
+        },
+        SaveError::NotASave => print("The save file isn't a Rusty Dungeon save."),
+        SaveError::Version(version) => {
+            print("The save was made by a different version of the game (save format ");
+            print_number(*version as u64);
+            print(", this game reads format ");
+            print_number(SAVE_VERSION as u64);
+            print(").");
+        },
+        SaveError::Checksum => print("The save file is corrupted, its checksum doesn't match."),
+        SaveError::Corrupt => print("The save file is corrupted, its contents don't make sense."),
+    }
+}
+
+// Everything about a run that isn't part of the dungeon, the player, the RNG or the log
+#[derive(Clone, Copy)]
+struct RunInfo {
+    seed: u64,
+    turn: u64,
+    difficulty: Difficulty,
+    time_mode: TimeMode,
+}
+
+fn write_item(w: &mut ByteWriter, item: &Item) {
+    w.u8(item.kind as u8);
+    w.u16(item.count);
+    w.u8(item.enchant);
+    w.u8(item.affix as u8);
+    w.u8(item.affix_power);
+}
+
+fn read_item(r: &mut ByteReader) -> Option<Item> {
+    Some(Item {
+        kind: r.pick(&ITEM_KINDS)?,
+        count: r.u16()?,
+        enchant: r.u8()?,
+        affix: r.pick(&AFFIX_VALUES)?,
+        affix_power: r.u8()?,
+    })
+}
+
+fn write_optional_item(w: &mut ByteWriter, item: &Option<Item>) {
+    w.bool(item.is_some());
+    if let Some(item) = item {
+        write_item(w, item);
+    }
+}
+
+fn read_optional_item(r: &mut ByteReader) -> Option<Option<Item>> {
+    if r.bool()? {
+        Some(Some(read_item(r)?))
+    } else {
+        Some(None)
+    }
+}
+
+fn write_effects(w: &mut ByteWriter, effects: &StatusEffects) {
+    for i in 0..NUM_STATUS_KINDS {
+        w.u16(effects.turns[i]);
+        w.u8(effects.potency[i]);
+    }
+}
+
+fn read_effects(r: &mut ByteReader) -> Option<StatusEffects> {
+    let mut effects = StatusEffects::new();
+    for i in 0..NUM_STATUS_KINDS {
+        effects.turns[i] = r.u16()?;
+        effects.potency[i] = r.u8()?;
+    }
+    Some(effects)
+}
+
+// The first byte says which cause it is, the second which monster for monster kills
+fn write_death_cause(w: &mut ByteWriter, cause: Option<DeathCause>) {
+    match cause {
+        None => { w.u8(0); w.u8(0); },
+        Some(DeathCause::Monster(kind)) => { w.u8(1); w.u8(kind.0); },
+        Some(DeathCause::Poison) => { w.u8(2); w.u8(0); },
+        Some(DeathCause::Starvation) => { w.u8(3); w.u8(0); },
+        Some(DeathCause::Spikes) => { w.u8(4); w.u8(0); },
+        Some(DeathCause::Pit) => { w.u8(5); w.u8(0); },
+        Some(DeathCause::Script) => { w.u8(6); w.u8(0); },
+    }
+}
+
+fn read_death_cause(r: &mut ByteReader) -> Option<Option<DeathCause>> {
+    let cause = r.u8()?;
+    let monster = MonsterKind(r.u8()?);
+    match cause {
+        0 => Some(None),
+        1 => Some(Some(DeathCause::Monster(monster))),
+        2 => Some(Some(DeathCause::Poison)),
+        3 => Some(Some(DeathCause::Starvation)),
+        4 => Some(Some(DeathCause::Spikes)),
+        5 => Some(Some(DeathCause::Pit)),
+        6 => Some(Some(DeathCause::Script)),
+        _ => None,
+    }
+}
+
+fn write_player(w: &mut ByteWriter, player: &Player) {
+    w.u64(player.x as u64);
+    w.u64(player.y as u64);
+    w.i32(player.hp);
+    w.i32(player.base_max_hp);
+    w.i32(player.base_attack);
+    w.i32(player.base_defense);
+    for slot in player.inventory.slots.iter() {
+        write_optional_item(w, slot);
+    }
+    for slot in player.equipment.iter() {
+        write_optional_item(w, slot);
+    }
+    w.bytes(&player.identification.appearance);
+    for known in player.identification.known.iter() {
+        w.bool(*known);
+    }
+    write_effects(w, &player.effects);
+    w.u32(player.level);
+    w.u32(player.xp);
+    w.u32(player.explored_tiles);
+    w.bytes(&player.perks);
+    w.u8(player.name_len as u8);
+    w.bytes(&player.name);
+    w.u8(player.class as u8);
+    w.u8(player.race as u8);
+    w.u16(player.ability_cooldown);
+    w.i32(player.nutrition);
+    w.i32(player.energy);
+    write_death_cause(w, player.death_cause);
+}
+
+fn read_player(r: &mut ByteReader, player: &mut Player) -> Option<()> {
+    player.x = r.u64()? as usize;
+    player.y = r.u64()? as usize;
+    player.hp = r.i32()?;
+    player.base_max_hp = r.i32()?;
+    player.base_attack = r.i32()?;
+    player.base_defense = r.i32()?;
+    for i in 0..INVENTORY_CAPACITY {
+        player.inventory.slots[i] = read_optional_item(r)?;
+    }
+    for i in 0..NUM_EQUIP_SLOTS {
+        player.equipment[i] = read_optional_item(r)?;
+    }
+    player.identification.appearance.copy_from_slice(r.bytes(NUM_ITEM_KINDS)?);
+    for i in 0..NUM_ITEM_KINDS {
+        player.identification.known[i] = r.bool()?;
+    }
+    player.effects = read_effects(r)?;
+    player.level = r.u32()?;
+    player.xp = r.u32()?;
+    player.explored_tiles = r.u32()?;
+    player.perks.copy_from_slice(r.bytes(NUM_PERKS)?);
+    player.name_len = r.u8()? as usize;
+    player.name.copy_from_slice(r.bytes(MAX_NAME_LEN)?);
+    player.class = r.pick(&CLASSES)?;
+    player.race = r.pick(&RACES)?;
+    player.ability_cooldown = r.u16()?;
+    player.nutrition = r.i32()?;
+    player.energy = r.i32()?;
+    player.death_cause = read_death_cause(r)?;
+    if player.name_len > MAX_NAME_LEN || player.level == 0 || player.level > MAX_LEVEL {
+        return None;
+    }
+    Some(())
+}
+
+fn write_dungeon<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    w: &mut ByteWriter, dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>
+) 
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    w.u32(MAP_WIDTH as u32);
+    w.u32(MAP_HEIGHT as u32);
+    w.u64(dungeon.rng.state);
+    w.u64(dungeon.loot_rng.state);
+    // Every tile is ASCII so one byte each is enough
+    for tile in dungeon.map.iter() {
+        w.u8(*tile as u8);
+    }
+    for explored in dungeon.explored.iter() {
+        w.bool(*explored);
+    }
+    w.u64(dungeon.spawn_x);
+    w.u64(dungeon.spawn_y);
+    w.u64(dungeon.stairs_x);
+    w.u64(dungeon.stairs_y);
+    w.u64(dungeon.key_x);
+    w.u64(dungeon.key_y);
+    w.u64(dungeon.depth);
+    w.u64(dungeon.food_per_floor);
+    w.u32(dungeon.num_items as u32);
+    for floor_item in dungeon.floor_items().iter() {
+        w.u64(floor_item.x);
+        w.u64(floor_item.y);
+        write_item(w, &floor_item.item);
+    }
+    w.u32(dungeon.num_monsters as u32);
+    for monster in dungeon.monsters[..dungeon.num_monsters].iter() {
+        w.u8(monster.kind.0);
+        w.u64(monster.x);
+        w.u64(monster.y);
+        w.i32(monster.hp);
+        write_effects(w, &monster.effects);
+        w.bool(monster.awake);
+        w.i32(monster.energy);
+    }
+    w.u32(dungeon.num_traps as u32);
+    for trap in dungeon.traps[..dungeon.num_traps].iter() {
+        w.u64(trap.x);
+        w.u64(trap.y);
+        w.u8(trap.kind as u8);
+        w.bool(trap.revealed);
+    }
+}
+
+fn read_dungeon<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    r: &mut ByteReader, dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>
+) -> Option<()>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    if r.u32()? as usize != MAP_WIDTH || r.u32()? as usize != MAP_HEIGHT {
+        return None;
+    }
+    dungeon.rng.state = r.u64()?;
+    dungeon.loot_rng.state = r.u64()?;
+    for i in 0..MAP_WIDTH * MAP_HEIGHT {
+        let tile = r.u8()?;
+        if !tile.is_ascii() {
+            return None;
+        }
+        dungeon.map[i] = tile as char;
+    }
+    for i in 0..MAP_WIDTH * MAP_HEIGHT {
+        dungeon.explored[i] = r.bool()?;
+    }
+    dungeon.spawn_x = r.u64()?;
+    dungeon.spawn_y = r.u64()?;
+    dungeon.stairs_x = r.u64()?;
+    dungeon.stairs_y = r.u64()?;
+    dungeon.key_x = r.u64()?;
+    dungeon.key_y = r.u64()?;
+    dungeon.depth = r.u64()?;
+    dungeon.food_per_floor = r.u64()?;
+    // Anything placed in the margin would let the window run off the map
+    let special_tiles = [
+        (dungeon.spawn_x, dungeon.spawn_y), (dungeon.stairs_x, dungeon.stairs_y), (dungeon.key_x, dungeon.key_y),
+    ];
+    if dungeon.depth == 0 || special_tiles.iter().any(|(x, y)| !dungeon.in_play_area(*x, *y)) {
+        return None;
+    }
+    dungeon.num_rooms = 0;
+    dungeon.num_items = r.u32()? as usize;
+    if dungeon.num_items > MAX_FLOOR_ITEMS {
+        return None;
+    }
+    for i in 0..dungeon.num_items {
+        dungeon.items[i] = FloorItem { x: r.u64()?, y: r.u64()?, item: read_item(r)? };
+        if !dungeon.in_play_area(dungeon.items[i].x, dungeon.items[i].y) {
+            return None;
+        }
+    }
+    dungeon.num_monsters = r.u32()? as usize;
+    if dungeon.num_monsters > MAX_MONSTERS {
+        return None;
+    }
+    for i in 0..dungeon.num_monsters {
+        let kind = r.u8()?;
+        if kind as usize >= monster_defs().len() {
+            return None;
+        }
+        let (x, y) = (r.u64()?, r.u64()?);
+        if !dungeon.in_play_area(x, y) {
+            return None;
+        }
+        let mut monster = Monster::new(MonsterKind(kind), x, y);
+        monster.hp = r.i32()?;
+        monster.effects = read_effects(r)?;
+        monster.awake = r.bool()?;
+        monster.energy = r.i32()?;
+        dungeon.monsters[i] = monster;
+    }
+    dungeon.num_traps = r.u32()? as usize;
+    if dungeon.num_traps > MAX_TRAPS {
+        return None;
+    }
+    for i in 0..dungeon.num_traps {
+        dungeon.traps[i] = Trap { x: r.u64()?, y: r.u64()?, kind: r.pick(&TRAP_KINDS)?, revealed: r.bool()? };
+        if !dungeon.in_play_area(dungeon.traps[i].x, dungeon.traps[i].y) {
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn write_log(w: &mut ByteWriter, log: &MessageLog) {
+    w.u32(log.len as u32);
+    for i in 0..log.len {
+        let entry = log.get(i);
+        w.u8(entry.len as u8);
+        w.bytes(&entry.text[..entry.len]);
+        w.u64(entry.turn);
+        w.u32(entry.count);
+    }
+}
+
+fn read_log(r: &mut ByteReader, log: &mut MessageLog) -> Option<()> {
+    *log = MessageLog::new();
+    let len = r.u32()? as usize;
+    if len > MESSAGE_LOG_CAPACITY {
+        return None;
+    }
+    for _ in 0..len {
+        let text_len = r.u8()? as usize;
+        let text = r.bytes(text_len)?;
+        if core::str::from_utf8(text).is_err() {
+            return None;
+        }
+        let turn = r.u64()?;
+        let count = r.u32()?;
+        log.add(text, turn);
+        let last = log.len - 1;
+        log.entries[(log.start + last) % MESSAGE_LOG_CAPACITY].count = count;
+    }
+    // Old messages were already seen before the game was saved
+    log.unseen = 0;
+    Some(())
+}
+
+fn write_save_payload<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    w: &mut ByteWriter, run: &RunInfo,
+    dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, player: &Player, rng: &XorshiftRng, log: &MessageLog
+)
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    w.u64(run.seed);
+    w.u64(run.turn);
+    w.u8(run.difficulty as u8);
+    w.u8(run.time_mode as u8);
+    w.u64(rng.state);
+    write_player(w, player);
+    write_dungeon(w, dungeon);
+    write_log(w, log);
+}
+
+fn save_game<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    path: &PathBuf, run: &RunInfo, flags: u32,
+    dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, player: &Player, rng: &XorshiftRng, log: &MessageLog
+) -> core::result::Result<(), SaveError>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(SAVE_BUFFER) };
+    let mut w = ByteWriter::new(&mut buf[SAVE_HEADER_SIZE..]);
+    write_save_payload(&mut w, run, dungeon, player, rng, log);
+    if w.overflowed {
+        return Err(SaveError::Corrupt);
+    }
+    let payload_len = w.len;
+    let payload_checksum = checksum(&buf[SAVE_HEADER_SIZE..SAVE_HEADER_SIZE + payload_len]);
+    let mut header = ByteWriter::new(&mut buf[..SAVE_HEADER_SIZE]);
+    header.bytes(SAVE_MAGIC);
+    header.u32(SAVE_VERSION);
+    header.u32(flags);
+    header.u64(payload_len as u64);
+    header.u64(payload_checksum);
+
+    if let Some(dir) = path.as_str().rfind('/') {
+        let mut dir_path = PathBuf::new();
+        dir_path.push(&path.buf[..dir]);
+        if let Result::Err(errno) = create_dir_all(&dir_path) {
+            return Err(SaveError::Io(errno));
+        }
+    }
+    match write_file_atomic(path, &buf[..SAVE_HEADER_SIZE + payload_len]) {
+        Result::Ok(()) => Ok(()),
+        Result::Err(errno) => Err(SaveError::Io(errno)),
+    }
+}
+
+fn delete_save(path: &PathBuf) -> core::result::Result<(), SaveError> {
+    let ret = unsafe { unlink(path.as_ptr()) };
+    if ret < 0 && -ret != ENOENT {
+        return Err(SaveError::Io(-ret));
+    }
+    Ok(())
+}
+
+// Validates the header and checksum before touching any game state. On success returns the 
+// header flags and the run info, the rest of the state is loaded into the arguments.
+fn load_game<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    path: &PathBuf,
+    dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>, player: &mut Player, rng: &mut XorshiftRng, log: &mut MessageLog
+) -> core::result::Result<(u32, RunInfo), SaveError>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(SAVE_BUFFER) };
+    let len = match read_file(path, buf) {
+        Result::Ok(len) => len,
+        Result::Err(ENOENT) => return Err(SaveError::NotFound),
+        Result::Err(EFBIG) => return Err(SaveError::NotASave),
+        Result::Err(errno) => return Err(SaveError::Io(errno)),
+    };
+    let mut header = ByteReader::new(&buf[..len]);
+    let magic = header.bytes(4).ok_or(SaveError::NotASave)?;
+    if !bytes_equal(magic, SAVE_MAGIC) {
+        return Err(SaveError::NotASave);
+    }
+    let version = header.u32().ok_or(SaveError::Corrupt)?;
+    if version != SAVE_VERSION {
+        return Err(SaveError::Version(version));
+    }
+    let flags = header.u32().ok_or(SaveError::Corrupt)?;
+    let payload_len = header.u64().ok_or(SaveError::Corrupt)? as usize;
+    let payload_checksum = header.u64().ok_or(SaveError::Corrupt)?;
+    if SAVE_HEADER_SIZE + payload_len != len {
+        return Err(SaveError::Corrupt);
+    }
+    let payload = &buf[SAVE_HEADER_SIZE..len];
+    if checksum(payload) != payload_checksum {
+        return Err(SaveError::Checksum);
+    }
+
+    let mut r = ByteReader::new(payload);
+    let run = (|| {
+        let run = RunInfo {
+            seed: r.u64()?,
+            turn: r.u64()?,
+            difficulty: r.pick(&DIFFICULTIES)?,
+            time_mode: r.pick(&TIME_MODES)?,
+        };
+        rng.state = r.u64()?;
+        read_player(&mut r, player)?;
+        read_dungeon(&mut r, dungeon)?;
+        read_log(&mut r, log)?;
+        // The player is read before the floor they're on, so their position is checked last
+        if !dungeon.in_play_area(player.x as u64, player.y as u64) {
+            return None;
+        }
+        Some(run)
+    })().ok_or(SaveError::Corrupt)?;
+    Ok((flags, run))
+}
+
+const HIGH_SCORE_FILE_NAME: &str = "scores.bin";
+const HIGH_SCORE_MAGIC: &[u8; 4] = b"RDHS";
+// Bump whenever the record layout changes, older records are then skipped
+const HIGH_SCORE_VERSION: u8 = 1;
+// Magic, version, name, class, race, difficulty, seed, depth, turns, score, cause and checksum
+const HIGH_SCORE_RECORD_SIZE: usize = 4 + 1 + 1 + MAX_NAME_LEN + 3 + 8 * 4 + 2 + 8;
+const HIGH_SCORE_TABLE_LEN: usize = 10;
+const SCORE_PER_DEPTH: u64 = 100;
+const SCORE_PER_XP: u64 = 2;
+const SCORE_PER_GOLD: u64 = 1;
+const LOCK_SH: i32 = 1;
+const LOCK_EX: i32 = 2;
+
+// One finished run, every run that ends in death is appended to the high score file
+#[derive(Clone, Copy)]
+struct HighScore {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    class: Class,
+    race: Race,
+    difficulty: Difficulty,
+    seed: u64,
+    depth: u64,
+    turns: u64,
+    score: u64,
+    cause: Option<DeathCause>,
+}
+
+impl HighScore {
+    fn new(player: &Player, difficulty: Difficulty, seed: u64, depth: u64, turns: u64) -> Self {
+        HighScore {
+            name: player.name,
+            name_len: player.name_len,
+            class: player.class,
+            race: player.race,
+            difficulty,
+            seed,
+            depth,
+            turns,
+            score: score_run(player, depth),
+            cause: player.death_cause,
+        }
+    }
+
+    fn write(&self, buf: &mut [u8; HIGH_SCORE_RECORD_SIZE]) {
+        let mut w = ByteWriter::new(&mut buf[..]);
+        w.bytes(HIGH_SCORE_MAGIC);
+        w.u8(HIGH_SCORE_VERSION);
+        w.u8(self.name_len as u8);
+        w.bytes(&self.name);
+        w.u8(self.class as u8);
+        w.u8(self.race as u8);
+        w.u8(self.difficulty as u8);
+        w.u64(self.seed);
+        w.u64(self.depth);
+        w.u64(self.turns);
+        w.u64(self.score);
+        write_death_cause(&mut w, self.cause);
+        let record_checksum = checksum(&buf[..HIGH_SCORE_RECORD_SIZE - 8]);
+        buf[HIGH_SCORE_RECORD_SIZE - 8..].copy_from_slice(&record_checksum.to_le_bytes());
+    }
+
+    // Returns None for records from another version or ones that were damaged
+    fn read(buf: &[u8; HIGH_SCORE_RECORD_SIZE]) -> Option<Self> {
+        let mut r = ByteReader::new(&buf[..]);
+        if !bytes_equal(r.bytes(4)?, HIGH_SCORE_MAGIC) || r.u8()? != HIGH_SCORE_VERSION {
+            return None;
+        }
+        let mut checksum_reader = ByteReader::new(&buf[HIGH_SCORE_RECORD_SIZE - 8..]);
+        if checksum(&buf[..HIGH_SCORE_RECORD_SIZE - 8]) != checksum_reader.u64()? {
+            return None;
+        }
+        let name_len = core::cmp::min(r.u8()? as usize, MAX_NAME_LEN);
+        let mut name = [0; MAX_NAME_LEN];
+        name.copy_from_slice(r.bytes(MAX_NAME_LEN)?);
+        Some(HighScore {
+            name,
+            name_len,
+            class: r.pick(&CLASSES)?,
+            race: r.pick(&RACES)?,
+            difficulty: r.pick(&DIFFICULTIES)?,
+            seed: r.u64()?,
+            depth: r.u64()?,
+            turns: r.u64()?,
+            score: r.u64()?,
+            cause: read_death_cause(&mut r)?,
+        })
+    }
+}
+
+// Going deeper is worth the most, with experience and gold breaking ties between runs
+fn score_run(player: &Player, depth: u64) -> u64 {
+    let gold = match player.inventory.find(ItemKind::Gold) {
+        Some(slot) => player.inventory.slots[slot].map_or(0, |item| item.count as u64),
+        None => 0,
+    };
+    depth * SCORE_PER_DEPTH + player.xp as u64 * SCORE_PER_XP + gold * SCORE_PER_GOLD
+}
+
+// The best runs in the file, highest score first
+struct HighScoreTable {
+    entries: [Option<HighScore>; HIGH_SCORE_TABLE_LEN],
+    // Every readable run in the file, including ones that didn't make the table
+    total_runs: u64,
+}
+
+impl HighScoreTable {
+    fn new() -> Self {
+        HighScoreTable { entries: [None; HIGH_SCORE_TABLE_LEN], total_runs: 0 }
+    }
+
+    // Returns the entry's place in the table, or None if it didn't make it
+    fn insert(&mut self, entry: HighScore) -> Option<usize> {
+        self.total_runs += 1;
+        // Ties go to the older run
+        let rank = self.entries.iter().position(|e| e.is_none_or(|e| e.score < entry.score))?;
+        for i in (rank + 1..HIGH_SCORE_TABLE_LEN).rev() {
+            self.entries[i] = self.entries[i - 1];
+        }
+        self.entries[rank] = Some(entry);
+        Some(rank)
+    }
+}
+
+enum HighScoreError {
+    Io(i32),
+    // The file opened but flock failed, some network file systems don't support it
+    Lock(i32),
+}
+
+fn print_high_score_error(err: &HighScoreError) {
+    match err {
+        HighScoreError::Io(errno) => {
+            print("The high score file couldn't be accessed (errno ");
+            print_number(*errno as u64);
+            print(").");
+        },
+        HighScoreError::Lock(errno) => {
+            print("The high score file couldn't be locked (errno ");
+            print_number(*errno as u64);
+            print(").");
+        },
+    }
+}
+
+// Reads every record from an open high score file into the table, returns the file's length
+fn read_high_score_records(fd: i32, table: &mut HighScoreTable) -> Result<usize> {
+    let mut record = [0u8; HIGH_SCORE_RECORD_SIZE];
+    let mut total = 0;
+    loop {
+        let mut len = 0;
+        while len < HIGH_SCORE_RECORD_SIZE {
+            let ret = unsafe { read(fd, record.as_mut_ptr().add(len), HIGH_SCORE_RECORD_SIZE - len) };
+            if ret < 0 {
+                return Result::Err(-ret);
+            }
+            if ret == 0 {
+                // A partial record at the end is a write that never finished
+                return Result::Ok(total + len);
+            }
+            len += ret as usize;
+        }
+        total += len;
+        if let Some(entry) = HighScore::read(&record) {
+            table.insert(entry);
+        }
+    }
+}
+
+fn read_high_scores(path: &PathBuf, table: &mut HighScoreTable) -> core::result::Result<(), HighScoreError> {
+    let fd = unsafe { open(path.as_ptr(), O_RDONLY, 0) };
+    if fd == -ENOENT {
+        return Ok(());
+    }
+    if fd < 0 {
+        return Err(HighScoreError::Io(-fd));
+    }
+    // Shared so several players can read at once but never while someone is appending
+    let ret = unsafe { flock(fd, LOCK_SH) };
+    let result = if ret < 0 {
+        Err(HighScoreError::Lock(-ret))
+    } else {
+        match read_high_score_records(fd, table) {
+            Result::Ok(_) => Ok(()),
+            Result::Err(errno) => Err(HighScoreError::Io(errno)),
+        }
+    };
+    // Closing releases the lock
+    unsafe { close(fd) };
+    result
+}
+
+// Appends the run to the file and fills the table with everything in it, the lock is held
+// throughout so concurrent players on the same machine can't interleave their writes. 
+// Returns the run's place in the table.
+fn record_high_score(
+    path: &PathBuf, entry: &HighScore, table: &mut HighScoreTable
+) -> core::result::Result<Option<usize>, HighScoreError> {
+    if let Some(dir) = path.as_str().rfind('/') {
+        let mut dir_path = PathBuf::new();
+        dir_path.push(&path.buf[..dir]);
+        if let Result::Err(errno) = create_dir_all(&dir_path) {
+            return Err(HighScoreError::Io(errno));
+        }
+    }
+    let fd = unsafe { open(path.as_ptr(), O_RDWR | O_CREAT | O_APPEND, 0o644) };
+    if fd < 0 {
+        return Err(HighScoreError::Io(-fd));
+    }
+    let ret = unsafe { flock(fd, LOCK_EX) };
+    if ret < 0 {
+        unsafe { close(fd) };
+        return Err(HighScoreError::Lock(-ret));
+    }
+    let len = match read_high_score_records(fd, table) {
+        Result::Ok(len) => len,
+        Result::Err(errno) => {
+            unsafe { close(fd) };
+            return Err(HighScoreError::Io(errno));
+        },
+    };
+    // A torn record left at the end would shift every record appended after it, so it's cut off
+    // and the new one goes where it started
+    let torn = len % HIGH_SCORE_RECORD_SIZE;
+    if torn != 0 {
+        let ret = unsafe { ftruncate(fd, (len - torn) as u64) };
+        if ret < 0 {
+            unsafe { close(fd) };
+            return Err(HighScoreError::Io(-ret));
+        }
+    }
+    let mut record = [0u8; HIGH_SCORE_RECORD_SIZE];
+    entry.write(&mut record);
+    let written = write_all(fd, &record);
+    unsafe { close(fd) };
+    match written {
+        Result::Ok(()) => Ok(table.insert(*entry)),
+        Result::Err(errno) => Err(HighScoreError::Io(errno)),
+    }
+}
+
+fn show_high_scores(table: &HighScoreTable, highlight: Option<usize>) {
+    clear_screen();
+    print("High scores (");
+    print_number(table.total_runs);
+    print(if table.total_runs == 1 { " run" } else { " runs" });
+    print(" recorded)\n\n");
+    let mut line = MessageLine::new();
+    for (rank, entry) in table.entries.iter().enumerate() {
+        let entry = match entry {
+            Some(entry) => entry,
+            None => break,
+        };
+        line.clear();
+        line.push(if highlight == Some(rank) { "> " } else { "  " });
+        if rank + 1 < 10 {
+            line.push(" ");
+        }
+        line.push_number(rank as u64 + 1);
+        line.push(". ");
+        line.push_number(entry.score);
+        line.push("  ");
+        line.push(core::str::from_utf8(&entry.name[..entry.name_len]).unwrap_or(DEFAULT_NAME));
+        line.push(" the ");
+        line.push(RACE_DEFS[entry.race as usize].name);
+        line.push(" ");
+        line.push(CLASS_DEFS[entry.class as usize].name);
+        line.push(", ");
+        match entry.cause {
+            Some(cause) => format_death_cause(cause, &mut line),
+            None => line.push("died"),
+        }
+        line.push(" on depth ");
+        line.push_number(entry.depth);
+        line.push("\n        ");
+        line.push_number(entry.turns);
+        line.push(" turns on ");
+        line.push(DIFFICULTY_DEFS[entry.difficulty as usize].name);
+        line.push(", seed ");
+        line.push_number(entry.seed);
+        line.push("\n");
+        print(line.as_str());
+    }
+    if table.total_runs == 0 {
+        print("No runs yet.\n");
+    }
+    print("\nPress space to continue");
+    loop_until_continue();
+}
+
+const REPLAY_MAGIC: &[u8; 4] = b"RDRP";
+// Bump whenever a change to the game would make old replays play out differently
+const REPLAY_VERSION: u32 = 1;
+// Magic, version and seed
+const REPLAY_HEADER_SIZE: usize = 4 + 4 + 8;
+// Followed by the turn and the key
+const REPLAY_EVENT_KEY: u8 = 1;
+// Followed by the hash of the final game state
+const REPLAY_EVENT_END: u8 = 2;
+const DEFAULT_SEED: u64 = 1232123123234;
+
+// Every new game gets its own seed so the floors and the potion and scroll appearances change 
+// from run to run. The map tools and the bot start from DEFAULT_SEED instead.
+fn new_run_seed() -> u64 {
+    let (sec, ns) = get_time();
+    // Xorshift gets stuck on a zero seed
+    core::cmp::max((sec as u64).wrapping_mul(1_000_000_000).wrapping_add(ns as u64), 1)
+}
+
+// Where keys come from and go to. It's global because every prompt reads keys and threading 
+// it through all of them would touch most of the game for something only replays care about.
+struct InputState {
+    // Keys come from this replay file instead of the terminal while it's open
+    replay_fd: i32,
+    // Every key read from the terminal is appended to this file while it's open
+    record_fd: i32,
+    // Stored with each recorded key so a replay notices as soon as it goes out of step
+    turn: u64,
+    // Set while the bot plays, prompts get the keys it queued and space once they run out
+    bot: bool,
+    bot_keys: [u8; MAX_BOT_KEYS],
+    num_bot_keys: usize,
+}
+
+static mut INPUT: InputState = InputState { 
+    replay_fd: -1, record_fd: -1, turn: 0, 
+    bot: false, bot_keys: [0; MAX_BOT_KEYS], num_bot_keys: 0 
+};
+// Replays run without a terminal, so nothing is drawn
+static mut OUTPUT_MUTED: bool = false;
+
+fn set_input_turn(turn: u64) {
+    unsafe { (*core::ptr::addr_of_mut!(INPUT)).turn = turn };
+}
+
+fn is_replaying() -> bool {
+    unsafe { (*core::ptr::addr_of!(INPUT)).replay_fd >= 0 }
+}
+
+// Keys the game makes up itself, like the real time idle wait, are recorded through here too
+fn record_key(key: u8) {
+    let input = unsafe { &mut *core::ptr::addr_of_mut!(INPUT) };
+    if input.record_fd < 0 {
+        return;
+    }
+    let mut buf = [0u8; 10];
+    let mut w = ByteWriter::new(&mut buf);
+    w.u8(REPLAY_EVENT_KEY);
+    w.u64(input.turn);
+    w.u8(key);
+    if let Result::Err(_) = write_all(input.record_fd, &buf) {
+        // Better to lose the recording than to interrupt the game over it
+        unsafe { close(input.record_fd) };
+        input.record_fd = -1;
+    }
+}
+
+// Reads exactly buf.len() bytes, returns false at the end of the file
+fn read_exact(fd: i32, buf: &mut [u8]) -> Result<bool> {
+    let mut len = 0;
+    while len < buf.len() {
+        let ret = unsafe { read(fd, buf.as_mut_ptr().add(len), buf.len() - len) };
+        if ret < 0 {
+            return Result::Err(-ret);
+        }
+        if ret == 0 {
+            return Result::Ok(false);
+        }
+        len += ret as usize;
+    }
+    Result::Ok(true)
+}
+
+// Any problem with a replay ends the run, there's no terminal to recover on
+fn replay_failed(message: &str, turn: u64) -> ! {
+    unsafe { OUTPUT_MUTED = false };
+    print("Replay failed on turn ");
+    print_number(turn);
+    print(": ");
+    print(message);
+    print("\n");
+    exit(1);
+}
+
+fn next_replay_key(input: &mut InputState) -> u8 {
+    let mut event = [0u8; 10];
+    match read_exact(input.replay_fd, &mut event) {
+        Result::Ok(true) => (),
+        Result::Ok(false) => replay_failed("the replay ran out of keys before the game ended", input.turn),
+        Result::Err(_) => replay_failed("the replay file couldn't be read", input.turn),
+    }
+    let mut r = ByteReader::new(&event);
+    if r.u8() != Some(REPLAY_EVENT_KEY) {
+        replay_failed("the game is still going but the recording ended here", input.turn);
+    }
+    if r.u64() != Some(input.turn) {
+        replay_failed("the game went out of step with the recording", input.turn);
+    }
+    r.u8().unwrap_or(0)
+}
+
+// Called once the game is over. A recording gets the final state hash, a replay checks against 
+// it and exits with the result.
+fn finish_input(state_hash: u64) {
+    let input = unsafe { &mut *core::ptr::addr_of_mut!(INPUT) };
+    if input.record_fd >= 0 {
+        let mut buf = [0u8; 9];
+        let mut w = ByteWriter::new(&mut buf);
+        w.u8(REPLAY_EVENT_END);
+        w.u64(state_hash);
+        let _ = write_all(input.record_fd, &buf);
+        unsafe { close(input.record_fd) };
+        input.record_fd = -1;
+    }
+    if input.replay_fd >= 0 {
+        let mut end = [0u8; 9];
+        let recorded_hash = match read_exact(input.replay_fd, &mut end) {
+            Result::Ok(true) if end[0] == REPLAY_EVENT_END => ByteReader::new(&end[1..]).u64(),
+            _ => None,
+        };
+        match recorded_hash {
+            None => replay_failed("the game ended before the recording did", input.turn),
+            Some(hash) if hash != state_hash => replay_failed("the final game state doesn't match the recording", input.turn),
+            Some(_) => {
+                unsafe { OUTPUT_MUTED = false };
+                print("Replay verified after ");
+                print_number(input.turn);
+                print(" turns, final state hash ");
+                print_number(state_hash);
+                print("\n");
+                exit(0);
+            },
+        }
+    }
+}
+
+fn path_from_arg(arg: &[u8]) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    if !path.push(arg) {
+        return None;
+    }
+    Some(path)
+}
+
+// Starts a recording, returns the errno if the file couldn't be written
+fn start_recording(path: &PathBuf, seed: u64) -> Result<()> {
+    let fd = unsafe { open(path.as_ptr(), O_WRONLY | O_CREAT | O_TRUNC, 0o644) };
+    if fd < 0 {
+        return Result::Err(-fd);
+    }
+    let mut header = [0u8; REPLAY_HEADER_SIZE];
+    let mut w = ByteWriter::new(&mut header);
+    w.bytes(REPLAY_MAGIC);
+    w.u32(REPLAY_VERSION);
+    w.u64(seed);
+    if let Result::Err(errno) = write_all(fd, &header) {
+        unsafe { close(fd) };
+        return Result::Err(errno);
+    }
+    unsafe { (*core::ptr::addr_of_mut!(INPUT)).record_fd = fd };
+    Result::Ok(())
+}
+
+// Opens a replay and returns its seed, keys are read from it from then on
+fn start_replay(path: &PathBuf) -> core::result::Result<u64, &'static str> {
+    let fd = unsafe { open(path.as_ptr(), O_RDONLY, 0) };
+    if fd < 0 {
+        return Err("the replay file couldn't be opened");
+    }
+    let mut header = [0u8; REPLAY_HEADER_SIZE];
+    let read_header = read_exact(fd, &mut header);
+    let mut r = ByteReader::new(&header);
+    let result = match read_header {
+        Result::Ok(true) if r.bytes(4) == Some(&REPLAY_MAGIC[..]) => match (r.u32(), r.u64()) {
+            (Some(REPLAY_VERSION), Some(seed)) => Ok(seed),
+            _ => Err("the replay was recorded by a different version of the game"),
+        },
+        _ => Err("the file isn't a Rusty Dungeon replay"),
+    };
+    match result {
+        Ok(_) => unsafe { (*core::ptr::addr_of_mut!(INPUT)).replay_fd = fd },
+        Err(_) => unsafe { close(fd); },
+    }
+    result
+}
+
+// A hash of everything a save would hold, two runs that end with the same hash played out the same
+fn state_hash<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    run: &RunInfo, dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, player: &Player, rng: &XorshiftRng, log: &MessageLog
+) -> u64
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(SAVE_BUFFER) };
+    let mut w = ByteWriter::new(buf);
+    write_save_payload(&mut w, run, dungeon, player, rng, log);
+    let len = w.len;
+    checksum(&buf[..len])
+}
+
+const MAP_FILE_CAPACITY: usize = 64 * 1024;
+// Custom maps replace the first floors in order, the generator takes over after them
+const MAX_CUSTOM_MAPS: usize = 8;
+
+// Text of the map file being loaded or edited, too big for the stack. A playtest from the editor
+// never loads map files, so the two never use it at once.
+static mut MAP_TEXT: [u8; MAP_FILE_CAPACITY] = [0; MAP_FILE_CAPACITY];
+
+enum MapError {
+    Io(i32),
+    TooBig { max_width: usize, max_height: usize },
+    Empty,
+    UnknownGlyph { line: usize, column: usize, glyph: char },
+    Duplicate { line: usize, column: usize, glyph: char, first_line: usize },
+    TooMany { line: usize, column: usize, glyph: char },
+    Missing(char),
+    // The stairs or the key can't be reached from the spawn
+    Unreachable,
+}
+
+fn print_map_error(err: &MapError) {
+    let mut line = MessageLine::new();
+    format_map_error(err, &mut line);
+    print(line.as_str());
+}
+
+fn format_map_error(err: &MapError, line: &mut MessageLine) {
+    match err {
+        MapError::Io(errno) => {
+            line.push("couldn't be read (errno ");
+            line.push_number(*errno as u64);
+            line.push(")");
+        },
+        MapError::TooBig { max_width, max_height } => {
+            line.push("the drawing is bigger than ");
+            line.push_number(*max_width as u64);
+            line.push("x");
+            line.push_number(*max_height as u64);
+        },
+        MapError::Empty => line.push("there's nothing but walls"),
+        MapError::UnknownGlyph { line: number, column, glyph } => {
+            line.push_number(*number as u64);
+            line.push(":");
+            line.push_number(*column as u64);
+            line.push(": '");
+            line.push_char(*glyph);
+            line.push("' isn't a map glyph");
+        },
+        MapError::Duplicate { line: number, column, glyph, first_line } => {
+            line.push_number(*number as u64);
+            line.push(":");
+            line.push_number(*column as u64);
+            line.push(": there can only be one '");
+            line.push_char(*glyph);
+            line.push("', the first is on line ");
+            line.push_number(*first_line as u64);
+        },
+        MapError::TooMany { line: number, column, glyph } => {
+            line.push_number(*number as u64);
+            line.push(":");
+            line.push_number(*column as u64);
+            line.push(": too many '");
+            line.push_char(*glyph);
+            line.push("' on one floor");
+        },
+        MapError::Missing(glyph) => {
+            line.push("the map needs a '");
+            line.push_char(*glyph);
+            line.push("'");
+        },
+        MapError::Unreachable => line.push("the key and the stairs must both be reachable from the spawn"),
+    }
+}
+
+fn load_map_file<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    path: &PathBuf, dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>
+) -> core::result::Result<(), MapError>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(MAP_TEXT) };
+    match read_file(path, buf) {
+        Result::Ok(len) => dungeon.load_map(&buf[..len]),
+        Result::Err(errno) => Err(MapError::Io(errno)),
+    }
+}
+
+// Built in monster and item definitions, mods are read on top of them
+const DEFS_TEXT: &str = include_str!("defs.txt");
+const DEFS_FILE_NAME: &str = "defs.txt";
+const MODS_DIR_NAME: &[u8] = b"/mods";
+const MOD_FILE_EXTENSION: &[u8] = b".txt";
+const MAX_MOD_FILES: usize = 16;
+const MODS_TEXT_CAPACITY: usize = 64 * 1024;
+// Keeps stats small enough that the combat maths can't overflow
+const MAX_DEF_VALUE: u64 = 9999;
+
+// Indexed by StatusKind
+const STATUS_NAMES: [&str; NUM_STATUS_KINDS] = [
+    "poison", "regeneration", "haste", "slow", "confusion", "blindness", "strength", "toughness"
+];
+
+const ITEM_FLAG_NAMES: [(u8, &str); 4] = [
+    (ITEM_FLAG_STACKABLE, "stackable"), (ITEM_FLAG_QUEST, "quest"), (ITEM_FLAG_POTION, "potion"), (ITEM_FLAG_SCROLL, "scroll"),
+];
+
+const MONSTER_FLAG_NAMES: [(u8, &str); 2] = [(MONSTER_FLAG_AWAKE, "awake"), (MONSTER_FLAG_STATIONARY, "stationary")];
+
+// Every mod file is kept for the whole run, the definitions borrow their ids and names from it
+static mut MODS_TEXT: [u8; MODS_TEXT_CAPACITY] = [0u8; MODS_TEXT_CAPACITY];
+
+enum DefsError {
+    Io(i32),
+    // The mod files don't fit in MODS_TEXT
+    TooBig,
+    TooManyFiles,
+    NotUtf8,
+    // Only the built in file can be missing an item
+    MissingItem(&'static str),
+    BadSection { line: usize },
+    UnknownItem { line: usize, id: &'static str },
+    TooManyMonsters { line: usize },
+    OutsideSection { line: usize },
+    NoValue { line: usize },
+    UnknownKey { line: usize, key: &'static str },
+    BadValue { line: usize, key: &'static str, value: &'static str },
+    // A finished section doesn't make sense, line is where the section starts
+    Invalid { line: usize, id: &'static str, reason: &'static str },
+    TooManyScripts { line: usize },
+    TooManyOps { line: usize },
+    UnknownScript { line: usize, name: &'static str },
+    Script { line: usize, token: &'static str, reason: &'static str },
+}
+
+fn format_defs_error(err: &DefsError, line: &mut MessageLine) {
+    match err {
+        DefsError::Io(errno) => {
+            line.push("couldn't be read (errno ");
+            line.push_number(*errno as u64);
+            line.push(")");
+        },
+        DefsError::TooBig => {
+            line.push("the mods add up to more than ");
+            line.push_number((MODS_TEXT_CAPACITY / 1024) as u64);
+            line.push(" KiB");
+        },
+        DefsError::TooManyFiles => {
+            line.push("there can only be ");
+            line.push_number(MAX_MOD_FILES as u64);
+            line.push(" mod files");
+        },
+        DefsError::NotUtf8 => line.push("isn't valid UTF-8"),
+        DefsError::MissingItem(id) => {
+            line.push("item '");
+            line.push(id);
+            line.push("' isn't defined");
+        },
+        DefsError::BadSection { line: number } => {
+            line.push_number(*number as u64);
+            line.push(": sections look like [monster ID], [item ID] or [script NAME]");
+        },
+        DefsError::UnknownItem { line: number, id } => {
+            line.push_number(*number as u64);
+            line.push(": there's no item '");
+            line.push(id);
+            line.push("', items can be changed but not added");
+        },
+        DefsError::TooManyMonsters { line: number } => {
+            line.push_number(*number as u64);
+            line.push(": there can only be ");
+            line.push_number(MAX_MONSTER_KINDS as u64);
+            line.push(" monsters");
+        },
+        DefsError::OutsideSection { line: number } => {
+            line.push_number(*number as u64);
+            line.push(": keys have to come after a [monster ID] or [item ID] section");
+        },
+        DefsError::NoValue { line: number } => {
+            line.push_number(*number as u64);
+            line.push(": lines look like key = value");
+        },
+        DefsError::UnknownKey { line: number, key } => {
+            line.push_number(*number as u64);
+            line.push(": unknown key '");
+            line.push(key);
+            line.push("'");
+        },
+        DefsError::BadValue { line: number, key, value } => {
+            line.push_number(*number as u64);
+            line.push(": '");
+            line.push(value);
+            line.push("' isn't a valid ");
+            line.push(key);
+        },
+        DefsError::Invalid { line: number, id, reason } => {
+            line.push_number(*number as u64);
+            line.push(": ");
+            line.push(id);
+            line.push(" ");
+            line.push(reason);
+        },
+        DefsError::TooManyScripts { line: number } => {
+            line.push_number(*number as u64);
+            line.push(": there can only be ");
+            line.push_number(MAX_SCRIPTS as u64);
+            line.push(" scripts");
+        },
+        DefsError::TooManyOps { line: number } => {
+            line.push_number(*number as u64);
+            line.push(": scripts can only add up to ");
+            line.push_number(MAX_SCRIPT_OPS as u64);
+            line.push(" words");
+        },
+        DefsError::UnknownScript { line: number, name } => {
+            line.push_number(*number as u64);
+            line.push(": there's no script '");
+            line.push(name);
+            line.push("', scripts have to be defined before they're used");
+        },
+        DefsError::Script { line: number, token, reason } => {
+            line.push_number(*number as u64);
+            line.push(": '");
+            line.push(token);
+            line.push("' ");
+            line.push(reason);
+        },
+    }
+}
+
+// Which definition the keys being read belong to
+#[derive(Clone, Copy)]
+enum DefSection {
+    Monster(usize),
+    Item(usize),
+    Script(usize),
+}
+
+// Why a key couldn't be set, parse_defs adds where it happened
+enum FieldError {
+    UnknownKey,
+    BadValue,
+    UnknownScript,
+}
+
+// Parses one definition file on top of what's already defined. Text has to live for the whole
+// run since the definitions keep slices of it.
+fn parse_defs(defs: &mut Defs, text: &'static str) -> core::result::Result<(), DefsError> {
+    // The section being read and the line it started on
+    let mut section: Option<(DefSection, usize)> = None;
+    let mut compiler = ScriptCompiler::new();
+    for (i, raw_line) in text.split('\n').enumerate() {
+        let line_number = i + 1;
+        let line = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            if let Some((current, start)) = section {
+                finish_section(defs, current, start, &compiler)?;
+            }
+            section = Some((parse_section_header(defs, line, line_number)?, line_number));
+            compiler = ScriptCompiler::new();
+            continue;
+        }
+        let current = match section {
+            Some((current, _)) => current,
+            None => return Err(DefsError::OutsideSection { line: line_number }),
+        };
+        if let DefSection::Script(_) = current {
+            compile_script_line(defs, &mut compiler, line, line_number)?;
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(DefsError::NoValue { line: line_number }),
+        };
+        let result = match current {
+            DefSection::Monster(index) => set_monster_key(&mut defs.monsters[index], key, value),
+            DefSection::Item(index) => set_item_key(&mut defs.items[index], &defs.scripts[..defs.num_scripts], key, value),
+            DefSection::Script(_) => Ok(()),
+        };
+        match result {
+            Ok(()) => (),
+            Err(FieldError::UnknownKey) => return Err(DefsError::UnknownKey { line: line_number, key }),
+            Err(FieldError::BadValue) => return Err(DefsError::BadValue { line: line_number, key, value }),
+            Err(FieldError::UnknownScript) => return Err(DefsError::UnknownScript { line: line_number, name: value }),
+        }
+    }
+    if let Some((current, start)) = section {
+        finish_section(defs, current, start, &compiler)?;
+    }
+    Ok(())
+}
+
+fn finish_section(defs: &mut Defs, section: DefSection, line: usize, compiler: &ScriptCompiler) -> core::result::Result<(), DefsError> {
+    match section {
+        DefSection::Script(index) => finish_script(defs, index, compiler, line),
+        _ => check_def(defs, section, line),
+    }
+}
+
+// [monster ID] picks the monster with that id or adds a new one, [item ID] picks an item and 
+// [script NAME] starts a script, replacing any with the same name
+fn parse_section_header(defs: &mut Defs, line: &'static str, line_number: usize) -> core::result::Result<DefSection, DefsError> {
+    let bad_section = DefsError::BadSection { line: line_number };
+    let inner = match line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        Some(inner) => inner.trim(),
+        None => return Err(bad_section),
+    };
+    let (kind, id) = match inner.split_once(' ') {
+        Some((kind, id)) => (kind, id.trim()),
+        None => return Err(bad_section),
+    };
+    if id.is_empty() || !id.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+        return Err(bad_section);
+    }
+    match kind {
+        "monster" => {
+            if let Some(index) = defs.monsters[..defs.num_monsters].iter().position(|def| def.id == id) {
+                return Ok(DefSection::Monster(index));
+            }
+            if defs.num_monsters == MAX_MONSTER_KINDS {
+                return Err(DefsError::TooManyMonsters { line: line_number });
+            }
+            let index = defs.num_monsters;
+            defs.monsters[index] = NEW_MONSTER_DEF;
+            defs.monsters[index].id = id;
+            defs.monsters[index].name = id;
+            defs.num_monsters += 1;
+            Ok(DefSection::Monster(index))
+        },
+        "item" => match ITEM_IDS.iter().position(|item_id| *item_id == id) {
+            Some(index) => Ok(DefSection::Item(index)),
+            None => Err(DefsError::UnknownItem { line: line_number, id }),
+        },
+        "script" => {
+            let index = match defs.scripts[..defs.num_scripts].iter().position(|script| script.name == id) {
+                Some(index) => {
+                    remove_script_ops(defs, index);
+                    index
+                },
+                None if defs.num_scripts == MAX_SCRIPTS => return Err(DefsError::TooManyScripts { line: line_number }),
+                None => {
+                    defs.num_scripts += 1;
+                    defs.num_scripts - 1
+                },
+            };
+            defs.scripts[index] = ScriptDef { name: id, start: defs.num_ops, len: 0 };
+            Ok(DefSection::Script(index))
+        },
+        _ => Err(bad_section),
+    }
+}
+
+fn parse_def_number(value: &str) -> core::result::Result<u64, FieldError> {
+    match parse_number(value.as_bytes()) {
+        Some(n) if n <= MAX_DEF_VALUE => Ok(n),
+        _ => Err(FieldError::BadValue),
+    }
+}
+
+fn parse_def_name(value: &'static str) -> core::result::Result<&'static str, FieldError> {
+    if value.is_empty() {
+        Err(FieldError::BadValue)
+    } else {
+        Ok(value)
+    }
+}
+
+// Exactly one visible character
+fn parse_def_glyph(value: &str) -> core::result::Result<char, FieldError> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(glyph), None) if !glyph.is_whitespace() && !glyph.is_control() => Ok(glyph),
+        _ => Err(FieldError::BadValue),
+    }
+}
+
+fn parse_def_colour(value: &str) -> core::result::Result<Colour, FieldError> {
+    match COLOUR_NAMES.iter().position(|name| *name == value) {
+        Some(index) => Ok(COLOURS[index]),
+        None => Err(FieldError::BadValue),
+    }
+}
+
+fn parse_def_status(value: &str) -> core::result::Result<StatusKind, FieldError> {
+    match STATUS_NAMES.iter().position(|name| *name == value) {
+        Some(index) => Ok(STATUS_KINDS[index]),
+        None => Err(FieldError::BadValue),
+    }
+}
+
+// Any number of the named flags separated by spaces, or none
+fn parse_def_flags(value: &str, names: &[(u8, &str)]) -> core::result::Result<u8, FieldError> {
+    let mut flags = 0;
+    for word in value.split_whitespace() {
+        match names.iter().find(|(_, name)| *name == word) {
+            Some((flag, _)) => flags |= flag,
+            None if word == "none" => (),
+            None => return Err(FieldError::BadValue),
+        }
+    }
+    Ok(flags)
+}
+
+fn set_monster_key(def: &mut MonsterDef, key: &str, value: &'static str) -> core::result::Result<(), FieldError> {
+    match key {
+        "name" => def.name = parse_def_name(value)?,
+        "glyph" => def.glyph = parse_def_glyph(value)?,
+        "colour" => def.colour = parse_def_colour(value)?,
+        "hp" => def.max_hp = parse_def_number(value)? as i32,
+        "attack" => def.attack = parse_def_number(value)? as i32,
+        "defense" => def.defense = parse_def_number(value)? as i32,
+        "xp" => def.xp = parse_def_number(value)? as u32,
+        "speed" => def.speed = parse_def_number(value)? as i32,
+        "inflicts" if value == "none" => def.inflicts = None,
+        "inflicts" => match value.split_once(' ') {
+            Some((status, turns)) => {
+                def.inflicts = Some((parse_def_status(status)?, parse_def_number(turns.trim())? as u16));
+            },
+            None => return Err(FieldError::BadValue),
+        },
+        "innate" if value == "none" => def.innate = None,
+        "innate" => def.innate = Some(parse_def_status(value)?),
+        "flags" => def.flags = parse_def_flags(value, &MONSTER_FLAG_NAMES)?,
+        "min_depth" => def.min_depth = parse_def_number(value)?,
+        "max_depth" => def.max_depth = parse_def_number(value)?,
+        "weight" => def.weight = parse_def_number(value)? as u32,
+        _ => return Err(FieldError::UnknownKey),
+    }
+    Ok(())
+}
+
+fn set_item_key(def: &mut ItemDef, scripts: &[ScriptDef], key: &str, value: &'static str) -> core::result::Result<(), FieldError> {
+    let script = || match scripts.iter().position(|script| script.name == value) {
+        Some(index) => Ok(Some(index)),
+        None if value == "none" => Ok(None),
+        None => Err(FieldError::UnknownScript),
+    };
+    match key {
+        "name" => def.name = parse_def_name(value)?,
+        "glyph" => def.glyph = parse_def_glyph(value)?,
+        "colour" => def.colour = parse_def_colour(value)?,
+        "attack" => def.attack = parse_def_number(value)? as i32,
+        "defense" => def.defense = parse_def_number(value)? as i32,
+        "slot" if value == "none" => def.slot = None,
+        "slot" => match EQUIP_SLOT_NAMES.iter().position(|name| name.eq_ignore_ascii_case(value)) {
+            Some(index) => def.slot = Some(EQUIP_SLOTS[index]),
+            None => return Err(FieldError::BadValue),
+        },
+        "flags" => def.flags = parse_def_flags(value, &ITEM_FLAG_NAMES)?,
+        "min_depth" => def.min_depth = parse_def_number(value)?,
+        "max_depth" => def.max_depth = parse_def_number(value)?,
+        "weight" => def.weight = parse_def_number(value)? as u32,
+        "on_use" => def.on_use = script()?,
+        "on_pickup" => def.on_pickup = script()?,
+        _ => return Err(FieldError::UnknownKey),
+    }
+    Ok(())
+}
+
+// Glyphs that map files and the map drawing already use for tiles
+fn is_tile_glyph(glyph: char) -> bool {
+    match glyph {
+        WALL_CHAR | FLOOR_CHAR | DOOR_CLOSED_CHAR | DOOR_OPEN_CHAR | TRAP_CHAR 
+            | PLAYER_CHAR | KEY_CHAR | STAIRS_CHAR | UNEXPLORED_CHAR => true,
+        _ => lock_colour(glyph).is_some(),
+    }
+}
+
+// Checks a section once all its keys are read. Map files find monsters and items by glyph, so a 
+// monster's glyph can't be shared with anything else.
+fn check_def(defs: &Defs, section: DefSection, line: usize) -> core::result::Result<(), DefsError> {
+    let (id, glyph, min_depth, max_depth) = match section {
+        DefSection::Monster(index) => {
+            let def = &defs.monsters[index];
+            (def.id, def.glyph, def.min_depth, def.max_depth)
+        },
+        DefSection::Item(index) => {
+            let def = &defs.items[index];
+            (ITEM_IDS[index], def.glyph, def.min_depth, def.max_depth)
+        },
+        DefSection::Script(_) => return Ok(()),
+    };
+    let invalid = |reason| Err(DefsError::Invalid { line, id, reason });
+    let monsters = &defs.monsters[..defs.num_monsters];
+    let monster_glyph_users = monsters.iter().filter(|def| def.glyph == glyph).count();
+    if glyph == UNEXPLORED_CHAR {
+        return invalid("needs a glyph");
+    }
+    if min_depth == 0 {
+        return invalid("needs a min_depth of at least 1");
+    }
+    if max_depth != 0 && max_depth < min_depth {
+        return invalid("has a max_depth shallower than its min_depth");
+    }
+    match section {
+        DefSection::Monster(index) => {
+            let def = &defs.monsters[index];
+            if is_tile_glyph(glyph) {
+                return invalid("can't use the glyph of a map tile");
+            }
+            if monster_glyph_users > 1 || defs.items.iter().any(|item| item.glyph == glyph) {
+                return invalid("has a glyph something else already uses");
+            }
+            if def.max_hp == 0 {
+                return invalid("needs at least 1 hp");
+            }
+            if def.speed == 0 {
+                return invalid("needs a speed of at least 1");
+            }
+        },
+        DefSection::Item(index) => {
+            let def = &defs.items[index];
+            if def.flags & ITEM_FLAG_QUEST == 0 && is_tile_glyph(glyph) {
+                return invalid("can't use the glyph of a map tile");
+            }
+            if monster_glyph_users > 0 {
+                return invalid("has a glyph a monster already uses");
+            }
+            if def.slot.is_some() && def.flags & ITEM_FLAG_STACKABLE != 0 {
+                return invalid("can't be both equipment and stackable");
+            }
+            let potions = defs.items.iter().filter(|item| item.flags & ITEM_FLAG_POTION != 0).count();
+            let scrolls = defs.items.iter().filter(|item| item.flags & ITEM_FLAG_SCROLL != 0).count();
+            if potions > POTION_COLOURS.len() {
+                return invalid("makes more potions than there are potion colours");
+            }
+            if scrolls > SCROLL_LABELS.len() {
+                return invalid("makes more scrolls than there are scroll labels");
+            }
+        },
+        DefSection::Script(_) => (),
+    }
+    Ok(())
+}
+
+// Full paths of the .txt files in the mods directory, sorted so they're always read in the same order
+fn list_mod_files(dir: &PathBuf, paths: &mut [PathBuf; MAX_MOD_FILES]) -> core::result::Result<usize, DefsError> {
+    let fd = unsafe { open(dir.as_ptr(), O_RDONLY | O_DIRECTORY, 0) };
+    if fd < 0 {
+        return Err(DefsError::Io(-fd));
+    }
+    let mut buf = [0u8; 4096];
+    let mut num_paths = 0;
+    loop {
+        let ret = unsafe { getdents64(fd, buf.as_mut_ptr(), buf.len()) };
+        if ret <= 0 {
+            unsafe { close(fd) };
+            if ret < 0 {
+                return Err(DefsError::Io(-ret));
+            }
+            break;
+        }
+        // Each record is inode, offset, record length, type and then the null terminated name
+        let mut offset = 0;
+        while offset < ret as usize {
+            let record_len = u16::from_le_bytes([buf[offset + 16], buf[offset + 17]]) as usize;
+            let name_bytes = &buf[offset + 19..offset + record_len];
+            let name = &name_bytes[..name_bytes.iter().position(|c| *c == 0).unwrap_or(name_bytes.len())];
+            offset += record_len;
+            if !name.ends_with(MOD_FILE_EXTENSION) || name.starts_with(b".") {
+                continue;
+            }
+            if num_paths == MAX_MOD_FILES {
+                unsafe { close(fd) };
+                return Err(DefsError::TooManyFiles);
+            }
+            let mut path = *dir;
+            if !path.push(b"/") || !path.push(name) {
+                unsafe { close(fd) };
+                return Err(DefsError::Io(ENAMETOOLONG));
+            }
+            // Insertion sort, there are only a few and a swap would need memmove
+            let mut index = num_paths;
+            while index > 0 && paths[index - 1].buf[..paths[index - 1].len] > path.buf[..path.len] {
+                paths[index] = paths[index - 1];
+                index -= 1;
+            }
+            paths[index] = path;
+            num_paths += 1;
+        }
+    }
+    Ok(num_paths)
+}
+
+fn exit_with_defs_error(file: &str, err: &DefsError) -> ! {
+    let mut line = MessageLine::new();
+    line.push(file);
+    line.push(": ");
+    format_defs_error(err, &mut line);
+    print(line.as_str());
+    print("\n");
+    exit(1);
+}
+
+// Just defs.txt, which is all the tests and the fuzz target use
+fn load_builtin_defs() {
+    let defs = unsafe { &mut *core::ptr::addr_of_mut!(DEFS) };
+    if let Err(err) = parse_defs(defs, DEFS_TEXT) {
+        exit_with_defs_error(DEFS_FILE_NAME, &err);
+    }
+    for (i, def) in defs.items.iter().enumerate() {
+        if def.name.is_empty() {
+            exit_with_defs_error(DEFS_FILE_NAME, &DefsError::MissingItem(ITEM_IDS[i]));
+        }
+    }
+}
+
+// Reads defs.txt and then every .txt file in $XDG_DATA_HOME/rusty-dungeon/mods. A broken file
+// stops the game with the file and line, rather than quietly playing without the mod.
+fn load_defs(env: &Env) {
+    load_builtin_defs();
+    let defs = unsafe { &mut *core::ptr::addr_of_mut!(DEFS) };
+    let mut dir = match data_dir(env) {
+        Some(dir) => dir,
+        None => return,
+    };
+    if !dir.push(MODS_DIR_NAME) {
+        return;
+    }
+    let mut paths = [PathBuf::new(); MAX_MOD_FILES];
+    let num_paths = match list_mod_files(&dir, &mut paths) {
+        Ok(num_paths) => num_paths,
+        // No mods installed
+        Err(DefsError::Io(ENOENT)) => return,
+        Err(err) => exit_with_defs_error(dir.as_str(), &err),
+    };
+    let mut free: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(MODS_TEXT) };
+    for path in paths[..num_paths].iter() {
+        let len = match read_file(path, free) {
+            Result::Ok(len) => len,
+            Result::Err(EFBIG) => exit_with_defs_error(path.as_str(), &DefsError::TooBig),
+            Result::Err(errno) => exit_with_defs_error(path.as_str(), &DefsError::Io(errno)),
+        };
+        let (text, rest) = core::mem::take(&mut free).split_at_mut(len);
+        free = rest;
+        let result = match core::str::from_utf8(text) {
+            Ok(text) => parse_defs(defs, text),
+            Err(_) => Err(DefsError::NotUtf8),
+        };
+        if let Err(err) = result {
+            exit_with_defs_error(path.as_str(), &err);
+        }
+    }
+}
+
+// Mod scripts are compiled into one shared pool of instructions when the definitions load
+const MAX_SCRIPTS: usize = 32;
+const MAX_SCRIPT_OPS: usize = 4096;
+// How deeply if and begin can be nested inside each other
+const MAX_SCRIPT_NESTING: usize = 16;
+const MAX_SCRIPT_STACK: usize = 32;
+// Instructions one run of a script may take before it's stopped, so a loop can't hang the game
+const MAX_SCRIPT_STEPS: u32 = 10000;
+// Scripts with these names run every time the player moves onto another tile and every time a
+// floor is entered
+const ON_STEP_SCRIPT: &str = "on_step";
+const ON_ENTER_LEVEL_SCRIPT: &str = "on_enter_level";
+
+// Built in words, stack effects are in the README
+#[derive(Copy, Clone, PartialEq)]
+enum Word {
+    Dup,
+    Drop,
+    Swap,
+    Over,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    Less,
+    Greater,
+    Not,
+    And,
+    Or,
+    Depth,
+    Hp,
+    MaxHp,
+    X,
+    Y,
+    Random,
+    RandomFloor,
+    Tile,
+    Message,
+    Damage,
+    Heal,
+    Teleport,
+    Spawn,
+    SetTile,
+}
+
+const NUM_WORDS: usize = 29;
+const WORDS: [Word; NUM_WORDS] = [
+    Word::Dup, Word::Drop, Word::Swap, Word::Over,
+    Word::Add, Word::Subtract, Word::Multiply, Word::Divide, Word::Modulo,
+    Word::Equal, Word::Less, Word::Greater, Word::Not, Word::And, Word::Or,
+    Word::Depth, Word::Hp, Word::MaxHp, Word::X, Word::Y, Word::Random, Word::RandomFloor, Word::Tile,
+    Word::Message, Word::Damage, Word::Heal, Word::Teleport, Word::Spawn, Word::SetTile,
+];
+
+// Indexed by Word
+const WORD_NAMES: [&str; NUM_WORDS] = [
+    "dup", "drop", "swap", "over",
+    "+", "-", "*", "/", "mod",
+    "=", "<", ">", "not", "and", "or",
+    "depth", "hp", "max_hp", "x", "y", "random", "random_floor", "tile",
+    "message", "damage", "heal", "teleport", "spawn", "set_tile",
+];
+
+#[derive(Copy, Clone)]
+enum Op {
+    Number(i64),
+    Text(&'static str),
+    Word(Word),
+    // Targets are indices into the shared pool
+    Jump(usize),
+    JumpIfFalse(usize),
+}
+
+#[derive(Copy, Clone)]
+struct ScriptDef {
+    name: &'static str,
+    // Where its instructions are in the pool, redefining a script removes them and compiles new
+    // ones onto the end
+    start: usize,
+    len: usize,
+}
+
+const NO_SCRIPT_DEF: ScriptDef = ScriptDef { name: "", start: 0, len: 0 };
+
+fn script_named(name: &str) -> Option<usize> {
+    defs().scripts[..defs().num_scripts].iter().position(|script| script.name == name)
+}
+
+// The if, else and begin words that still need their then or until
+#[derive(Copy, Clone)]
+enum ScriptBlock {
+    If(usize),
+    Else(usize),
+    Begin(usize),
+}
+
+struct ScriptCompiler {
+    blocks: [ScriptBlock; MAX_SCRIPT_NESTING],
+    depth: usize,
+}
+
+impl ScriptCompiler {
+    fn new() -> Self {
+        ScriptCompiler { blocks: [ScriptBlock::Begin(0); MAX_SCRIPT_NESTING], depth: 0 }
+    }
+}
+
+fn emit_op(defs: &mut Defs, op: Op, line: usize) -> core::result::Result<usize, DefsError> {
+    if defs.num_ops == MAX_SCRIPT_OPS {
+        return Err(DefsError::TooManyOps { line });
+    }
+    defs.ops[defs.num_ops] = op;
+    defs.num_ops += 1;
+    Ok(defs.num_ops - 1)
+}
+
+// Takes a script's instructions out of the pool and moves the ones after them down, so a mod can
+// replace a script without using up the pool
+fn remove_script_ops(defs: &mut Defs, index: usize) {
+    let ScriptDef { start, len, .. } = defs.scripts[index];
+    // A forward copy is safe since they move down, and unlike copy_within it needs no memmove
+    for i in start..defs.num_ops - len {
+        defs.ops[i] = defs.ops[i + len];
+    }
+    defs.num_ops -= len;
+    // Scripts only jump within themselves, so every target in the moved ones moves with them
+    for op in defs.ops[start..defs.num_ops].iter_mut() {
+        match op {
+            Op::Jump(target) | Op::JumpIfFalse(target) => *target -= len,
+            _ => (),
+        }
+    }
+    for script in defs.scripts[..defs.num_scripts].iter_mut() {
+        if script.start > start {
+            script.start -= len;
+        }
+    }
+    defs.scripts[index].len = 0;
+}
+
+// Compiles one line of a [script NAME] section onto the end of the pool. Words are separated by
+// spaces, text goes in double quotes and a # comments out the rest of the line.
+fn compile_script_line(
+    defs: &mut Defs, compiler: &mut ScriptCompiler, line: &'static str, line_number: usize
+) -> core::result::Result<(), DefsError> {
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let (token, after) = if let Some(quoted) = rest.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => rest.split_at(end + 2),
+                None => return Err(DefsError::Script { line: line_number, token: rest, reason: "has no closing quote" }),
+            }
+        } else {
+            rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()))
+        };
+        rest = after.trim_start();
+        let script_error = |reason| Err(DefsError::Script { line: line_number, token, reason });
+        if token.starts_with('#') {
+            break;
+        }
+        if token.starts_with('"') {
+            emit_op(defs, Op::Text(&token[1..token.len() - 1]), line_number)?;
+            continue;
+        }
+        let digits = token.strip_prefix('-').unwrap_or(token);
+        if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()) {
+            let n = match parse_number(digits.as_bytes()) {
+                Some(n) if n <= i64::MAX as u64 => n as i64,
+                _ => return script_error("is too big"),
+            };
+            emit_op(defs, Op::Number(if digits.len() < token.len() { -n } else { n }), line_number)?;
+            continue;
+        }
+        match token {
+            "if" | "begin" => {
+                if compiler.depth == MAX_SCRIPT_NESTING {
+                    return script_error("is nested too deeply");
+                }
+                compiler.blocks[compiler.depth] = if token == "if" {
+                    ScriptBlock::If(emit_op(defs, Op::JumpIfFalse(0), line_number)?)
+                } else {
+                    ScriptBlock::Begin(defs.num_ops)
+                };
+                compiler.depth += 1;
+            },
+            "else" => match compiler.blocks[..compiler.depth].last() {
+                Some(ScriptBlock::If(jump)) => {
+                    let jump = *jump;
+                    let else_jump = emit_op(defs, Op::Jump(0), line_number)?;
+                    defs.ops[jump] = Op::JumpIfFalse(defs.num_ops);
+                    compiler.blocks[compiler.depth - 1] = ScriptBlock::Else(else_jump);
+                },
+                _ => return script_error("needs an if before it"),
+            },
+            "then" => match compiler.blocks[..compiler.depth].last() {
+                Some(ScriptBlock::If(jump)) => {
+                    defs.ops[*jump] = Op::JumpIfFalse(defs.num_ops);
+                    compiler.depth -= 1;
+                },
+                Some(ScriptBlock::Else(jump)) => {
+                    defs.ops[*jump] = Op::Jump(defs.num_ops);
+                    compiler.depth -= 1;
+                },
+                _ => return script_error("needs an if before it"),
+            },
+            "until" => match compiler.blocks[..compiler.depth].last() {
+                Some(ScriptBlock::Begin(start)) => {
+                    emit_op(defs, Op::JumpIfFalse(*start), line_number)?;
+                    compiler.depth -= 1;
+                },
+                _ => return script_error("needs a begin before it"),
+            },
+            _ => match WORD_NAMES.iter().position(|name| *name == token) {
+                Some(index) => {
+                    emit_op(defs, Op::Word(WORDS[index]), line_number)?;
+                },
+                None => return script_error("isn't a word scripts know"),
+            },
+        }
+    }
+    Ok(())
+}
+
+fn finish_script(defs: &mut Defs, index: usize, compiler: &ScriptCompiler, line: usize) -> core::result::Result<(), DefsError> {
+    if compiler.depth > 0 {
+        let reason = match compiler.blocks[compiler.depth - 1] {
+            ScriptBlock::Begin(_) => "has a begin without an until",
+            _ => "has an if without a then",
+        };
+        return Err(DefsError::Invalid { line, id: defs.scripts[index].name, reason });
+    }
+    defs.scripts[index].len = defs.num_ops - defs.scripts[index].start;
+    Ok(())
+}
+
+#[derive(Copy, Clone)]
+enum Value {
+    Number(i64),
+    Text(&'static str),
+}
+
+struct ScriptStack {
+    values: [Value; MAX_SCRIPT_STACK],
+    len: usize,
+}
+
+impl ScriptStack {
+    fn new() -> Self {
+        ScriptStack { values: [Value::Number(0); MAX_SCRIPT_STACK], len: 0 }
+    }
+
+    fn push(&mut self, value: Value) -> core::result::Result<(), &'static str> {
+        if self.len == MAX_SCRIPT_STACK {
+            return Err("the stack is full");
+        }
+        self.values[self.len] = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn push_number(&mut self, n: i64) -> core::result::Result<(), &'static str> {
+        self.push(Value::Number(n))
+    }
+
+    fn pop(&mut self) -> core::result::Result<Value, &'static str> {
+        if self.len == 0 {
+            return Err("the stack is empty");
+        }
+        self.len -= 1;
+        Ok(self.values[self.len])
+    }
+
+    fn pop_number(&mut self) -> core::result::Result<i64, &'static str> {
+        match self.pop()? {
+            Value::Number(n) => Ok(n),
+            Value::Text(_) => Err("it needed a number and got text"),
+        }
+    }
+
+    fn pop_text(&mut self) -> core::result::Result<&'static str, &'static str> {
+        match self.pop()? {
+            Value::Text(text) => Ok(text),
+            Value::Number(_) => Err("it needed text and got a number"),
+        }
+    }
+}
+
+// Every ASCII character, so scripts can be handed a tile as text
+static ASCII_TEXT: [u8; 128] = {
+    let mut text = [0u8; 128];
+    let mut i = 0;
+    while i < 128 {
+        text[i] = i as u8;
+        i += 1;
+    }
+    text
+};
+
+fn glyph_text(glyph: char) -> &'static str {
+    let i = glyph as usize;
+    if i < ASCII_TEXT.len() {
+        core::str::from_utf8(&ASCII_TEXT[i..i + 1]).unwrap_or("?")
+    } else {
+        "?"
+    }
+}
+
+// The tile a script may use, the margin around the map always stays wall so the window can't
+// run off the map following a player teleported there
+fn script_tile_position<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, x: i64, y: i64
+) -> Option<(u64, u64)>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    if x < 0 || y < 0 || !dungeon.in_play_area(x as u64, y as u64) {
+        return None;
+    }
+    Some((x as u64, y as u64))
+}
+
+// Somewhere a creature could stand, hidden traps count as taken so scripts can't find them
+fn is_open_floor<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, player: &Player, x: u64, y: u64
+) -> bool
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let tile = dungeon.check_collision(x, y);
+    (tile == FLOOR_CHAR || tile == DOOR_OPEN_CHAR)
+        && dungeon.monster_index_at(x, y).is_none()
+        && (x, y) != (player.x as u64, player.y as u64)
+}
+
+fn run_word<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    word: Word, stack: &mut ScriptStack, player: &mut Player, 
+    dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>, rng: &mut XorshiftRng, messages: &mut TurnMessages
+) -> core::result::Result<(), &'static str>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    match word {
+        Word::Dup => {
+            let value = stack.pop()?;
+            stack.push(value)?;
+            stack.push(value)?;
+        },
+        Word::Drop => {
+            stack.pop()?;
+        },
+        Word::Swap => {
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            stack.push(b)?;
+            stack.push(a)?;
+        },
+        Word::Over => {
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            stack.push(a)?;
+            stack.push(b)?;
+            stack.push(a)?;
+        },
+        Word::Add | Word::Subtract | Word::Multiply | Word::Divide | Word::Modulo 
+            | Word::Less | Word::Greater | Word::And | Word::Or => {
+            let b = stack.pop_number()?;
+            let a = stack.pop_number()?;
+            let result = match word {
+                Word::Add => a.wrapping_add(b),
+                Word::Subtract => a.wrapping_sub(b),
+                Word::Multiply => a.wrapping_mul(b),
+                Word::Divide | Word::Modulo if b == 0 => return Err("it divided by zero"),
+                Word::Divide => a.wrapping_div(b),
+                Word::Modulo => a.wrapping_rem(b),
+                Word::Less => (a < b) as i64,
+                Word::Greater => (a > b) as i64,
+                Word::And => (a != 0 && b != 0) as i64,
+                _ => (a != 0 || b != 0) as i64,
+            };
+            stack.push_number(result)?;
+        },
+        Word::Equal => {
+            let equal = match (stack.pop()?, stack.pop()?) {
+                (Value::Number(b), Value::Number(a)) => a == b,
+                (Value::Text(b), Value::Text(a)) => a == b,
+                _ => false,
+            };
+            stack.push_number(equal as i64)?;
+        },
+        Word::Not => {
+            let n = stack.pop_number()?;
+            stack.push_number((n == 0) as i64)?;
+        },
+        Word::Depth => stack.push_number(dungeon.depth as i64)?,
+        Word::Hp => stack.push_number(player.hp as i64)?,
+        Word::MaxHp => stack.push_number(player.max_hp() as i64)?,
+        Word::X => stack.push_number(player.x as i64)?,
+        Word::Y => stack.push_number(player.y as i64)?,
+        Word::Random => {
+            let n = stack.pop_number()?;
+            if n < 1 {
+                return Err("random needs a number above 0");
+            }
+            stack.push_number(rng.range(n as u64) as i64)?;
+        },
+        Word::RandomFloor => {
+            let (x, y) = dungeon.random_reachable_floor(player.x as u64, player.y as u64, rng)
+                .unwrap_or((player.x as u64, player.y as u64));
+            stack.push_number(x as i64)?;
+            stack.push_number(y as i64)?;
+        },
+        Word::Tile => {
+            let y = stack.pop_number()?;
+            let x = stack.pop_number()?;
+            let tile = match script_tile_position(dungeon, x, y) {
+                Some((x, y)) => tile_glyph(dungeon.check_collision(x, y)),
+                None => WALL_CHAR,
+            };
+            stack.push(Value::Text(glyph_text(tile)))?;
+        },
+        Word::Message => {
+            match stack.pop()? {
+                Value::Number(n) => {
+                    if n < 0 {
+                        messages.push("-");
+                    }
+                    messages.push_number(n.unsigned_abs());
+                },
+                Value::Text(text) => messages.push(text),
+            }
+            messages.push(" ");
+        },
+        Word::Damage => {
+            let amount = stack.pop_number()?.clamp(0, MAX_DEF_VALUE as i64) as i32;
+            let was_alive = player.hp > 0;
+            player.hp -= amount;
+            if was_alive && player.hp <= 0 {
+                player.death_cause = Some(DeathCause::Script);
+            }
+        },
+        Word::Heal => {
+            let amount = stack.pop_number()?.clamp(0, MAX_DEF_VALUE as i64) as i32;
+            player.hp = core::cmp::min(player.hp + amount, player.max_hp());
+        },
+        Word::Teleport => {
+            let y = stack.pop_number()?;
+            let x = stack.pop_number()?;
+            if let Some((x, y)) = script_tile_position(dungeon, x, y) {
+                if is_open_floor(dungeon, player, x, y) {
+                    player.x = x as usize;
+                    player.y = y as usize;
+                }
+            }
+        },
+        Word::Spawn => {
+            let y = stack.pop_number()?;
+            let x = stack.pop_number()?;
+            let id = stack.pop_text()?;
+            let kind = match monster_defs().iter().position(|def| def.id == id) {
+                Some(index) => MonsterKind(index as u8),
+                None => return Err("spawn was given a monster that doesn't exist"),
+            };
+            if let Some((x, y)) = script_tile_position(dungeon, x, y) {
+                if is_open_floor(dungeon, player, x, y) {
+                    dungeon.add_monster(Monster::new(kind, x, y));
+                }
+            }
+        },
+        Word::SetTile => {
+            let glyph = stack.pop_text()?;
+            let y = stack.pop_number()?;
+            let x = stack.pop_number()?;
+            let tile = match glyph {
+                "#" => WALL_CHAR,
+                "." => FLOOR_CHAR,
+                "+" => DOOR_CLOSED_CHAR,
+                "'" => DOOR_OPEN_CHAR,
+                _ => return Err("set_tile only makes walls, floors and doors"),
+            };
+            if let Some((x, y)) = script_tile_position(dungeon, x, y) {
+                // The stairs, locked doors and traps stay put, and nothing gets walled in
+                let current = dungeon.check_collision(x, y);
+                let changeable = current == WALL_CHAR || current == FLOOR_CHAR 
+                    || current == DOOR_CLOSED_CHAR || current == DOOR_OPEN_CHAR;
+                let blocks = tile == WALL_CHAR || tile == DOOR_CLOSED_CHAR;
+                let occupied = dungeon.monster_index_at(x, y).is_some() 
+                    || dungeon.item_index_at(x, y).is_some()
+                    || (x, y) == (player.x as u64, player.y as u64);
+                if changeable && !(blocks && occupied) {
+                    dungeon.set_tile(x, y, tile);
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+// Runs a script to the end, anything that goes wrong stops it and is reported in the messages
+// rather than ending the game
+fn run_script<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    script: usize, player: &mut Player, 
+    dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>, rng: &mut XorshiftRng, messages: &mut TurnMessages
+)
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let def = &defs().scripts[script];
+    let mut stack = ScriptStack::new();
+    let mut pc = def.start;
+    let mut steps = 0;
+    let mut result = Ok(());
+    while pc < def.start + def.len && result.is_ok() {
+        steps += 1;
+        if steps > MAX_SCRIPT_STEPS {
+            result = Err("it ran for too long");
+            break;
+        }
+        let op = defs().ops[pc];
+        pc += 1;
+        result = match op {
+            Op::Number(n) => stack.push_number(n),
+            Op::Text(text) => stack.push(Value::Text(text)),
+            Op::Word(word) => run_word(word, &mut stack, player, dungeon, rng, messages),
+            Op::Jump(target) => {
+                pc = target;
+                Ok(())
+            },
+            Op::JumpIfFalse(target) => stack.pop_number().map(|n| if n == 0 {
+                pc = target;
+            }),
+        };
+    }
+    if let Err(reason) = result {
+        messages.push("The ");
+        messages.push(def.name);
+        messages.push(" script stopped because ");
+        messages.push(reason);
+        messages.push(". ");
+    }
+}
+
+fn run_named_script<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(
+    name: &str, player: &mut Player, 
+    dungeon: &mut Dungeon<MAP_WIDTH, MAP_HEIGHT>, rng: &mut XorshiftRng, messages: &mut TurnMessages
+)
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    if let Some(script) = script_named(name) {
+        run_script(script, player, dungeon, rng, messages);
+    }
+}
+
+// How the game was launched from the command line
+struct LaunchOptions<'a> {
+    seed: u64,
+    // Set when the level editor starts the game, the map is the first floor and the new game 
+    // menus, saves and high scores are all skipped
+    playtest_map: Option<&'a [u8]>,
+    // Hand drawn floors to play before the generated ones
+    maps: [PathBuf; MAX_CUSTOM_MAPS],
+    num_maps: usize,
+    // Keys are recorded, so the saved game isn't offered and the replay starts from the menu
+    recording: bool,
+    // The bot plays instead of the terminal, like a playtest there are no menus, saves or scores
+    bot: Option<BotConfig>,
+}
+
+const WINDOW_WIDTH: usize = 40;
+const WINDOW_HEIGHT: usize = 20;
+// The margin keeps every room reachable with the player centred in the window
+const LEVEL_WIDTH: usize = 100 + (2 * WINDOW_WIDTH);
+const LEVEL_HEIGHT: usize = 100 + (2 * WINDOW_HEIGHT);
+const DEFAULT_MIN_ROOMS: u64 = 10;
+const DEFAULT_MAX_ROOMS: u64 = 50;
+const DEFAULT_MIN_ROOM_DIM: u64 = 5;
+const DEFAULT_MAX_ROOM_DIM: u64 = 20;
+// Smaller rooms can't fit the spawn, the stairs and the key, bigger ones can run off the map
+const MIN_ROOM_DIM_LIMIT: u64 = 2;
+const MAX_ROOM_DIM_LIMIT: u64 = 40;
+
+// Everything Dungeon::generate depends on, so the map tools can build the same floors as the game
+#[derive(Clone, Copy)]
+struct GenParams {
+    seed: u64,
+    depth: u64,
     min_rooms: u64,
     max_rooms: u64,
     min_room_dim: u64,
     max_room_dim: u64,
-    spawn_x: u64,
-    spawn_y: u64,
-    window_width: u64,
-    window_height: u64,
 }
 
-impl<const MAP_WIDTH: usize, const MAP_HEIGHT: usize> Dungeon<MAP_WIDTH, MAP_HEIGHT> 
-where
-    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
-{
-    fn new(
-        seed: u64, 
-        min_rooms: u64, 
-        max_rooms: u64, 
-        min_room_dim: u64, 
-        max_room_dim: u64, 
-        window_width: u64, 
-        window_height: u64
-    ) -> Self {
-        Dungeon {
-            rng: XorshiftRng::new(seed),
-            map: ['#'; MAP_WIDTH * MAP_HEIGHT],
-            min_rooms: min_rooms,
-            max_rooms: max_rooms,
-            min_room_dim: min_room_dim,
-            max_room_dim: max_room_dim,
-            spawn_x: 0,
-            spawn_y: 0,
-            window_width: window_width,
-            window_height: window_height 
+impl GenParams {
+    fn new() -> Self {
+        GenParams {
+            seed: DEFAULT_SEED,
+            depth: 1,
+            min_rooms: DEFAULT_MIN_ROOMS,
+            max_rooms: DEFAULT_MAX_ROOMS,
+            min_room_dim: DEFAULT_MIN_ROOM_DIM,
+            max_room_dim: DEFAULT_MAX_ROOM_DIM,
+        }
+    }
+
+    // Parses one "--name value" pair, returns false if it isn't a generator option or the value is bad
+    fn parse_option(&mut self, name: &[u8], value: &[u8]) -> bool {
+        let value = match parse_number(value) {
+            Some(value) => value,
+            None => return false,
+        };
+        match name {
+            b"--seed" => self.seed = value,
+            b"--depth" => self.depth = value,
+            b"--min-rooms" => self.min_rooms = value,
+            b"--max-rooms" => self.max_rooms = value,
+            b"--min-room-size" => self.min_room_dim = value,
+            b"--max-room-size" => self.max_room_dim = value,
+            _ => return false,
+        }
+        true
+    }
+
+    fn validate(&self) -> core::result::Result<(), &'static str> {
+        if self.depth == 0 {
+            return Err("--depth starts at 1");
+        }
+        if self.min_rooms == 0 || self.min_rooms > self.max_rooms || self.max_rooms as usize > MAX_ROOMS {
+            return Err("--min-rooms must be at least 1 and at most --max-rooms, which must be at most 64");
+        }
+        if self.min_room_dim < MIN_ROOM_DIM_LIMIT || self.min_room_dim > self.max_room_dim || self.max_room_dim > MAX_ROOM_DIM_LIMIT {
+            return Err("room sizes must be between 2 and 40 with --min-room-size at most --max-room-size");
+        }
+        Ok(())
+    }
+
+    // The floor before anything is generated on it
+    fn new_dungeon(&self) -> Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT> {
+        let mut dungeon = Dungeon::<LEVEL_WIDTH, LEVEL_HEIGHT>::new(
+            self.seed,
+            self.min_rooms, self.max_rooms,
+            self.min_room_dim, self.max_room_dim,
+            WINDOW_WIDTH as u64, WINDOW_HEIGHT as u64
+        );
+        dungeon.depth = self.depth;
+        dungeon.food_per_floor = DIFFICULTY_DEFS[Difficulty::Normal as usize].food_per_floor;
+        dungeon
+    }
+
+    fn generate(&self) -> Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT> {
+        let mut dungeon = self.new_dungeon();
+        dungeon.generate();
+        dungeon
+    }
+}
+
+fn parse_number(text: &[u8]) -> Option<u64> {
+    if text.is_empty() {
+        return None;
+    }
+    let mut n: u64 = 0;
+    for c in text.iter() {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((c - b'0') as u64)?;
+    }
+    Some(n)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MapFormat {
+    Ascii,
+    Json,
+    Ppm,
+}
+
+// Tiles with the spawn and the key drawn on top, the same layout map files use
+fn map_glyph<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, x: u64, y: u64) -> char
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    if (x, y) == (dungeon.spawn_x, dungeon.spawn_y) {
+        PLAYER_CHAR
+    } else if (x, y) == (dungeon.key_x, dungeon.key_y) {
+        KEY_CHAR
+    } else {
+        dungeon.map[y as usize * MAP_WIDTH + x as usize]
+    }
+}
+
+const PPM_WALL_COLOUR: [u8; 3] = [40, 40, 48];
+const PPM_FLOOR_COLOUR: [u8; 3] = [200, 200, 190];
+const PPM_DOOR_COLOUR: [u8; 3] = [140, 90, 40];
+const PPM_SPAWN_COLOUR: [u8; 3] = [60, 200, 60];
+const PPM_STAIRS_COLOUR: [u8; 3] = [240, 220, 40];
+const PPM_KEY_COLOUR: [u8; 3] = [250, 140, 0];
+const PPM_TRAP_COLOUR: [u8; 3] = [200, 40, 200];
+// Indexed by lock colour
+const PPM_LOCK_COLOURS: [[u8; 3]; NUM_LOCK_COLOURS] = [[220, 40, 40], [40, 180, 40], [40, 80, 220]];
+
+fn ppm_colour(glyph: char) -> [u8; 3] {
+    match glyph {
+        WALL_CHAR => PPM_WALL_COLOUR,
+        DOOR_CLOSED_CHAR | DOOR_OPEN_CHAR => PPM_DOOR_COLOUR,
+        PLAYER_CHAR => PPM_SPAWN_COLOUR,
+        STAIRS_CHAR => PPM_STAIRS_COLOUR,
+        KEY_CHAR => PPM_KEY_COLOUR,
+        TRAP_CHAR => PPM_TRAP_COLOUR,
+        _ => match lock_colour(glyph) {
+            Some(colour) => PPM_LOCK_COLOURS[colour],
+            None => PPM_FLOOR_COLOUR,
+        },
+    }
+}
+
+fn push_json_point(line: &mut MessageLine, name: &str, x: u64, y: u64) {
+    line.push("  \"");
+    line.push(name);
+    line.push("\": {\"x\": ");
+    line.push_number(x);
+    line.push(", \"y\": ");
+    line.push_number(y);
+    line.push("},\n");
+}
+
+fn write_stdout(bytes: &[u8]) {
+    if let Result::Err(_) = write_all(STDOUT_FILENO, bytes) {
+        exit(1);
+    }
+}
+
+fn dump_map<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, params: &GenParams, format: MapFormat)
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized,
+    // One row of pixels
+    [(); MAP_WIDTH * 3]: Sized
+{
+    let mut line = MessageLine::new();
+    match format {
+        MapFormat::Ascii => {
+            for y in 0..MAP_HEIGHT as u64 {
+                line.clear();
+                for x in 0..MAP_WIDTH as u64 {
+                    line.push_char(map_glyph(dungeon, x, y));
+                }
+                line.push("\n");
+                print(line.as_str());
+            }
+        },
+        MapFormat::Json => {
+            line.push("{\n  \"width\": ");
+            line.push_number(MAP_WIDTH as u64);
+            line.push(",\n  \"height\": ");
+            line.push_number(MAP_HEIGHT as u64);
+            line.push(",\n  \"seed\": ");
+            line.push_number(params.seed);
+            line.push(",\n  \"depth\": ");
+            line.push_number(params.depth);
+            line.push(",\n");
+            push_json_point(&mut line, "spawn", dungeon.spawn_x, dungeon.spawn_y);
+            push_json_point(&mut line, "key", dungeon.key_x, dungeon.key_y);
+            push_json_point(&mut line, "stairs", dungeon.stairs_x, dungeon.stairs_y);
+            line.push("  \"rooms\": [");
+            print(line.as_str());
+            for i in 0..dungeon.num_rooms {
+                let room = &dungeon.rooms[i];
+                line.clear();
+                line.push(if i == 0 { "\n    " } else { ",\n    " });
+                line.push("{\"x\": ");
+                line.push_number(room.x);
+                line.push(", \"y\": ");
+                line.push_number(room.y);
+                line.push(", \"width\": ");
+                line.push_number(room.width);
+                line.push(", \"height\": ");
+                line.push_number(room.height);
+                line.push("}");
+                print(line.as_str());
+            }
+            print("\n  ],\n  \"tiles\": [");
+            // One string per row, none of the tile glyphs need escaping
+            for y in 0..MAP_HEIGHT {
+                line.clear();
+                line.push(if y == 0 { "\n    \"" } else { ",\n    \"" });
+                for x in 0..MAP_WIDTH {
+                    line.push_char(dungeon.map[y * MAP_WIDTH + x]);
+                }
+                line.push("\"");
+                print(line.as_str());
+            }
+            print("\n  ]\n}\n");
+        },
+        MapFormat::Ppm => {
+            // Binary PPM, one pixel per tile
+            line.push("P6\n");
+            line.push_number(MAP_WIDTH as u64);
+            line.push(" ");
+            line.push_number(MAP_HEIGHT as u64);
+            line.push("\n255\n");
+            write_stdout(line.as_str().as_bytes());
+            let mut row = [0u8; MAP_WIDTH * 3];
+            for y in 0..MAP_HEIGHT as u64 {
+                for x in 0..MAP_WIDTH as u64 {
+                    let colour = ppm_colour(map_glyph(dungeon, x, y));
+                    row[x as usize * 3..x as usize * 3 + 3].copy_from_slice(&colour);
+                }
+                write_stdout(&row);
+            }
+        },
+    }
+}
+
+const DEFAULT_STATS_COUNT: u64 = 100;
+// Spawn to stairs distances are bucketed this many tiles at a time, the last bucket is open ended
+const PATH_BUCKET_SIZE: u64 = 25;
+const NUM_PATH_BUCKETS: usize = 12;
+const PATH_BAR_WIDTH: u64 = 40;
+
+// What the stats harness measures on one floor
+struct FloorStats {
+    // In tenths of a percent of the whole map
+    coverage: u64,
+    rooms: u64,
+    // Corridors always run from one room to another, so this stays 0 unless the generator breaks
+    dead_ends: u64,
+    // With every lock open, None if the stairs can't be reached at all
+    path_length: Option<u64>,
+    // The key and the stairs can be reached in order
+    valid: bool,
+    generate_us: u64,
+}
+
+fn measure_floor<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>, generate_us: u64) -> FloorStats
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let all_keys = (1 << NUM_LOCK_COLOURS) - 1;
+    let mut walkable = 0;
+    let mut dead_ends = 0;
+    for y in 1..MAP_HEIGHT - 1 {
+        for x in 1..MAP_WIDTH - 1 {
+            if !dungeon.is_passable(x as u64, y as u64, all_keys) {
+                continue;
+            }
+            walkable += 1;
+            let neighbours = [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)];
+            let exits = neighbours.iter().filter(|(nx, ny)| dungeon.is_passable(*nx as u64, *ny as u64, all_keys)).count();
+            if exits == 1 {
+                dead_ends += 1;
+            }
+        }
+    }
+    FloorStats {
+        coverage: walkable * 1000 / (MAP_WIDTH * MAP_HEIGHT) as u64,
+        rooms: dungeon.num_rooms as u64,
+        dead_ends,
+        path_length: dungeon.path_length(dungeon.spawn_x, dungeon.spawn_y, dungeon.stairs_x, dungeon.stairs_y, all_keys),
+        valid: dungeon.is_solvable(),
+        generate_us,
+    }
+}
+
+fn elapsed_us(start: (usize, usize), end: (usize, usize)) -> u64 {
+    let start_ns = start.0 as u64 * 1_000_000_000 + start.1 as u64;
+    let end_ns = end.0 as u64 * 1_000_000_000 + end.1 as u64;
+    end_ns.saturating_sub(start_ns) / 1000
+}
+
+// Writes e.g. 12.3 for 123
+fn push_tenths(line: &mut MessageLine, tenths: u64) {
+    line.push_number(tenths / 10);
+    line.push(".");
+    line.push_number(tenths % 10);
+}
+
+// Running minimum, maximum and total of one statistic
+struct Summary {
+    min: u64,
+    max: u64,
+    total: u64,
+    count: u64,
+}
+
+impl Summary {
+    fn new() -> Self {
+        Summary { min: u64::MAX, max: 0, total: 0, count: 0 }
+    }
+
+    fn add(&mut self, value: u64) {
+        self.min = core::cmp::min(self.min, value);
+        self.max = core::cmp::max(self.max, value);
+        self.total += value;
+        self.count += 1;
+    }
+
+    // Values are scaled by ten when tenths is false so every column prints one decimal
+    fn push_row(&self, line: &mut MessageLine, name: &str, tenths: bool) {
+        let scale = if tenths { 1 } else { 10 };
+        line.clear();
+        line.push(name);
+        for _ in name.len()..24 {
+            line.push_char(' ');
+        }
+        if self.count == 0 {
+            line.push("-\n");
+            return;
+        }
+        for value in [self.min * scale, self.total * scale / self.count, self.max * scale].iter() {
+            let mut cell = MessageLine::new();
+            push_tenths(&mut cell, *value);
+            for _ in cell.len..12 {
+                line.push_char(' ');
+            }
+            line.push(cell.as_str());
+        }
+        line.push("\n");
+    }
+}
+
+// Generates a run of seeds without a terminal and reports how the floors came out
+fn run_gen_stats(env: &Env) -> ! {
+    let mut params = GenParams::new();
+    let mut count = DEFAULT_STATS_COUNT;
+    let mut csv = false;
+    let mut i = 2;
+    while let Some(name) = env.arg(i) {
+        if name == b"--csv" {
+            csv = true;
+            i += 1;
+            continue;
+        }
+        let parsed = match (name, env.arg(i + 1)) {
+            (b"--count", Some(value)) => match parse_number(value) {
+                Some(value) if value > 0 => {
+                    count = value;
+                    true
+                },
+                _ => false,
+            },
+            (_, Some(value)) => params.parse_option(name, value),
+            (_, None) => false,
+        };
+        if !parsed {
+            print_usage();
+            exit(2);
+        }
+        i += 2;
+    }
+    if let Err(message) = params.validate() {
+        print(message);
+        print("\n");
+        exit(2);
+    }
+
+    let first_seed = params.seed;
+    let mut line = MessageLine::new();
+    if csv {
+        print("seed,coverage_percent,rooms,dead_ends,path_length,valid,generate_us\n");
+    }
+    let mut coverage = Summary::new();
+    let mut rooms = Summary::new();
+    let mut dead_ends = Summary::new();
+    let mut path_lengths = Summary::new();
+    let mut generate_us = Summary::new();
+    let mut path_buckets = [0u64; NUM_PATH_BUCKETS];
+    let mut invalid = 0;
+    for seed in first_seed..first_seed.saturating_add(count) {
+        params.seed = seed;
+        let mut dungeon = params.new_dungeon();
+        let start = get_time();
+        dungeon.generate();
+        let stats = measure_floor(&dungeon, elapsed_us(start, get_time()));
+
+        if csv {
+            line.clear();
+            line.push_number(seed);
+            line.push(",");
+            push_tenths(&mut line, stats.coverage);
+            line.push(",");
+            line.push_number(stats.rooms);
+            line.push(",");
+            line.push_number(stats.dead_ends);
+            line.push(",");
+            if let Some(length) = stats.path_length {
+                line.push_number(length);
+            }
+            line.push(if stats.valid { ",1," } else { ",0," });
+            line.push_number(stats.generate_us);
+            line.push("\n");
+            print(line.as_str());
+        }
+        coverage.add(stats.coverage);
+        rooms.add(stats.rooms);
+        dead_ends.add(stats.dead_ends);
+        generate_us.add(stats.generate_us);
+        if let Some(length) = stats.path_length {
+            path_lengths.add(length);
+            path_buckets[core::cmp::min(length / PATH_BUCKET_SIZE, NUM_PATH_BUCKETS as u64 - 1) as usize] += 1;
+        }
+        if !stats.valid {
+            invalid += 1;
+        }
+    }
+    if csv {
+        exit(0);
+    }
+
+    line.clear();
+    line.push_number(coverage.count);
+    line.push(" floors, seeds ");
+    line.push_number(first_seed);
+    line.push(" to ");
+    line.push_number(params.seed);
+    line.push(", depth ");
+    line.push_number(params.depth);
+    line.push("\n\n                                 min        mean         max\n");
+    print(line.as_str());
+    coverage.push_row(&mut line, "Floor coverage %", true);
+    print(line.as_str());
+    rooms.push_row(&mut line, "Rooms", false);
+    print(line.as_str());
+    dead_ends.push_row(&mut line, "Dead ends", false);
+    print(line.as_str());
+    path_lengths.push_row(&mut line, "Spawn to stairs", false);
+    print(line.as_str());
+    generate_us.push_row(&mut line, "Generation time (us)", false);
+    print(line.as_str());
+
+    line.clear();
+    line.push("\nInvalid floors: ");
+    line.push_number(invalid);
+    line.push(" (");
+    push_tenths(&mut line, invalid * 1000 / coverage.count);
+    line.push("%)\n\nSpawn to stairs distance\n");
+    print(line.as_str());
+    let most = path_buckets.iter().copied().max().unwrap_or(0);
+    for (bucket, floors) in path_buckets.iter().enumerate() {
+        line.clear();
+        let mut label = MessageLine::new();
+        label.push_number(bucket as u64 * PATH_BUCKET_SIZE);
+        if bucket + 1 < NUM_PATH_BUCKETS {
+            label.push("-");
+            label.push_number((bucket as u64 + 1) * PATH_BUCKET_SIZE - 1);
+        } else {
+            label.push("+");
+        }
+        for _ in label.len..10 {
+            line.push_char(' ');
+        }
+        line.push(label.as_str());
+        line.push(" ");
+        let bar = if most == 0 { 0 } else { (floors * PATH_BAR_WIDTH).div_ceil(most) };
+        for _ in 0..bar {
+            line.push_char('#');
+        }
+        line.push(" ");
+        line.push_number(*floors);
+        line.push("\n");
+        print(line.as_str());
+    }
+    exit(0);
+}
+
+const DEFAULT_CHECK_COUNT: u64 = 1000;
+// Enough bytes for the seed and one byte for each of the other generator parameters
+const CHECK_INPUT_CAPACITY: usize = 64;
+// A floor takes a few milliseconds, one that takes this long is stuck
+const CHECK_TIMEOUT_SECS: u32 = 10;
+
+// The floor --check-gen is generating, so the alarm handler can say which one got stuck
+static mut CHECKING_PARAMS: Option<GenParams> = None;
+
+extern "C" fn on_check_timeout(_signal: i32) {
+    if let Some(params) = unsafe { *core::ptr::addr_of!(CHECKING_PARAMS) } {
+        report_bad_floor(&params, "the generator didn't finish");
+    }
+    exit(1);
+}
+
+fn watch_for_hangs() {
+    let action = SigAction {
+        sa_handler: on_check_timeout as extern "C" fn(i32) as usize,
+        sa_flags: SA_RESTORER,
+        sa_restorer: signal_restorer as extern "C" fn() -> ! as usize,
+        sa_mask: 0,
+    };
+    unsafe { rt_sigaction(SIGALRM, &action, core::ptr::null_mut()) };
+}
+
+// A hang would stop the check without saying anything, so an alarm reports the floor and exits
+// with 1 if the generator takes too long
+fn generate_with_timeout(params: &GenParams) -> Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT> {
+    unsafe {
+        CHECKING_PARAMS = Some(*params);
+        alarm(CHECK_TIMEOUT_SECS);
+    }
+    let dungeon = params.generate();
+    unsafe { alarm(0) };
+    dungeon
+}
+
+// Looks for anything on a freshly generated floor that the game relies on and the generator 
+// could get wrong, returns what was wrong with it
+fn check_floor<const MAP_WIDTH: usize, const MAP_HEIGHT: usize>(dungeon: &Dungeon<MAP_WIDTH, MAP_HEIGHT>) -> core::result::Result<(), &'static str>
+where
+    [(); MAP_WIDTH * MAP_HEIGHT]: Sized
+{
+    let mut stairs = 0;
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            let tile = dungeon.map[y * MAP_WIDTH + x];
+            if tile != WALL_CHAR && !dungeon.in_play_area(x as u64, y as u64) {
+                return Err("a tile was carved outside the room area");
+            }
+            if tile == STAIRS_CHAR {
+                stairs += 1;
+            }
+        }
+    }
+    if stairs != 1 || dungeon.check_collision(dungeon.stairs_x, dungeon.stairs_y) != STAIRS_CHAR {
+        return Err("the floor doesn't have exactly one staircase where it should be");
+    }
+    if !dungeon.in_play_area(dungeon.spawn_x, dungeon.spawn_y) || dungeon.check_collision(dungeon.spawn_x, dungeon.spawn_y) != FLOOR_CHAR {
+        return Err("the spawn isn't on a floor tile");
+    }
+    let spawn = (dungeon.spawn_x, dungeon.spawn_y);
+    let stairs = (dungeon.stairs_x, dungeon.stairs_y);
+    let key = (dungeon.key_x, dungeon.key_y);
+    if spawn == stairs || key == spawn || key == stairs {
+        return Err("the spawn, the key and the stairs overlap");
+    }
+    let mut keys = 0;
+    for floor_item in dungeon.floor_items().iter() {
+        if !dungeon.in_play_area(floor_item.x, floor_item.y) || dungeon.check_collision(floor_item.x, floor_item.y) == WALL_CHAR {
+            return Err("an item is inside a wall");
+        }
+        if floor_item.item.kind == ItemKind::StairsKey {
+            if (floor_item.x, floor_item.y) != key {
+                return Err("the key isn't where the floor says it is");
+            }
+            keys += 1;
+        }
+    }
+    if keys != 1 {
+        return Err("the floor doesn't have exactly one key");
+    }
+    for monster in dungeon.monsters[..dungeon.num_monsters].iter() {
+        if !dungeon.in_play_area(monster.x, monster.y) || !dungeon.is_walkable_for_monster(monster.x, monster.y) {
+            return Err("a monster is inside a wall");
+        }
+    }
+    for trap in dungeon.traps[..dungeon.num_traps].iter() {
+        if dungeon.check_collision(trap.x, trap.y) != TRAP_CHAR {
+            return Err("a trap isn't on a trap tile");
+        }
+    }
+    if !dungeon.is_solvable() {
+        return Err("the key or the stairs can't be reached");
+    }
+    Ok(())
+}
+
+// Turns arbitrary bytes into valid generator parameters, so a fuzzer's input always reaches 
+// the generator instead of being rejected by validate
+fn params_from_bytes(bytes: &[u8]) -> GenParams {
+    let byte = |i: usize| if i < bytes.len() { bytes[i] as u64 } else { 0 };
+    let mut seed = 0;
+    for i in 0..8 {
+        seed |= byte(i) << (i * 8);
+    }
+    let min_rooms = 1 + byte(9) % MAX_ROOMS as u64;
+    let max_rooms = min_rooms + byte(10) % (MAX_ROOMS as u64 + 1 - min_rooms);
+    let min_room_dim = MIN_ROOM_DIM_LIMIT + byte(11) % (MAX_ROOM_DIM_LIMIT - MIN_ROOM_DIM_LIMIT + 1);
+    let max_room_dim = min_room_dim + byte(12) % (MAX_ROOM_DIM_LIMIT + 1 - min_room_dim);
+    GenParams {
+        seed,
+        depth: 1 + byte(8) % 32,
+        min_rooms,
+        max_rooms,
+        min_room_dim,
+        max_room_dim,
+    }
+}
+
+// Entry point for the cargo-fuzz target in fuzz/, it checks one floor like --check-gen --input.
+// libFuzzer's own -timeout catches a generator that hangs.
+#[cfg(fuzzing)]
+pub fn fuzz_generate(bytes: &[u8]) {
+    static LOAD_DEFS: std::sync::Once = std::sync::Once::new();
+    LOAD_DEFS.call_once(load_builtin_defs);
+    let params = params_from_bytes(bytes);
+    if let Err(problem) = check_floor(&params.generate()) {
+        report_bad_floor(&params, problem);
+        panic!("{}", problem);
+    }
+}
+
+fn report_bad_floor(params: &GenParams, problem: &str) {
+    let mut line = MessageLine::new();
+    line.push("seed ");
+    line.push_number(params.seed);
+    line.push(" --depth ");
+    line.push_number(params.depth);
+    line.push(" --min-rooms ");
+    line.push_number(params.min_rooms);
+    line.push(" --max-rooms ");
+    line.push_number(params.max_rooms);
+    line.push(" --min-room-size ");
+    line.push_number(params.min_room_dim);
+    line.push(" --max-room-size ");
+    line.push_number(params.max_room_dim);
+    line.push(": ");
+    line.push(problem);
+    line.push("\n");
+    print(line.as_str());
+}
+
+// Generates every seed in the range twice, once with the game's parameters and once with random 
+// ones, and checks each floor. Exits with 1 if any floor was bad
+fn run_check_gen(env: &Env) -> ! {
+    if env.arg(2) == Some(b"--input") {
+        // One floor from raw bytes, for driving the generator from an external fuzzer
+        let path = match env.arg(3).and_then(path_from_arg) {
+            Some(path) if env.arg(4).is_none() => path,
+            _ => {
+                print_usage();
+                exit(2);
+            }
+        };
+        let mut bytes = [0u8; CHECK_INPUT_CAPACITY];
+        let len = match read_file(&path, &mut bytes) {
+            Result::Ok(len) => len,
+            Result::Err(errno) => {
+                print("Couldn't read ");
+                print(path.as_str());
+                print(" (errno ");
+                print_number(errno as u64);
+                print(")\n");
+                exit(2);
+            }
+        };
+        let params = params_from_bytes(&bytes[..len]);
+        watch_for_hangs();
+        if let Err(problem) = check_floor(&generate_with_timeout(&params)) {
+            report_bad_floor(&params, problem);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    let mut first_seed = DEFAULT_SEED;
+    let mut count = DEFAULT_CHECK_COUNT;
+    let mut i = 2;
+    while let Some(name) = env.arg(i) {
+        let value = env.arg(i + 1).and_then(parse_number);
+        match (name, value) {
+            (b"--seed", Some(value)) => first_seed = value,
+            (b"--count", Some(value)) if value > 0 => count = value,
+            _ => {
+                print_usage();
+                exit(2);
+            }
         }
+        i += 2;
     }
 
-    fn map_width(&self) -> usize {
-        MAP_WIDTH
+    watch_for_hangs();
+    let mut bad = 0;
+    let mut rng = XorshiftRng::new(first_seed);
+    for seed in first_seed..first_seed.saturating_add(count) {
+        let mut params = GenParams::new();
+        params.seed = seed;
+        params.depth = 1 + seed % 10;
+        let mut random_bytes = [0u8; 13];
+        for byte in random_bytes.iter_mut() {
+            *byte = rng.range(256) as u8;
+        }
+        let mut random_params = params_from_bytes(&random_bytes);
+        random_params.seed = seed;
+        for params in [params, random_params].iter() {
+            if let Err(problem) = check_floor(&generate_with_timeout(params)) {
+                report_bad_floor(params, problem);
+                bad += 1;
+            }
+        }
     }
+    let mut line = MessageLine::new();
+    line.push_number(count * 2);
+    line.push(" floors checked, ");
+    line.push_number(bad);
+    line.push(" bad\n");
+    print(line.as_str());
+    exit(if bad == 0 { 0 } else { 1 });
+}
 
-    fn map_height(&self) -> usize {
-        MAP_HEIGHT
+// Follow-up keys the bot can queue for the prompt its last key opens
+const MAX_BOT_KEYS: usize = 4;
+// The bot gives up if this many of its keys in a row didn't let a turn pass
+const BOT_STALL_LIMIT: u32 = 50;
+const DEFAULT_BOT_COUNT: u64 = 20;
+const DEFAULT_BOT_GOAL_DEPTH: u64 = 5;
+const DEFAULT_BOT_MAX_TURNS: u64 = 5000;
+const MAX_BOT_DEATH_CAUSES: usize = 16;
+const BOT_DIRECTIONS: [(i64, i64, u8); 4] = [(0, -1, KEY_UP), (0, 1, KEY_DOWN), (-1, 0, KEY_LEFT), (1, 0, KEY_RIGHT)];
+
+fn queue_bot_key(key: u8) {
+    let input = unsafe { &mut *core::ptr::addr_of_mut!(INPUT) };
+    if input.num_bot_keys < MAX_BOT_KEYS {
+        input.bot_keys[input.num_bot_keys] = key;
+        input.num_bot_keys += 1;
     }
+}
 
-    fn map_size(&self) -> usize {
-        MAP_WIDTH * MAP_HEIGHT 
+fn next_bot_key(input: &mut InputState) -> u8 {
+    if input.num_bot_keys == 0 {
+        return KEY_CONTINUE;
     }
+    let key = input.bot_keys[0];
+    for i in 1..input.num_bot_keys {
+        input.bot_keys[i - 1] = input.bot_keys[i];
+    }
+    input.num_bot_keys -= 1;
+    key
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BotStrategy {
+    // Makes for the key and then the stairs as soon as it has seen them
+    Dive,
+    // Explores the whole floor and picks up and wears what it finds before taking the stairs
+    Explore,
+}
+
+const BOT_STRATEGIES: [BotStrategy; 2] = [BotStrategy::Dive, BotStrategy::Explore];
+// Indexed by BotStrategy
+const BOT_STRATEGY_NAMES: [&str; 2] = ["dive", "explore"];
+
+#[derive(Clone, Copy)]
+struct BotConfig {
+    strategy: BotStrategy,
+    class: Class,
+    // Reaching this depth wins the run
+    goal_depth: u64,
+    // The bot quits once this many turns have passed
+    max_turns: u64,
+}
+
+// How a run ended, for the tools that play it without a player
+struct RunEnd {
+    depth: u64,
+    turn: u64,
+    died: bool,
+    death_cause: Option<DeathCause>,
+}
+
+const BOT_MAP_SIZE: usize = LEVEL_WIDTH * LEVEL_HEIGHT;
 
-    fn place_floor_tile(&mut self, x: usize, y: usize) {
-        if x < MAP_WIDTH && y < MAP_HEIGHT {
-            self.map[y * MAP_WIDTH + x] = FLOOR_CHAR;
+// The bot's path search scratch space, as big as the level so it's kept out of the stack. It's
+// kept between turns because clearing it cost more than searching, a tile has been reached in
+// the current search when its stamp matches Bot::search. The queue is FLOOD_QUEUE.
+static mut BOT_REACHED: [u32; BOT_MAP_SIZE] = [0; BOT_MAP_SIZE];
+static mut BOT_FIRST_STEP: [u8; BOT_MAP_SIZE] = [0; BOT_MAP_SIZE];
+
+// Plays the game through the same keys as a player, it only knows the tiles the player has seen
+struct Bot {
+    config: BotConfig,
+    last_turn: u64,
+    stalled: u32,
+    // Stamp of the current path search in BOT_REACHED
+    search: u32,
+}
+
+impl Bot {
+    fn new(config: BotConfig) -> Self {
+        // Stamps left by the last bot would match this one's first searches
+        unsafe { (*core::ptr::addr_of_mut!(BOT_REACHED)).fill(0) };
+        Bot {
+            config,
+            last_turn: 0,
+            stalled: 0,
+            search: 0,
         }
     }
 
-    fn generate(&mut self) {
-        for i in 0..self.map_size() {
-            self.map[i] = '#';
+    // Toughness, might and guard in turn
+    fn perk_key(&self, player: &Player) -> u8 {
+        b'a' + (player.level as usize % 3) as u8
+    }
+
+    fn next_key(&mut self, dungeon: &Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>, player: &Player, turn: u64) -> u8 {
+        if turn == self.last_turn {
+            self.stalled += 1;
+        } else {
+            self.last_turn = turn;
+            self.stalled = 0;
+        }
+        if dungeon.depth >= self.config.goal_depth || turn >= self.config.max_turns || self.stalled > BOT_STALL_LIMIT {
+            return KEY_QUIT;
         }
 
-        // num_rooms must be at least 3 to accomodate special tiles
-        let num_rooms = {
-            let num_rooms_rng = self.rng.range_with_min(self.min_rooms, self.max_rooms);
-            if num_rooms_rng >= 3 {
-                num_rooms_rng
+        let explore = self.config.strategy == BotStrategy::Explore;
+        // Room is kept for the stairs key and a key of every colour
+        let pack_full = player.inventory.len() + NUM_LOCK_COLOURS + 1 >= INVENTORY_CAPACITY;
+        let (x, y) = (player.x as u64, player.y as u64);
+
+        if player.class == Class::Fighter && player.ability_cooldown == 0 && player.hp * 3 <= player.max_hp() {
+            return KEY_ABILITY;
+        }
+        if hunger_stage(player.nutrition) > 0 {
+            if let Some(slot) = player.inventory.find(ItemKind::Ration) {
+                queue_bot_key(b'a' + slot as u8);
+                return KEY_USE;
+            }
+        }
+        if explore {
+            for (slot, item) in player.inventory.slots.iter().enumerate() {
+                if let Some(equip_slot) = item.and_then(|item| item.slot()) {
+                    if player.equipment[equip_slot as usize].is_none() {
+                        queue_bot_key(b'a' + slot as u8);
+                        return KEY_EQUIP;
+                    }
+                }
+            }
+        }
+        if let Some(index) = dungeon.item_index_at(x, y) {
+            let kind = dungeon.items[index].item.kind;
+            if !pack_full && (explore || kind == ItemKind::Ration) {
+                return KEY_PICKUP;
+            }
+        }
+        // Sleeping monsters are left alone when diving
+        for (dx, dy, key) in BOT_DIRECTIONS.iter() {
+            let target_x = (x as i64 + dx) as u64;
+            let target_y = (y as i64 + dy) as u64;
+            if let Some(index) = dungeon.monster_index_at(target_x, target_y) {
+                if explore || dungeon.monsters[index].awake {
+                    return *key;
+                }
+            }
+        }
+
+        let unlocked = unlocked_colours(player);
+        let has_key = player.inventory.find(ItemKind::StairsKey).is_some();
+        let stairs_index = (dungeon.stairs_y as usize) * LEVEL_WIDTH + (dungeon.stairs_x as usize);
+        let key_index = (dungeon.key_y as usize) * LEVEL_WIDTH + (dungeon.key_x as usize);
+        // Diving goes straight for the key and then the stairs once they have been seen
+        let target = if has_key { stairs_index } else { key_index };
+        if !explore && dungeon.explored[target] {
+            if let Some(key) = self.step_towards(dungeon, player, true, &|index| index == target) {
+                return key;
+            }
+        }
+        let mut wanted = [0; MAX_FLOOR_ITEMS];
+        let mut num_wanted = 0;
+        for floor_item in dungeon.floor_items().iter() {
+            if !dungeon.is_explored(floor_item.x, floor_item.y) {
+                continue;
+            }
+            let kind = floor_item.item.kind;
+            let is_wanted = if floor_item.item.has_flag(ITEM_FLAG_QUEST) {
+                kind != ItemKind::StairsKey || !has_key
             } else {
-                3
+                !pack_full && (explore || kind == ItemKind::Ration)
+            };
+            if is_wanted {
+                wanted[num_wanted] = (floor_item.y as usize) * LEVEL_WIDTH + (floor_item.x as usize);
+                num_wanted += 1;
             }
+        }
+        // Wanted items and the edge of what has been explored
+        let is_goal = |index: usize| {
+            let x = (index % LEVEL_WIDTH) as u64;
+            let y = (index / LEVEL_WIDTH) as u64;
+            let is_frontier = dungeon.explored[index] && index != stairs_index
+                && dungeon.is_passable(x, y, unlocked)
+                && (!dungeon.explored[index - 1] || !dungeon.explored[index + 1]
+                    || !dungeon.explored[index - LEVEL_WIDTH] || !dungeon.explored[index + LEVEL_WIDTH]);
+            is_frontier || wanted[..num_wanted].contains(&index)
         };
+        // Known traps are only crossed when there's no other way
+        for avoid_traps in [true, false].iter() {
+            if let Some(key) = self.step_towards(dungeon, player, *avoid_traps, &is_goal) {
+                return key;
+            }
+            if has_key && dungeon.explored[stairs_index] {
+                if let Some(key) = self.step_towards(dungeon, player, *avoid_traps, &|index| index == stairs_index) {
+                    return key;
+                }
+            }
+        }
+        // Nothing left to find, so search for the traps that might be hiding the way on
+        KEY_SEARCH
+    }
 
-        let mut stairs_x: u64 = 0;
-        let mut stairs_y: u64 = 0;
-        let mut key_x: u64 = 0;
-        let mut key_y: u64 = 0;
+    // The first step towards the nearest goal, walking over tiles the player has seen and, if 
+    // avoid_traps is set, around the traps they know about. Monsters are walked into, which attacks them
+    fn step_towards(&mut self, dungeon: &Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>, player: &Player, avoid_traps: bool, is_goal: &dyn Fn(usize) -> bool) -> Option<u8> {
+        let unlocked = unlocked_colours(player);
+        let reached = unsafe { &mut *core::ptr::addr_of_mut!(BOT_REACHED) };
+        let first_step = unsafe { &mut *core::ptr::addr_of_mut!(BOT_FIRST_STEP) };
+        let queue = unsafe { &mut *core::ptr::addr_of_mut!(FLOOD_QUEUE) };
+        self.search += 1;
+        let search = self.search;
+        let mut head = 0;
+        let mut tail = 0;
+        for trap in dungeon.traps[..dungeon.num_traps].iter() {
+            if trap.revealed && avoid_traps {
+                reached[(trap.y as usize) * LEVEL_WIDTH + (trap.x as usize)] = search;
+            }
+        }
+        // The stairs turn the player back without the key
+        if player.inventory.find(ItemKind::StairsKey).is_none() {
+            reached[(dungeon.stairs_y as usize) * LEVEL_WIDTH + (dungeon.stairs_x as usize)] = search;
+        }
+        let start = player.y * LEVEL_WIDTH + player.x;
+        reached[start] = search;
+        queue[tail] = start as u32;
+        tail += 1;
+        while head < tail {
+            let index = queue[head] as usize;
+            head += 1;
+            let tile_x = (index % LEVEL_WIDTH) as i64;
+            let tile_y = (index / LEVEL_WIDTH) as i64;
+            for (dx, dy, key) in BOT_DIRECTIONS.iter() {
+                let next_x = tile_x + dx;
+                let next_y = tile_y + dy;
+                if next_x < 0 || next_y < 0 || next_x >= LEVEL_WIDTH as i64 || next_y >= LEVEL_HEIGHT as i64 {
+                    continue;
+                }
+                let next = (next_y as usize) * LEVEL_WIDTH + (next_x as usize);
+                if reached[next] == search {
+                    continue;
+                }
+                let step = if index == start { *key } else { first_step[index] };
+                if is_goal(next) {
+                    return Some(step);
+                }
+                if !dungeon.explored[next] || !dungeon.is_passable(next_x as u64, next_y as u64, unlocked) {
+                    continue;
+                }
+                reached[next] = search;
+                first_step[next] = step;
+                queue[tail] = next as u32;
+                tail += 1;
+            }
+        }
+        None
+    }
+}
 
+// Colour bits for the locks the player has keys for
+fn unlocked_colours(player: &Player) -> u8 {
+    let mut unlocked = 0;
+    for (colour, kind) in COLOURED_KEY_KINDS.iter().enumerate() {
+        if player.inventory.find(*kind).is_some() {
+            unlocked |= 1 << colour;
+        }
+    }
+    unlocked
+}
 
-        let mut prev_room_x: u64 = 0;
-        let mut prev_room_y: u64 = 0;
+fn push_percent(line: &mut MessageLine, count: u64, total: u64) {
+    push_tenths(line, count * 1000 / total);
+    line.push("%");
+}
 
-        for i in 0..num_rooms {
-            let room_half_width = self.rng.range_with_min(self.min_room_dim, self.max_room_dim) / 2;
-            let room_half_height = self.rng.range_with_min(self.min_room_dim, self.max_room_dim) / 2;
-            // window_width and window_height are used to create a buffer zone around the edges of
-            // edges of the map so that all rooms can be accessed with the window inbounds and the 
-            // player centered in the window
-            let room_x = self.rng.range_with_min(
-                self.window_width + room_half_width + 1, 
-                (MAP_WIDTH as u64) - room_half_width - self.window_width
-            );
-            let room_y = self.rng.range_with_min(
-                self.window_height + room_half_height + 1, 
-                (MAP_HEIGHT as u64) - room_half_height - self.window_height
-            );
-            
-            // Fill in room with walkable tiles
-            for y in (room_y - room_half_height)..(room_y + room_half_height) {
-                for x in (room_x - room_half_width)..(room_x + room_half_width) {
-                    self.map[(y as usize) * MAP_WIDTH + (x as usize)] = '.';
+// Plays a run of seeds with the bot and reports how it did
+fn run_bot(env: &Env) -> ! {
+    let mut config = BotConfig {
+        strategy: BotStrategy::Dive,
+        class: Class::Fighter,
+        goal_depth: DEFAULT_BOT_GOAL_DEPTH,
+        max_turns: DEFAULT_BOT_MAX_TURNS,
+    };
+    let mut first_seed = DEFAULT_SEED;
+    let mut count = DEFAULT_BOT_COUNT;
+    let mut min_win_percent = 0;
+    let mut i = 2;
+    while let Some(name) = env.arg(i) {
+        let value = env.arg(i + 1);
+        let number = value.and_then(parse_number);
+        let parsed = match (name, number) {
+            (b"--seed", Some(number)) => {
+                first_seed = number;
+                true
+            },
+            (b"--count", Some(number)) if number > 0 => {
+                count = number;
+                true
+            },
+            (b"--goal-depth", Some(number)) if number > 1 => {
+                config.goal_depth = number;
+                true
+            },
+            (b"--max-turns", Some(number)) if number > 0 => {
+                config.max_turns = number;
+                true
+            },
+            (b"--min-win-rate", Some(number)) if number <= 100 => {
+                min_win_percent = number;
+                true
+            },
+            (b"--strategy", _) => match BOT_STRATEGY_NAMES.iter().position(|name| value == Some(name.as_bytes())) {
+                Some(index) => {
+                    config.strategy = BOT_STRATEGIES[index];
+                    true
+                },
+                None => false,
+            },
+            (b"--class", _) => match CLASS_DEFS.iter().position(|def| value.is_some_and(|value| value.eq_ignore_ascii_case(def.name.as_bytes()))) {
+                Some(index) => {
+                    config.class = CLASSES[index];
+                    true
+                },
+                None => false,
+            },
+            _ => false,
+        };
+        if !parsed {
+            print_usage();
+            exit(2);
+        }
+        i += 2;
+    }
+
+    let results = play_bot_runs(env, config, first_seed, count);
+    let mut line = MessageLine::new();
+    line.push_number(count);
+    line.push(" runs of the ");
+    line.push(BOT_STRATEGY_NAMES[config.strategy as usize]);
+    line.push(" bot as a ");
+    line.push(CLASS_DEFS[config.class as usize].name);
+    line.push(", seeds ");
+    line.push_number(first_seed);
+    line.push(" to ");
+    line.push_number(first_seed.saturating_add(count - 1));
+    line.push("\n\nWon (reached depth ");
+    line.push_number(config.goal_depth);
+    line.push("): ");
+    line.push_number(results.wins);
+    line.push(" (");
+    push_percent(&mut line, results.wins, count);
+    line.push(")\nDied: ");
+    line.push_number(results.deaths);
+    line.push(" (");
+    push_percent(&mut line, results.deaths, count);
+    line.push(")\nGave up: ");
+    line.push_number(results.gave_up());
+    line.push(" (");
+    push_percent(&mut line, results.gave_up(), count);
+    line.push(")\nAverage depth: ");
+    push_tenths(&mut line, results.total_depth * 10 / count);
+    line.push("\nAverage turns: ");
+    line.push_number(results.total_turns / count);
+    line.push("\n");
+    print(line.as_str());
+    if results.num_causes > 0 {
+        print("\nDeaths\n");
+    }
+    for (cause, deaths) in results.causes[..results.num_causes].iter() {
+        line.clear();
+        line.push("  ");
+        format_death_cause(*cause, &mut line);
+        line.push(": ");
+        line.push_number(*deaths);
+        line.push("\n");
+        print(line.as_str());
+    }
+    exit(if results.meets_win_rate(min_win_percent) { 0 } else { 1 });
+}
+
+// How a run of bot games went
+struct BotResults {
+    count: u64,
+    wins: u64,
+    deaths: u64,
+    total_depth: u64,
+    total_turns: u64,
+    // Most common first
+    causes: [(DeathCause, u64); MAX_BOT_DEATH_CAUSES],
+    num_causes: usize,
+}
+
+impl BotResults {
+    fn gave_up(&self) -> u64 {
+        self.count - self.wins - self.deaths
+    }
+
+    // At least min_percent of the runs reached the goal depth
+    fn meets_win_rate(&self, min_percent: u64) -> bool {
+        self.wins * 100 >= min_percent * self.count
+    }
+}
+
+// Plays count seeds starting at first_seed with the bot, without drawing anything
+fn play_bot_runs(env: &Env, config: BotConfig, first_seed: u64, count: u64) -> BotResults {
+    let mut options = LaunchOptions {
+        seed: first_seed,
+        playtest_map: None,
+        maps: [PathBuf::new(); MAX_CUSTOM_MAPS],
+        num_maps: 0,
+        recording: false,
+        bot: Some(config),
+    };
+    let mut results = BotResults {
+        count,
+        wins: 0,
+        deaths: 0,
+        total_depth: 0,
+        total_turns: 0,
+        causes: [(DeathCause::Poison, 0); MAX_BOT_DEATH_CAUSES],
+        num_causes: 0,
+    };
+    unsafe {
+        INPUT.bot = true;
+        OUTPUT_MUTED = true;
+    }
+    for seed in first_seed..first_seed.saturating_add(count) {
+        options.seed = seed;
+        let end = game(env, &options);
+        results.total_depth += end.depth;
+        results.total_turns += end.turn;
+        if end.died {
+            results.deaths += 1;
+            if let Some(cause) = end.death_cause {
+                let num_causes = results.num_causes;
+                match results.causes[..num_causes].iter().position(|(known, _)| *known == cause) {
+                    Some(index) => results.causes[index].1 += 1,
+                    None if num_causes < MAX_BOT_DEATH_CAUSES => {
+                        results.causes[num_causes] = (cause, 1);
+                        results.num_causes += 1;
+                    },
+                    None => (),
                 }
             }
-            
-            // Connect current room to previous room
-            if prev_room_x != 0 && prev_room_y != 0 {
-                let hall_x;
-                if prev_room_x < room_x {
-                    hall_x = room_x;
-                    for x in prev_room_x..(room_x+1) {
-                        self.map[(prev_room_y as usize) * MAP_WIDTH + (x as usize)] = '.';
-                    }
-                } else {
-                    hall_x = prev_room_x;
-                    for x in room_x..(prev_room_x+1) {
-                        self.map[(room_y as usize) * MAP_WIDTH + (x as usize)] = '.';
-                    }
+        } else if end.depth >= config.goal_depth {
+            results.wins += 1;
+        }
+    }
+    unsafe {
+        INPUT.bot = false;
+        OUTPUT_MUTED = false;
+    }
+    results.causes[..results.num_causes].sort_unstable_by_key(|(_, deaths)| core::cmp::Reverse(*deaths));
+    results
+}
+
+// Runs the generator without a terminal and writes the floor to stdout
+fn run_dump_map(env: &Env) -> ! {
+    let format = match env.arg(2) {
+        Some(b"ascii") => MapFormat::Ascii,
+        Some(b"json") => MapFormat::Json,
+        Some(b"ppm") => MapFormat::Ppm,
+        _ => {
+            print_usage();
+            exit(2);
+        },
+    };
+    let mut params = GenParams::new();
+    let mut i = 3;
+    while let Some(name) = env.arg(i) {
+        let parsed = match env.arg(i + 1) {
+            Some(value) => params.parse_option(name, value),
+            None => false,
+        };
+        if !parsed {
+            print_usage();
+            exit(2);
+        }
+        i += 2;
+    }
+    if let Err(message) = params.validate() {
+        print(message);
+        print("\n");
+        exit(2);
+    }
+    let dungeon = params.generate();
+    dump_map(&dungeon, &params, format);
+    exit(0);
+}
+
+// Painted as is, load_map places whatever item or monster a glyph stands for
+const TILE_BRUSHES: [(char, &str); 11] = [
+    (WALL_CHAR, "wall"), (FLOOR_CHAR, "floor"), (DOOR_CLOSED_CHAR, "door"), (DOOR_OPEN_CHAR, "open door"),
+    (LOCKED_DOOR_CHARS[0], "red door"), (LOCKED_DOOR_CHARS[1], "green door"), (LOCKED_DOOR_CHARS[2], "blue door"),
+    (TRAP_CHAR, "trap"), (PLAYER_CHAR, "spawn"), (KEY_CHAR, "key"), (STAIRS_CHAR, "stairs"),
+];
+const ITEM_BRUSHES: [(char, &str); 8] = [
+    (GOLD_CHAR, "gold"), (WEAPON_CHAR, "weapon"), (ARMOUR_CHAR, "armour"), (RING_CHAR, "ring"),
+    (AMULET_CHAR, "amulet"), (POTION_CHAR, "potion"), (SCROLL_CHAR, "scroll"), (FOOD_CHAR, "food"),
+];
+// The tiles and item glyphs, then every monster
+const MAX_BRUSHES: usize = TILE_BRUSHES.len() + ITEM_BRUSHES.len() + MAX_MONSTER_KINDS;
+
+#[derive(Clone, Copy)]
+struct Brush {
+    glyph: char,
+    name: &'static str,
+}
+
+// Tiles first, then one brush per item glyph, then every monster
+fn editor_brushes(brushes: &mut [Brush; MAX_BRUSHES]) -> usize {
+    let mut len = 0;
+    for (glyph, name) in TILE_BRUSHES.iter().chain(ITEM_BRUSHES.iter()) {
+        brushes[len] = Brush { glyph: *glyph, name };
+        len += 1;
+    }
+    for def in monster_defs().iter() {
+        brushes[len] = Brush { glyph: def.glyph, name: def.name };
+        len += 1;
+    }
+    len
+}
+
+// The editor paints straight onto a level sized canvas, inside the margin load_map centres drawings in
+struct Canvas {
+    tiles: [char; LEVEL_WIDTH * LEVEL_HEIGHT],
+}
+
+const CANVAS_LEFT: usize = WINDOW_WIDTH + 1;
+const CANVAS_TOP: usize = WINDOW_HEIGHT + 1;
+const CANVAS_WIDTH: usize = LEVEL_WIDTH - 2 * CANVAS_LEFT;
+const CANVAS_HEIGHT: usize = LEVEL_HEIGHT - 2 * CANVAS_TOP;
+
+impl Canvas {
+    fn new() -> Self {
+        Canvas { tiles: [WALL_CHAR; LEVEL_WIDTH * LEVEL_HEIGHT] }
+    }
+
+    fn get(&self, x: usize, y: usize) -> char {
+        self.tiles[y * LEVEL_WIDTH + x]
+    }
+
+    fn set(&mut self, x: usize, y: usize, glyph: char) {
+        // The spawn, the key and the stairs move instead of being copied
+        if glyph == PLAYER_CHAR || glyph == KEY_CHAR || glyph == STAIRS_CHAR {
+            for tile in self.tiles.iter_mut() {
+                if *tile == glyph {
+                    *tile = FLOOR_CHAR;
                 }
-                if prev_room_y < room_y {
-                    for y in prev_room_y..(room_y+1) {
-                        self.map[(y as usize) * MAP_WIDTH + (hall_x as usize)] = '.';
-                    }
-                } else {
-                    for y in room_y..(prev_room_y+1) {
-                        self.map[(y as usize) * MAP_WIDTH + (hall_x as usize)] = '.';
-                    }
+            }
+        }
+        self.tiles[y * LEVEL_WIDTH + x] = glyph;
+    }
+
+    // Reads a map file, trimming the walls around the drawing the same way load_map does, and
+    // places it at the top left of the drawing area. A map that doesn't fit leaves the canvas empty.
+    fn load(&mut self, text: &[u8]) -> core::result::Result<(), MapError> {
+        self.tiles = [WALL_CHAR; LEVEL_WIDTH * LEVEL_HEIGHT];
+        let mut top = usize::MAX;
+        let mut bottom = 0;
+        let mut left = usize::MAX;
+        let mut right = 0;
+        for (line_index, line) in text.split(|c| *c == b'\n').enumerate() {
+            for (column, c) in line.iter().enumerate() {
+                if *c != WALL_CHAR as u8 && *c != UNEXPLORED_CHAR as u8 && *c != b'\r' {
+                    top = core::cmp::min(top, line_index);
+                    bottom = line_index;
+                    left = core::cmp::min(left, column);
+                    right = core::cmp::max(right, column);
                 }
             }
-            
-            // Determine coordinates of special tiles
-            match i {
-                0 => {
-                    // Set spawn to the center of the first room
-                    self.spawn_x = room_x;
-                    self.spawn_y = room_y;
-                },
-                1 => {
-                    // Put stairs in the second room
-                    loop {
-                        stairs_y = self.rng.range_with_min(room_y - room_half_height, room_y + room_half_height);
-                        stairs_x = self.rng.range_with_min(room_x - room_half_width, room_x + room_half_width);
-                        // Make sure the stairs don't overlap the spawn
-                        if stairs_y != self.spawn_y || stairs_x != self.spawn_x {
-                            break;
-                        }
-                    }
-                },
-                2 => {
-                    // Put key in the third room
-                    loop {
-                        key_y = self.rng.range_with_min(room_y - room_half_height, room_y + room_half_height);
-                        key_x = self.rng.range_with_min(room_x - room_half_width, room_x + room_half_width);
-                        // Make sure the key doesn't overlap the spawn or the stairs
-                        if (key_y != stairs_y || key_x != stairs_x) && (key_y != self.spawn_y || key_x != self.spawn_x) {
-                            break;
-                        }
-                    }
-                },
-                _ => ()
+        }
+        if top == usize::MAX {
+            return Ok(());
+        }
+        if right - left + 1 > CANVAS_WIDTH || bottom - top + 1 > CANVAS_HEIGHT {
+            return Err(MapError::TooBig { max_width: CANVAS_WIDTH, max_height: CANVAS_HEIGHT });
+        }
+        for (line_index, line) in text.split(|c| *c == b'\n').enumerate().skip(top).take(bottom - top + 1) {
+            for (column, c) in line.iter().enumerate() {
+                if *c == b'\r' || column < left || column > right {
+                    continue;
+                }
+                let glyph = if *c == UNEXPLORED_CHAR as u8 { WALL_CHAR } else { *c as char };
+                self.tiles[(CANVAS_TOP + line_index - top) * LEVEL_WIDTH + CANVAS_LEFT + column - left] = glyph;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the drawing trimmed to its bounds plus a one tile wall border, returns the length
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let mut top = usize::MAX;
+        let mut bottom = 0;
+        let mut left = usize::MAX;
+        let mut right = 0;
+        for y in CANVAS_TOP..CANVAS_TOP + CANVAS_HEIGHT {
+            for x in CANVAS_LEFT..CANVAS_LEFT + CANVAS_WIDTH {
+                if self.get(x, y) != WALL_CHAR {
+                    top = core::cmp::min(top, y);
+                    bottom = y;
+                    left = core::cmp::min(left, x);
+                    right = core::cmp::max(right, x);
+                }
             }
+        }
+        if top == usize::MAX {
+            return 0;
+        }
+        let mut len = 0;
+        for y in top - 1..=bottom + 1 {
+            for x in left - 1..=right + 1 {
+                buf[len] = self.get(x, y) as u8;
+                len += 1;
+            }
+            buf[len] = b'\n';
+            len += 1;
+        }
+        len
+    }
+}
 
-            prev_room_x = room_x;
-            prev_room_y = room_y;
+// Where the cursor is and what the side panel shows
+struct EditorState<'a> {
+    cursor_x: usize,
+    cursor_y: usize,
+    brushes: &'a [Brush],
+    brush: usize,
+    pen_down: bool,
+    dirty: bool,
+}
+
+fn draw_editor(canvas: &Canvas, path: &PathBuf, state: &EditorState, status: &MessageLine) {
+    let (cursor_x, cursor_y) = (state.cursor_x, state.cursor_y);
+    let brush = &state.brushes[state.brush];
+    let mut lines = [MessageLine::new(); HUD_MAX_LINES];
+    lines[0].push(path.as_str());
+    if state.dirty {
+        lines[0].push(" (modified)");
+    }
+    lines[1].push("Cursor ");
+    lines[1].push_number((cursor_x - CANVAS_LEFT) as u64);
+    lines[1].push(",");
+    lines[1].push_number((cursor_y - CANVAS_TOP) as u64);
+    lines[2].push("Brush ");
+    lines[2].push_char(brush.glyph);
+    lines[2].push(" ");
+    lines[2].push(brush.name);
+    lines[3].push(if state.pen_down { "Pen down, moving paints" } else { "Pen up" });
+    let help = [
+        "", "Arrows move", "Space paints, X erases", "N/P next/previous brush", "L lifts or drops the pen",
+        "V validates, T playtests", "S saves, Q quits",
+    ];
+    for (i, text) in help.iter().enumerate() {
+        lines[4 + i].push(text);
+    }
+
+    clear_screen();
+    let mut row = MessageLine::new();
+    for h in 0..WINDOW_HEIGHT {
+        row.clear();
+        for w in 0..WINDOW_WIDTH {
+            let x = cursor_x + w - WINDOW_WIDTH / 2;
+            let y = cursor_y + h - WINDOW_HEIGHT / 2;
+            let glyph = canvas.get(x, y);
+            if (x, y) == (cursor_x, cursor_y) {
+                // Reverse video so the tile under the cursor stays readable
+                row.push("\x1b[7m");
+                row.push_char(glyph);
+                row.push("\x1b[27m");
+            } else {
+                row.push_char(glyph);
+            }
         }
-        
-        // Set special tiles
-        self.map[(stairs_y as usize) * MAP_WIDTH + (stairs_x as usize)] = STAIRS_CHAR;
-        self.map[(key_y as usize) * MAP_WIDTH + (key_x as usize)] = KEY_CHAR;
+        if let Some(line) = lines.get(h) {
+            for _ in 0..HUD_PANEL_GAP {
+                row.push_char(' ');
+            }
+            row.push(line.as_str());
+        }
+        row.push("\n");
+        print(row.as_str());
     }
+    print(status.as_str());
+}
 
-    fn check_collision(&self, x: u64, y: u64) -> char {
-        self.map[(y as usize) * MAP_WIDTH + (x as usize)]
+// Validates the drawing by loading it the same way --map would
+fn validate_canvas(canvas: &Canvas, text: &mut [u8; MAP_FILE_CAPACITY], status: &mut MessageLine) -> Option<usize> {
+    let len = canvas.write(text);
+    let mut dungeon = GenParams::new().new_dungeon();
+    match dungeon.load_map(&text[..len]) {
+        Ok(()) => Some(len),
+        Err(err) => {
+            status.push("Not playable yet, ");
+            format_map_error(&err, status);
+            status.push(".");
+            None
+        },
     }
 }
 
-fn loop_until_continue() {
+// Edits the map file at path, it doesn't have to exist yet
+fn edit_map(env: &Env, path: &PathBuf) {
+    let mut canvas = Canvas::new();
+    let text = unsafe { &mut *core::ptr::addr_of_mut!(MAP_TEXT) };
+    let mut status = MessageLine::new();
+    match read_file(path, text) {
+        Result::Ok(len) => {
+            if let Err(err) = canvas.load(&text[..len]) {
+                status.push("The map couldn't be loaded, ");
+                format_map_error(&err, &mut status);
+                status.push(".");
+            }
+        },
+        Result::Err(ENOENT) => status.push("New map."),
+        Result::Err(errno) => {
+            status.push("The map couldn't be read (errno ");
+            status.push_number(errno as u64);
+            status.push(").");
+        },
+    }
+
+    let mut brushes = [Brush { glyph: ' ', name: "" }; MAX_BRUSHES];
+    let num_brushes = editor_brushes(&mut brushes);
+    let mut brush = 1;
+    let mut pen_down = false;
+    let mut dirty = false;
+    let mut cursor_x = CANVAS_LEFT + CANVAS_WIDTH / 2;
+    let mut cursor_y = CANVAS_TOP + CANVAS_HEIGHT / 2;
     loop {
-        let cur_key = get_input();
-        if cur_key == KEY_CONTINUE {
-            break;
+        let state = EditorState {
+            cursor_x, cursor_y, brushes: &brushes[..num_brushes], brush, pen_down, dirty,
+        };
+        draw_editor(&canvas, path, &state, &status);
+        status.clear();
+        let key = wait_for_key();
+        match key {
+            KEY_UP if cursor_y > CANVAS_TOP => cursor_y -= 1,
+            KEY_DOWN if cursor_y < CANVAS_TOP + CANVAS_HEIGHT - 1 => cursor_y += 1,
+            KEY_LEFT if cursor_x > CANVAS_LEFT => cursor_x -= 1,
+            KEY_RIGHT if cursor_x < CANVAS_LEFT + CANVAS_WIDTH - 1 => cursor_x += 1,
+            KEY_NEXT_BRUSH => brush = (brush + 1) % num_brushes,
+            KEY_PREV_BRUSH => brush = (brush + num_brushes - 1) % num_brushes,
+            KEY_PEN => pen_down = !pen_down,
+            KEY_VALIDATE => {
+                if validate_canvas(&canvas, text, &mut status).is_some() {
+                    status.push("The map is playable.");
+                }
+            },
+            KEY_PLAYTEST => {
+                if let Some(len) = validate_canvas(&canvas, text, &mut status) {
+                    let options = LaunchOptions {
+                        seed: DEFAULT_SEED,
+                        playtest_map: Some(&text[..len]),
+                        maps: [PathBuf::new(); MAX_CUSTOM_MAPS],
+                        num_maps: 0,
+                        recording: false,
+                        bot: None,
+                    };
+                    game(env, &options);
+                    status.push("Back from the playtest.");
+                }
+            },
+            KEY_SAVE => {
+                let len = canvas.write(text);
+                match write_file_atomic(path, &text[..len]) {
+                    Result::Ok(()) => {
+                        dirty = false;
+                        status.push("Saved.");
+                    },
+                    Result::Err(errno) => {
+                        status.push("The map couldn't be saved (errno ");
+                        status.push_number(errno as u64);
+                        status.push(").");
+                    },
+                }
+            },
+            KEY_QUIT => {
+                if !dirty {
+                    return;
+                }
+                clear_screen();
+                if prompt_yes_no("Quit without saving your changes?") {
+                    return;
+                }
+            },
+            _ => (),
+        }
+        let painting = key == KEY_PAINT || key == KEY_ERASE || (pen_down && (key == KEY_UP || key == KEY_DOWN || key == KEY_LEFT || key == KEY_RIGHT));
+        if painting {
+            let glyph = if key == KEY_ERASE { WALL_CHAR } else { brushes[brush].glyph };
+            if canvas.get(cursor_x, cursor_y) != glyph {
+                canvas.set(cursor_x, cursor_y, glyph);
+                dirty = true;
+            }
         }
     }
 }
 
-fn game() {
+fn game(env: &Env, options: &LaunchOptions) -> RunEnd {
     let clear_screen_enabled = true;
     if clear_screen_enabled {
         clear_screen();
     }
     let mut rng = XorshiftRng::new(10142341231);
 
-    const window_width: usize = 40;
-    const half_window_width: usize = window_width / 2 + 1;
-    const window_height: usize = 20;
-    const half_window_height: usize = window_height / 2 + 1;
-    const window_size: usize = window_width * window_height;
-
-    const level_width: usize = 100 + (2 * window_width);
-    const level_height: usize = 100 + (2 * window_height);
-    const level_size: usize = level_width * level_height;
-    const min_rooms: u64 = 10;
-    const max_rooms: u64 = 50;
-    const min_room_dim: u64 = 5;
-    const max_room_dim: u64 = 20;
-    let mut dungeon = Dungeon::<level_width, level_height>::new(
-        1232123123234, 
-        min_rooms, max_rooms, 
-        min_room_dim, max_room_dim, 
-        window_width as u64, window_height as u64
+    let mut seed: u64 = options.seed;
+    let mut dungeon = Dungeon::<LEVEL_WIDTH, LEVEL_HEIGHT>::new(
+        seed, 
+        DEFAULT_MIN_ROOMS, DEFAULT_MAX_ROOMS, 
+        DEFAULT_MIN_ROOM_DIM, DEFAULT_MAX_ROOM_DIM, 
+        WINDOW_WIDTH as u64, WINDOW_HEIGHT as u64
     );
 
-    let mut window: [char; window_size] = [FLOOR_CHAR; window_size];
-
-    let mut player_x: usize = 0;
-    let mut player_y: usize = 0;
+    let mut window: [char; WINDOW_WIDTH * WINDOW_HEIGHT] = [FLOOR_CHAR; WINDOW_WIDTH * WINDOW_HEIGHT];
+    let mut window_colours: [Colour; WINDOW_WIDTH * WINDOW_HEIGHT] = [Colour::Default; WINDOW_WIDTH * WINDOW_HEIGHT];
 
-    let mut player_x_dir: isize = 1;
     let (mut last_sec, mut last_ns) = get_time();
     let mut last_key: u8 = 0;
-    let mut has_stairs_key: bool = false;
-    let mut should_generate_dungeon = true;
+    let mut player = Player::new(seed, Class::Fighter, Race::Human, &[]);
+    let mut log = MessageLog::new();
+    let mut turn: u64 = 0;
+    let mut difficulty = Difficulty::Normal;
+    let mut time_mode = TimeMode::TurnBased;
+    let mut permadeath = false;
+
+    // Replays leave the player's files alone
+    let playtest = options.playtest_map.is_some();
+    let mut bot = options.bot.map(Bot::new);
+    let headless = is_replaying() || playtest || bot.is_some();
+    let save_path = if headless { None } else { data_file(env, SAVE_FILE_NAME) };
+    let high_score_path = if headless { None } else { data_file(env, HIGH_SCORE_FILE_NAME) };
+
+    // Offer to pick up where the last run left off, unless this run is replayed from the start
+    let mut resumed = false;
+    if let (Some(path), false) = (&save_path, options.recording || is_replaying()) {
+        match load_game(path, &mut dungeon, &mut player, &mut rng, &mut log) {
+            Ok((flags, run)) => {
+                let mut question = MessageLine::new();
+                question.push("Continue your game as ");
+                format_title(&player, &mut question);
+                question.push(" on depth ");
+                question.push_number(dungeon.depth);
+                question.push("?");
+                let saved_permadeath = flags & SAVE_FLAG_PERMADEATH != 0;
+                if saved_permadeath {
+                    question.push(" This is a permadeath game, the save is deleted once you continue.");
+                }
+                clear_screen();
+                if prompt_yes_no(question.as_str()) {
+                    resumed = true;
+                    seed = run.seed;
+                    turn = run.turn;
+                    difficulty = run.difficulty;
+                    time_mode = run.time_mode;
+                    permadeath = saved_permadeath;
+                }
+                // Without the delete a permadeath run could be reloaded after dying
+                if resumed && permadeath {
+                    if let Err(err) = delete_save(path) {
+                        clear_screen();
+                        print("Your permadeath save couldn't be deleted, so it can't be continued. ");
+                        print_save_error(&err);
+                        print("\n\nPress space to start a new game");
+                        loop_until_continue();
+                        resumed = false;
+                        permadeath = false;
+                    }
+                }
+            },
+            Err(SaveError::NotFound) => (),
+            Err(err) => {
+                clear_screen();
+                print("Your saved game couldn't be loaded. ");
+                print_save_error(&err);
+                print("\n\nPress space to start a new game");
+                loop_until_continue();
+            },
+        }
+    }
+    if playtest {
+        player = Player::new(seed, Class::Fighter, Race::Human, b"Tester");
+    } else if let Some(config) = &options.bot {
+        player = Player::new(seed, config.class, Race::Human, b"Bot");
+        dungeon.food_per_floor = DIFFICULTY_DEFS[difficulty as usize].food_per_floor;
+    } else if !resumed {
+        // Loading may have got part way before failing or being turned down
+        dungeon = Dungeon::<LEVEL_WIDTH, LEVEL_HEIGHT>::new(
+            seed, 
+            DEFAULT_MIN_ROOMS, DEFAULT_MAX_ROOMS, 
+            DEFAULT_MIN_ROOM_DIM, DEFAULT_MAX_ROOM_DIM, 
+            WINDOW_WIDTH as u64, WINDOW_HEIGHT as u64
+        );
+        rng = XorshiftRng::new(10142341231);
+        log = MessageLog::new();
+        while choose_option("Rusty Dungeon", &MAIN_MENU_NAMES, &MAIN_MENU_DESCRIPTIONS) == 1 {
+            let mut table = HighScoreTable::new();
+            if let Some(path) = &high_score_path {
+                if let Err(err) = read_high_scores(path, &mut table) {
+                    clear_screen();
+                    print_high_score_error(&err);
+                    print("\n\nPress space to continue");
+                    loop_until_continue();
+                    continue;
+                }
+            }
+            show_high_scores(&table, None);
+        }
+        player = create_player(seed);
+        difficulty = choose_difficulty();
+        dungeon.food_per_floor = DIFFICULTY_DEFS[difficulty as usize].food_per_floor;
+        time_mode = TIME_MODES[choose_option("Choose a game mode:", &TIME_MODE_NAMES, &TIME_MODE_DESCRIPTIONS)];
+        permadeath = choose_option("Choose what happens when you die:", &DEATH_MODE_NAMES, &DEATH_MODE_DESCRIPTIONS) == 1;
+    }
+
+    let mut idle_ticks: u32 = 0;
+    // Turn based mode only redraws after input or when something else changed the screen
+    let mut needs_redraw = true;
+    // Text for the current turn, it goes into the log once the turn is over
+    let mut messages = TurnMessages::new();
+    let mut should_generate_dungeon = !resumed;
+    // Levels gained that the player hasn't picked a perk for yet
+    let mut level_ups: u32 = 0;
 
     // Game loop
     loop {
         let (cur_sec, cur_ns) = get_time();
         
         if should_generate_dungeon {
-            dungeon.generate();
-            player_x = dungeon.spawn_x as usize;
-            player_y = dungeon.spawn_y as usize;
-            has_stairs_key = false;
+            let map_index = dungeon.depth as usize - 1;
+            let playtest_map = if dungeon.depth == 1 { options.playtest_map } else { None };
+            if let Some(text) = playtest_map {
+                // The editor validated it already
+                if dungeon.load_map(text).is_err() {
+                    dungeon.generate();
+                }
+            } else if map_index < options.num_maps {
+                // The maps were checked at startup, so this only fails if one changed since
+                if let Err(err) = load_map_file(&options.maps[map_index], &mut dungeon) {
+                    clear_screen();
+                    print("The map for this floor couldn't be loaded, ");
+                    print_map_error(&err);
+                    print(". A random floor is generated instead.\n\nPress space to continue");
+                    loop_until_continue();
+                    dungeon.generate();
+                }
+            } else {
+                dungeon.generate();
+            }
+            if dungeon.depth > 1 {
+                level_ups += player.gain_xp(dungeon.depth as u32 * DESCEND_XP_PER_DEPTH);
+            }
+            // Keys only fit the locks of the floor they were found on
+            player.inventory.remove_kind(ItemKind::StairsKey);
+            for kind in COLOURED_KEY_KINDS.iter() {
+                player.inventory.remove_kind(*kind);
+            }
+            player.x = dungeon.spawn_x as usize;
+            player.y = dungeon.spawn_y as usize;
             should_generate_dungeon = false;
+            needs_redraw = true;
+            run_named_script(ON_ENTER_LEVEL_SCRIPT, &mut player, &mut dungeon, &mut rng, &mut messages);
+            log.flush(&mut messages, turn);
         }
 
-        let cur_key = get_input();
+        // Keys wait until the last one was acted on, so a replay sees them in the same turns
+        set_input_turn(turn);
+        let cur_key = match &mut bot {
+            Some(bot) if last_key == 0 => bot.next_key(&dungeon, &player, turn),
+            _ if last_key == 0 => get_input(),
+            _ => 0,
+        };
         if cur_key == KEY_QUIT {
             break;
         } else if ALL_KEYS.contains(&cur_key) {
@@ -592,93 +8520,560 @@ fn game() {
         }
 
         let time_diff_ms = get_time_diff_ms(last_sec, last_ns, cur_sec, cur_ns);
-        if time_diff_ms >= 30 {
+        let should_update = match time_mode {
+            TimeMode::RealTime if !is_replaying() => time_diff_ms >= REALTIME_TICK_MS,
+            _ => last_key != 0 || needs_redraw,
+        };
+        if should_update {
             last_sec = cur_sec;
             last_ns = cur_ns;
-           
+            needs_redraw = false;
+            // Replays get the idle waits from the recording
+            if time_mode == TimeMode::RealTime && !is_replaying() {
+                if last_key == 0 {
+                    idle_ticks += 1;
+                    if idle_ticks >= REALTIME_IDLE_TICKS {
+                        idle_ticks = 0;
+                        last_key = KEY_WAIT;
+                        record_key(KEY_WAIT);
+                    }
+                } else {
+                    idle_ticks = 0;
+                }
+            }
+
+            let player_x_before = player.x;
+            let player_y_before = player.y;
             let target_player_x;
             let target_player_y;
+            let is_move_key = last_key == KEY_UP || last_key == KEY_DOWN || last_key == KEY_LEFT || last_key == KEY_RIGHT;
+            if is_move_key && player.effects.has(StatusKind::Confusion) && rng.range(2) == 0 {
+                last_key = match confused_direction(&mut rng) {
+                    (1, 0) => KEY_RIGHT,
+                    (-1, 0) => KEY_LEFT,
+                    (0, 1) => KEY_DOWN,
+                    _ => KEY_UP,
+                };
+            }
             match last_key {
                 KEY_UP => {
-                    target_player_x = player_x;
-                    target_player_y = player_y - 1;
+                    target_player_x = player.x;
+                    target_player_y = player.y - 1;
                 },
                 KEY_DOWN => {
-                    target_player_x = player_x;
-                    target_player_y = player_y + 1;
+                    target_player_x = player.x;
+                    target_player_y = player.y + 1;
                 },
                 KEY_LEFT => {
-                    target_player_x = player_x - 1;
-                    target_player_y = player_y;
+                    target_player_x = player.x - 1;
+                    target_player_y = player.y;
                 }
                 KEY_RIGHT => {
-                    target_player_x = player_x + 1;
-                    target_player_y = player_y;
+                    target_player_x = player.x + 1;
+                    target_player_y = player.y;
                 },
                 _ => {
-                    target_player_x = player_x;
-                    target_player_y = player_y;
+                    target_player_x = player.x;
+                    target_player_y = player.y;
                 }
             }
 
             // Check if player can move to target position
-            let mut should_wait_for_continue = false;
+            let mut turn_taken = false;
+            messages.clear();
+            let target_monster = dungeon.monster_index_at(target_player_x as u64, target_player_y as u64);
             let target_tile = dungeon.check_collision(target_player_x as u64, target_player_y as u64);
-            match target_tile {
-                FLOOR_CHAR => {
-                    player_x = target_player_x;
-                    player_y = target_player_y;
-                },
-                STAIRS_CHAR => {
-                    if has_stairs_key {
-                        player_x = target_player_x;
-                        player_y = target_player_y;
-                        should_generate_dungeon = true;
+            if let Some(index) = target_monster {
+                // Bump attack
+                let monster = dungeon.monsters[index];
+                let damage = roll_damage(&mut rng, player.attack(), monster.def().defense);
+                dungeon.monsters[index].hp -= damage;
+                dungeon.monsters[index].awake = true;
+                messages.push("You hit the ");
+                messages.push(monster.def().name);
+                messages.push(" for ");
+                messages.push_number(damage as u64);
+                messages.push(". ");
+                if dungeon.monsters[index].hp <= 0 {
+                    messages.push("The ");
+                    messages.push(monster.def().name);
+                    messages.push(" dies. ");
+                    level_ups += player.gain_xp(dungeon.kill_monster(index));
+                }
+                turn_taken = true;
+            } else if (target_player_x, target_player_y) != (player.x, player.y) {
+                match target_tile {
+                    FLOOR_CHAR | DOOR_OPEN_CHAR => {
+                        player.x = target_player_x;
+                        player.y = target_player_y;
+                    },
+                    DOOR_CLOSED_CHAR => {
+                        dungeon.set_tile(target_player_x as u64, target_player_y as u64, DOOR_OPEN_CHAR);
+                        messages.push("You open the door. ");
+                        turn_taken = true;
+                    },
+                    TRAP_CHAR => {
+                        player.x = target_player_x;
+                        player.y = target_player_y;
+                        if let Some(index) = dungeon.trap_index_at(player.x as u64, player.y as u64) {
+                            if dungeon.trigger_trap(index, &mut player, &mut rng, &mut messages) {
+                                dungeon.depth += 1;
+                                should_generate_dungeon = true;
+                            }
+                        }
+                    },
+                    STAIRS_CHAR => {
+                        if let Some(slot) = player.inventory.find(ItemKind::StairsKey) {
+                            player.x = target_player_x;
+                            player.y = target_player_y;
+                            player.inventory.consume(slot);
+                            dungeon.depth += 1;
+                            should_generate_dungeon = true;
+                        } else {
+                            messages.push("You must find the key! ");
+                        }
+                    },
+                    _ => {
+                        if let Some(colour) = lock_colour(target_tile) {
+                            match player.inventory.find(COLOURED_KEY_KINDS[colour]) {
+                                Some(slot) => {
+                                    player.inventory.consume(slot);
+                                    dungeon.set_tile(target_player_x as u64, target_player_y as u64, DOOR_OPEN_CHAR);
+                                    messages.push("You unlock the ");
+                                    messages.push(LOCK_COLOUR_NAMES[colour]);
+                                    messages.push(" door. ");
+                                    turn_taken = true;
+                                },
+                                None => {
+                                    messages.push("The door is locked. You need the ");
+                                    messages.push(LOCK_COLOUR_NAMES[colour]);
+                                    messages.push(" key. ");
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Quest items are picked up as soon as the player steps on them
+            let moved = player.x != player_x_before || player.y != player_y_before;
+            if moved {
+                turn_taken = true;
+                if !should_generate_dungeon {
+                    let percent = player.perception_percent();
+                    dungeon.reveal_traps_near(player.x as u64, player.y as u64, 1, &mut rng, percent, &mut messages);
+                }
+            }
+            if moved && !should_generate_dungeon {
+                if let Some(index) = dungeon.item_index_at(player.x as u64, player.y as u64) {
+                    let floor_item = dungeon.items[index];
+                    if floor_item.item.has_flag(ITEM_FLAG_QUEST) {
+                        if player.inventory.add(floor_item.item) {
+                            dungeon.take_floor_item(index);
+                            if floor_item.item.kind == ItemKind::StairsKey {
+                                messages.push("You found the key! ");
+                            } else {
+                                messages.push("You pick up the ");
+                                format_item(&floor_item.item, &player.identification, &mut messages);
+                                messages.push(". ");
+                            }
+                            if let Some(script) = floor_item.item.def().on_pickup {
+                                run_script(script, &mut player, &mut dungeon, &mut rng, &mut messages);
+                            }
+                        } else {
+                            messages.push("Your pack is too full to carry the ");
+                            format_item(&floor_item.item, &player.identification, &mut messages);
+                            messages.push(". ");
+                        }
+                    }
+                }
+            }
+
+            if moved && !should_generate_dungeon && player.hp > 0 {
+                run_named_script(ON_STEP_SCRIPT, &mut player, &mut dungeon, &mut rng, &mut messages);
+            }
+
+            match last_key {
+                KEY_PICKUP => {
+                    match dungeon.item_index_at(player.x as u64, player.y as u64) {
+                        Some(index) => {
+                            let item = dungeon.items[index].item;
+                            if player.inventory.add(item) {
+                                dungeon.take_floor_item(index);
+                                turn_taken = true;
+                                messages.push("You pick up the ");
+                                format_item(&item, &player.identification, &mut messages);
+                                messages.push(". ");
+                                if let Some(script) = item.def().on_pickup {
+                                    run_script(script, &mut player, &mut dungeon, &mut rng, &mut messages);
+                                }
+                            } else {
+                                messages.push("Your pack is full. ");
+                            }
+                        },
+                        None => messages.push("There is nothing here to pick up. "),
+                    }
+                },
+                KEY_DROP => {
+                    if let Some(slot) = prompt_inventory_slot(&player, "Drop which item?") {
+                        if dungeon.item_index_at(player.x as u64, player.y as u64).is_some() {
+                            messages.push("There is already something lying here. ");
+                        } else if let Some(item) = player.inventory.take(slot) {
+                            dungeon.add_floor_item(player.x as u64, player.y as u64, item);
+                            turn_taken = true;
+                            messages.push("You drop the ");
+                            format_item(&item, &player.identification, &mut messages);
+                            messages.push(". ");
+                        }
+                    }
+                },
+                KEY_USE => {
+                    if let Some(slot) = prompt_inventory_slot(&player, "Use which item?") {
+                        use_item(&mut player, slot, &mut dungeon, &mut rng, &mut messages);
+                        turn_taken = true;
+                    }
+                },
+                KEY_EQUIP => {
+                    if let Some(slot) = prompt_inventory_slot(&player, "Equip which item?") {
+                        equip_item(&mut player, slot, &mut messages);
+                        turn_taken = true;
+                    }
+                },
+                KEY_REMOVE => {
+                    if let Some(slot) = prompt_equipment_slot(&player, dungeon.depth) {
+                        if player.unequip(slot) {
+                            messages.push("You take it off. ");
+                            turn_taken = true;
+                        } else {
+                            messages.push("Your pack is full. ");
+                        }
+                    }
+                },
+                KEY_OPEN | KEY_CLOSE => {
+                    let opening = last_key == KEY_OPEN;
+                    if let Some((dx, dy)) = prompt_direction(if opening { "Open in which direction?" } else { "Close in which direction?" }) {
+                        let door_x = (player.x as i64 + dx) as u64;
+                        let door_y = (player.y as i64 + dy) as u64;
+                        let tile = dungeon.check_collision(door_x, door_y);
+                        if opening && tile == DOOR_CLOSED_CHAR {
+                            dungeon.set_tile(door_x, door_y, DOOR_OPEN_CHAR);
+                            messages.push("You open the door. ");
+                            turn_taken = true;
+                        } else if opening && lock_colour(tile).is_some() {
+                            messages.push("That door is locked. ");
+                        } else if !opening && tile == DOOR_OPEN_CHAR {
+                            let is_blocked = dungeon.monster_index_at(door_x, door_y).is_some() 
+                                || dungeon.item_index_at(door_x, door_y).is_some();
+                            if is_blocked {
+                                messages.push("Something is in the way. ");
+                            } else {
+                                dungeon.set_tile(door_x, door_y, DOOR_CLOSED_CHAR);
+                                messages.push("You close the door. ");
+                                turn_taken = true;
+                            }
+                        } else {
+                            messages.push("There is no door there to ");
+                            messages.push(if opening { "open. " } else { "close. " });
+                        }
+                    }
+                },
+                KEY_SEARCH => {
+                    let found = dungeon.reveal_traps_near(
+                        player.x as u64, player.y as u64, SEARCH_RADIUS, 
+                        &mut rng, SEARCH_PERCENT, &mut messages
+                    );
+                    if found == 0 {
+                        messages.push("You search but find nothing. ");
+                    }
+                    turn_taken = true;
+                },
+                KEY_DISARM => {
+                    if let Some((dx, dy)) = prompt_direction("Disarm in which direction?") {
+                        let trap_x = (player.x as i64 + dx) as u64;
+                        let trap_y = (player.y as i64 + dy) as u64;
+                        match dungeon.trap_index_at(trap_x, trap_y) {
+                            Some(index) if dungeon.traps[index].revealed => {
+                                let name = TRAP_NAMES[dungeon.traps[index].kind as usize];
+                                if rng.range(DISARM_MISS_CHANCE) != 0 {
+                                    dungeon.remove_trap(index);
+                                    messages.push("You disarm the ");
+                                    messages.push(name);
+                                    messages.push(". ");
+                                    level_ups += player.gain_xp(DISARM_XP);
+                                } else if rng.range(DISARM_FAIL_CHANCE) == 0 {
+                                    messages.push("You set off the ");
+                                    messages.push(name);
+                                    messages.push("! ");
+                                    if dungeon.trigger_trap(index, &mut player, &mut rng, &mut messages) {
+                                        dungeon.depth += 1;
+                                        should_generate_dungeon = true;
+                                    }
+                                } else {
+                                    messages.push("You fail to disarm the ");
+                                    messages.push(name);
+                                    messages.push(". ");
+                                }
+                                turn_taken = true;
+                            },
+                            _ => messages.push("You don't know of any trap there. "),
+                        }
+                    }
+                },
+                KEY_INVENTORY => {
+                    show_inventory_screen(&player);
+                },
+                KEY_CHARACTER => {
+                    show_character_screen(&player, dungeon.depth);
+                    print("\nPress space to continue");
+                    loop_until_continue();
+                },
+                KEY_WAIT => {
+                    turn_taken = true;
+                },
+                KEY_HISTORY => {
+                    show_history(&log);
+                },
+                KEY_MAP => {
+                    show_map_overview(&dungeon, &player);
+                },
+                KEY_ABILITY => {
+                    let ability_name = player.class_def().ability_name;
+                    if player.ability_cooldown > 0 {
+                        messages.push(ability_name);
+                        messages.push(" will be ready in ");
+                        messages.push_number(player.ability_cooldown as u64);
+                        messages.push(" turns. ");
                     } else {
-                        print("You must find the key!");
-                        should_wait_for_continue = true;
+                        let used = match player.class {
+                            Class::Fighter => {
+                                let max_hp = player.max_hp();
+                                player.hp = core::cmp::min(player.hp + max_hp / SECOND_WIND_DIVISOR, max_hp);
+                                messages.push("You catch your second wind. ");
+                                true
+                            },
+                            Class::Rogue => {
+                                let found = dungeon.reveal_traps_near(
+                                    player.x as u64, player.y as u64, SENSE_TRAPS_RADIUS, 
+                                    &mut rng, 100, &mut messages
+                                );
+                                if found == 0 {
+                                    messages.push("You sense no traps nearby. ");
+                                }
+                                true
+                            },
+                            Class::Mage => {
+                                match prompt_direction("Cast a firebolt in which direction?") {
+                                    Some((dx, dy)) => {
+                                        let mut x = player.x as i64;
+                                        let mut y = player.y as i64;
+                                        let mut hit = false;
+                                        for _ in 0..FIREBOLT_RANGE {
+                                            x += dx;
+                                            y += dy;
+                                            if let Some(index) = dungeon.monster_index_at(x as u64, y as u64) {
+                                                // Magic ignores armour
+                                                let damage = FIREBOLT_MIN_DAMAGE + rng.range(FIREBOLT_EXTRA_DAMAGE + 1) as i32;
+                                                let name = dungeon.monsters[index].def().name;
+                                                dungeon.monsters[index].hp -= damage;
+                                                dungeon.monsters[index].awake = true;
+                                                messages.push("The firebolt burns the ");
+                                                messages.push(name);
+                                                messages.push(" for ");
+                                                messages.push_number(damage as u64);
+                                                messages.push(". ");
+                                                if dungeon.monsters[index].hp <= 0 {
+                                                    messages.push("The ");
+                                                    messages.push(name);
+                                                    messages.push(" dies. ");
+                                                    level_ups += player.gain_xp(dungeon.kill_monster(index));
+                                                }
+                                                hit = true;
+                                                break;
+                                            }
+                                            if dungeon.blocks_sight(x as u64, y as u64) {
+                                                break;
+                                            }
+                                        }
+                                        if !hit {
+                                            messages.push("The firebolt fizzles out. ");
+                                        }
+                                        true
+                                    },
+                                    None => false,
+                                }
+                            },
+                        };
+                        if used {
+                            player.ability_cooldown = player.class_def().ability_cooldown;
+                            turn_taken = true;
+                        }
                     }
                 },
-                KEY_CHAR => {
-                    player_x = target_player_x;
-                    player_y = target_player_y;
-                    print("You found the key!");
-                    has_stairs_key = true;
-                    should_wait_for_continue = true;
-                    dungeon.place_floor_tile(player_x, player_y);
-                }
                 _ => ()
             }
 
             last_key = 0;
 
-            draw_level_into_window(
-                &mut window, &dungeon.map, 
-                player_x, player_y, 
-                window_width, window_height, 
-                level_width, level_height
-            );
+            if turn_taken && !should_generate_dungeon {
+                player.energy -= ACTION_ENERGY;
+                log.flush(&mut messages, turn);
+                // The rest of the world keeps going until the player can act again
+                while player.energy < ACTION_ENERGY && player.hp > 0 {
+                    turn += 1;
+                    player.energy += player.speed();
+                    player.ability_cooldown = player.ability_cooldown.saturating_sub(1);
+                    dungeon.monsters_take_turn(&mut player, &mut rng, &mut messages);
+                    level_ups += player.gain_xp(dungeon.process_monster_statuses(&mut messages));
+                    process_player_statuses(&mut player, &mut messages);
+                    process_hunger(&mut player, difficulty, turn, &mut messages);
+                    log.flush(&mut messages, turn);
+                }
+            }
 
+            let fov_radius = if player.effects.has(StatusKind::Blindness) { 0 } else { FOV_RADIUS };
+            let newly_explored = dungeon.update_fov(player.x as u64, player.y as u64, fov_radius);
+            level_ups += player.gain_exploration_xp(newly_explored);
+
+            if level_ups > 0 && player.hp > 0 {
+                messages.push("You reach level ");
+                messages.push_number(player.level as u64);
+                messages.push("! ");
+                while level_ups > 0 {
+                    if let Some(bot) = &bot {
+                        queue_bot_key(bot.perk_key(&player));
+                    }
+                    choose_perk(&mut player);
+                    level_ups -= 1;
+                }
+            }
+            log.flush(&mut messages, turn);
+
+            let view = WindowView { player_x: player.x, player_y: player.y, width: WINDOW_WIDTH, height: WINDOW_HEIGHT };
+            draw_level_into_window(&mut window, &mut window_colours, &dungeon.map, &dungeon.explored, &view, LEVEL_WIDTH);
+            for i in 0..dungeon.num_traps {
+                let trap = &dungeon.traps[i];
+                if !trap.revealed || !dungeon.is_explored(trap.x, trap.y) {
+                    continue;
+                }
+                draw_glyph_into_window(
+                    &mut window, &mut window_colours, TRAP_CHAR, Colour::Default,
+                    trap.x as usize, trap.y as usize, &view
+                );
+            }
+            for floor_item in dungeon.floor_items().iter() {
+                if !dungeon.is_explored(floor_item.x, floor_item.y) {
+                    continue;
+                }
+                draw_glyph_into_window(
+                    &mut window, &mut window_colours, floor_item.item.glyph(), floor_item.item.def().colour,
+                    floor_item.x as usize, floor_item.y as usize, &view
+                );
+            }
+            for i in 0..dungeon.num_monsters {
+                let monster = &dungeon.monsters[i];
+                if !dungeon.is_visible(monster.x, monster.y) {
+                    continue;
+                }
+                draw_glyph_into_window(
+                    &mut window, &mut window_colours, monster.def().glyph, monster.def().colour,
+                    monster.x as usize, monster.y as usize, &view
+                );
+            }
 
-            if should_wait_for_continue {
-                loop_until_continue();
-            } 
 
             if clear_screen_enabled {
                 clear_screen();
             }
             
-            for h in 0..window_height {
-                let row_start: usize = h * window_width;
-                for w in 0..window_width {
-                    print_char(window[row_start + w]);
-                }
-                print("\n");
+            let hud = HudInfo { player: &player, depth: dungeon.depth, turn, seed, permadeath };
+            draw_screen(&window, &window_colours, WINDOW_WIDTH, WINDOW_HEIGHT, &hud);
+            draw_message_area(&mut log);
+
+            if let Some(index) = dungeon.item_index_at(player.x as u64, player.y as u64) {
+                print("You see ");
+                print_item(&dungeon.items[index].item, &player.identification);
+                print(" here.");
+            }
+
+            if player.hp <= 0 {
+                print("\nYou died on depth ");
+                print_number(dungeon.depth);
+                print(". Press space to continue");
+                loop_until_continue();
+                break;
+            }
+        }
+    }
+
+    let run = RunInfo { seed, turn, difficulty, time_mode };
+    finish_input(state_hash(&run, &dungeon, &player, &rng, &log));
+    let end = RunEnd { 
+        depth: dungeon.depth, 
+        turn, 
+        died: player.hp <= 0, 
+        death_cause: player.death_cause 
+    };
+    if playtest || bot.is_some() {
+        return end;
+    }
+
+    // Only runs that ended in death are over, quitting saves them for later
+    let mut high_score_error = None;
+    let mut delete_error = None;
+    if player.hp <= 0 {
+        // The save this run was resumed from goes first, while it's around the run could be
+        // continued and end up in the high scores twice
+        if let (Some(path), true) = (&save_path, resumed) {
+            delete_error = delete_save(path).err();
+        }
+        if let (Some(path), None) = (&high_score_path, &delete_error) {
+            let entry = HighScore::new(&player, difficulty, seed, dungeon.depth, turn);
+            let mut table = HighScoreTable::new();
+            match record_high_score(path, &entry, &mut table) {
+                Ok(rank) => show_high_scores(&table, rank),
+                Err(err) => high_score_error = Some(err),
             }
         }
     }
+
+    if clear_screen_enabled {
+        clear_screen();
+    }
+    print_run_summary(&player, dungeon.depth, turn);
+    if let Some(err) = &high_score_error {
+        print("Your run couldn't be added to the high scores. ");
+        print_high_score_error(err);
+        print("\n");
+    }
+    if let Some(err) = &delete_error {
+        print("Your save couldn't be deleted, so the run wasn't added to the high scores. ");
+        print_save_error(err);
+        print("\n");
+    }
+    if player.hp > 0 {
+        let saved = match &save_path {
+            Some(path) => save_game(path, &run, if permadeath { SAVE_FLAG_PERMADEATH } else { 0 }, &dungeon, &player, &rng, &log).map(|_| path),
+            None => Err(SaveError::NoDataDir),
+        };
+        match saved {
+            Ok(path) => {
+                print("Game saved to ");
+                print(path.as_str());
+                print("\n");
+            },
+            Err(err) => {
+                print("Your game couldn't be saved. ");
+                print_save_error(&err);
+                print("\n");
+            },
+        }
+    }
+    end
 }
 
+/// # Safety
+/// `s` has to be valid for writes of `n` bytes
+#[cfg(not(any(test, fuzzing)))]
 #[no_mangle]
 pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
     let mut i = 0;
@@ -689,6 +9084,9 @@ pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
     s
 }
 
+/// # Safety
+/// `src` has to be valid for reads and `dest` for writes of `n` bytes, and they can't overlap
+#[cfg(not(any(test, fuzzing)))]
 #[no_mangle]
 pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
     let mut i = 0;
@@ -699,48 +9097,612 @@ pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut
     dest
 }
 
+/// # Safety
+/// `s1` and `s2` have to be valid for reads of `n` bytes
+#[cfg(not(any(test, fuzzing)))]
+#[no_mangle]
+pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+    let mut i = 0;
+    while i < n {
+        let a = *s1.add(i);
+        let b = *s2.add(i);
+        if a != b {
+            return a as i32 - b as i32;
+        }
+        i += 1;
+    }
+    0
+}
+
+// Equality only version of memcmp that the compiler emits for some comparisons
+/// # Safety
+/// `s1` and `s2` have to be valid for reads of `n` bytes
+#[cfg(not(any(test, fuzzing)))]
+#[no_mangle]
+pub unsafe extern "C" fn bcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+    memcmp(s1, s2, n)
+}
+
+// The compiler turns loops that look for a null byte into calls to strlen
+/// # Safety
+/// `s` has to point to a null terminated string
+#[cfg(not(any(test, fuzzing)))]
+#[no_mangle]
+pub unsafe extern "C" fn strlen(s: *const u8) -> usize {
+    let mut n = 0;
+    while *s.add(n) != 0 {
+        n += 1;
+    }
+    n
+}
+
+#[cfg(not(any(test, fuzzing)))]
+#[unsafe(naked)]
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    unsafe {
-        asm!(
-            "and rsp, -16", // Align stack to 16 bytes
-            options(nostack)
-        );
-    }   
-    main();
+    core::arch::naked_asm!(
+        "mov rdi, rsp", // The kernel leaves argc, argv and envp on the stack
+        "and rsp, -16", // Align stack to 16 bytes
+        "call {start}",
+        start = sym start,
+    )
+}
+
+#[cfg(not(any(test, fuzzing)))]
+extern "C" fn start(stack: *const usize) -> ! {
+    let env = unsafe { Env::from_stack(stack) };
+    main(&env);
 }
 
-fn main() -> ! {
+fn print_usage() {
+    print("Usage: main [--record FILE | --replay FILE] [--map FILE]...\n");
+    print("       main --edit FILE\n");
+    print("       main --dump-map ascii|json|ppm [--seed N] [--depth N] [--min-rooms N] [--max-rooms N]\n");
+    print("                                      [--min-room-size N] [--max-room-size N]\n");
+    print("       main --gen-stats [--count N] [--csv] [--seed N] [--depth N] and the --dump-map generator options\n");
+    print("       main --check-gen [--count N] [--seed N]\n");
+    print("       main --check-gen --input FILE\n");
+    print("       main --bot [--count N] [--seed N] [--strategy dive|explore] [--class fighter|rogue|mage]\n");
+    print("                  [--goal-depth N] [--max-turns N] [--min-win-rate PERCENT]\n");
+}
+
+fn main(env: &Env) -> ! {
+    load_defs(env);
+    let mut options = LaunchOptions {
+        seed: DEFAULT_SEED,
+        playtest_map: None,
+        maps: [PathBuf::new(); MAX_CUSTOM_MAPS],
+        num_maps: 0,
+        recording: false,
+        bot: None,
+    };
+    let mut record_path = None;
+    let mut replay_path = None;
+    let mut edit_path = None;
+    if env.arg(1) == Some(b"--dump-map") {
+        run_dump_map(env);
+    }
+    if env.arg(1) == Some(b"--gen-stats") {
+        run_gen_stats(env);
+    }
+    if env.arg(1) == Some(b"--check-gen") {
+        run_check_gen(env);
+    }
+    if env.arg(1) == Some(b"--bot") {
+        run_bot(env);
+    }
+    let mut i = 1;
+    while let Some(arg) = env.arg(i) {
+        let value = env.arg(i + 1).and_then(path_from_arg);
+        if bytes_equal(arg, b"--record") && value.is_some() {
+            record_path = value;
+        } else if bytes_equal(arg, b"--replay") && value.is_some() {
+            replay_path = value;
+        } else if bytes_equal(arg, b"--edit") && value.is_some() {
+            edit_path = value;
+        } else if let Some(path) = value.filter(|_| bytes_equal(arg, b"--map") && options.num_maps < MAX_CUSTOM_MAPS) {
+            options.maps[options.num_maps] = path;
+            options.num_maps += 1;
+        } else {
+            print_usage();
+            exit(2);
+        }
+        i += 2;
+    }
+
+    // Replays only store keys, so they can't know which maps were used
+    if options.num_maps > 0 && (replay_path.is_some() || record_path.is_some()) {
+        print("--map can't be combined with --record or --replay\n");
+        exit(2);
+    }
+    // Check every map up front so a bad one doesn't turn up halfway through a run
+    for i in 0..options.num_maps {
+        let mut dungeon = GenParams::new().new_dungeon();
+        if let Err(err) = load_map_file(&options.maps[i], &mut dungeon) {
+            print(options.maps[i].as_str());
+            print(": ");
+            print_map_error(&err);
+            print("\n");
+            exit(1);
+        }
+    }
+
+    // Replays don't need a terminal, the keys come from the file
+    if let Some(path) = &replay_path {
+        match start_replay(path) {
+            Ok(seed) => options.seed = seed,
+            Err(message) => {
+                print("Couldn't replay ");
+                print(path.as_str());
+                print(": ");
+                print(message);
+                print("\n");
+                exit(1);
+            },
+        }
+        unsafe { OUTPUT_MUTED = true };
+        // finish_input exits once the game is over
+        game(env, &options);
+        exit(1);
+    }
+    options.seed = new_run_seed();
+    if let Some(path) = &record_path {
+        if let Result::Err(errno) = start_recording(path, options.seed) {
+            print("Couldn't record to ");
+            print(path.as_str());
+            print(" (errno ");
+            print_number(errno as u64);
+            print(")\n");
+            exit(1);
+        }
+        options.recording = true;
+    }
+
     let result = enable_raw_mode();
     match result {
         Result::Ok(mut orig_termios) => {
             let set_nb_result = set_non_blocking(STDIN_FILENO);
-            let orig_fcntl_flags;
-            match set_nb_result {
-                Result::Ok(flags) => orig_fcntl_flags = flags,
-                Result::Err(err) => {
+            let orig_fcntl_flags = match set_nb_result {
+                Result::Ok(flags) => flags,
+                Result::Err(_) => {
                     print("Failed to set input to non-blocking\n");
                     exit(1);
                 }
+            };
+            match &edit_path {
+                Some(path) => edit_map(env, path),
+                None => {
+                    game(env, &options);
+                },
             }
-            game();
             
             match disable_raw_mode(&mut orig_termios) {
                 Result::Ok(_) => print("Terminal returned to normal mode\n"),
-                Result::Err(err) => {
+                Result::Err(_) => {
                     print("Failed to restore terminal to normal mode\n");
                     exit(1);
                 }
             }
 
-            if let Result::Err(err) = set_blocking(STDIN_FILENO, orig_fcntl_flags) {
+            if let Result::Err(_) = set_blocking(STDIN_FILENO, orig_fcntl_flags) {
                 print("Failed to restore input to blocking\n");
             }
         },
-        Result::Err(err) => {
+        Result::Err(_) => {
             print("Failed to set terminal to raw mode\n");
             exit(1);
         }
     }
     exit(0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, RecvTimeoutError};
+    use std::sync::{Mutex, MutexGuard, Once};
+    use std::time::Duration;
+
+    // The definitions and the flood fill and save buffers are globals, so tests take turns
+    static GLOBALS: Mutex<()> = Mutex::new(());
+    static LOAD_DEFS: Once = Once::new();
+    // A floor takes milliseconds even without optimisations, one that takes this long is stuck
+    const TIMEOUT: Duration = Duration::from_secs(30);
+    // A floor is too big for the default test thread stack
+    const STACK_SIZE: usize = 64 << 20;
+    // Floors each test checks, the ignored long runs check many more
+    const QUICK_RUN: u64 = 1000;
+    const LONG_RUN: u64 = 20000;
+
+    fn setup() -> MutexGuard<'static, ()> {
+        let guard = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+        LOAD_DEFS.call_once(load_builtin_defs);
+        guard
+    }
+
+    // Runs f on its own thread, so a hang fails the test instead of stalling the whole run
+    fn with_timeout<T: Send + 'static>(what: &str, f: impl FnOnce() -> T + Send + 'static) -> T {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let _ = sender.send(f());
+            })
+            .unwrap();
+        match receiver.recv_timeout(TIMEOUT) {
+            Ok(value) => value,
+            Err(RecvTimeoutError::Timeout) => panic!("{what} didn't finish within {TIMEOUT:?}"),
+            Err(RecvTimeoutError::Disconnected) => panic!("{what} panicked"),
+        }
+    }
+
+    // Plays the seeds on the test thread's own stack, the bot doesn't touch the environment
+    fn bot_results(config: BotConfig, count: u64) -> BotResults {
+        with_timeout("the bot", move || {
+            let no_vars = [core::ptr::null::<u8>()];
+            let env = Env { argc: 0, argv: no_vars.as_ptr(), envp: no_vars.as_ptr() };
+            play_bot_runs(&env, config, DEFAULT_SEED, count)
+        })
+    }
+
+    fn describe(params: &GenParams) -> String {
+        format!(
+            "--seed {} --depth {} --min-rooms {} --max-rooms {} --min-room-size {} --max-room-size {}",
+            params.seed, params.depth, params.min_rooms, params.max_rooms, params.min_room_dim, params.max_room_dim
+        )
+    }
+
+    fn assert_floor_checks(params: GenParams) {
+        let what = describe(&params);
+        if let Err(problem) = with_timeout(&what, move || check_floor(&params.generate())) {
+            panic!("{what}: {problem}");
+        }
+    }
+
+    fn check_game_floors(count: u64) {
+        for seed in DEFAULT_SEED..DEFAULT_SEED + count {
+            let mut params = GenParams::new();
+            params.seed = seed;
+            params.depth = 1 + seed % 10;
+            assert_floor_checks(params);
+        }
+    }
+
+    fn check_random_parameters(count: u64) {
+        let mut rng = XorshiftRng::new(DEFAULT_SEED);
+        for _ in 0..count {
+            let mut bytes = [0u8; 13];
+            for byte in bytes.iter_mut() {
+                *byte = rng.range(256) as u8;
+            }
+            assert_floor_checks(params_from_bytes(&bytes));
+        }
+    }
+
+    #[test]
+    fn game_floors_pass_check() {
+        let _globals = setup();
+        check_game_floors(QUICK_RUN);
+    }
+
+    #[test]
+    #[ignore = "long run, use cargo test --release -- --ignored"]
+    fn many_game_floors_pass_check() {
+        let _globals = setup();
+        check_game_floors(LONG_RUN);
+    }
+
+    #[test]
+    fn random_parameters_pass_check() {
+        let _globals = setup();
+        check_random_parameters(QUICK_RUN);
+    }
+
+    #[test]
+    #[ignore = "long run, use cargo test --release -- --ignored"]
+    fn many_random_parameters_pass_check() {
+        let _globals = setup();
+        check_random_parameters(LONG_RUN);
+    }
+
+    #[test]
+    fn smallest_parameters_pass_check() {
+        let _globals = setup();
+        let mut params = GenParams::new();
+        params.min_rooms = 1;
+        params.max_rooms = 1;
+        params.min_room_dim = MIN_ROOM_DIM_LIMIT;
+        params.max_room_dim = MIN_ROOM_DIM_LIMIT;
+        for seed in 0..100 {
+            params.seed = seed;
+            assert_floor_checks(params);
+        }
+    }
+
+    #[test]
+    fn params_from_bytes_are_valid() {
+        let mut rng = XorshiftRng::new(DEFAULT_SEED);
+        let mut inputs = vec![vec![], vec![0; 13], vec![0xff; 13], vec![0xff; CHECK_INPUT_CAPACITY]];
+        for len in 0..2000 {
+            inputs.push((0..len % 20).map(|_| rng.range(256) as u8).collect());
+        }
+        for bytes in inputs.iter() {
+            let params = params_from_bytes(bytes);
+            if let Err(message) = params.validate() {
+                panic!("{bytes:?} gave {}: {message}", describe(&params));
+            }
+        }
+    }
+
+    #[test]
+    fn validate_rejects_bad_room_counts() {
+        let mut params = GenParams::new();
+        params.min_rooms = 0;
+        assert!(params.validate().is_err());
+        params.min_rooms = 5;
+        params.max_rooms = 4;
+        assert!(params.validate().is_err());
+        params.max_rooms = MAX_ROOMS as u64 + 1;
+        assert!(params.validate().is_err());
+        params.min_room_dim = 9;
+        params.max_room_dim = 8;
+        params.max_rooms = 5;
+        assert!(params.validate().is_err());
+        params.max_room_dim = 9;
+        assert!(params.validate().is_ok());
+    }
+
+    #[test]
+    fn empty_range_gives_min_without_drawing() {
+        let mut rng = XorshiftRng::new(DEFAULT_SEED);
+        assert_eq!(rng.range_with_min(7, 7), 7);
+        assert_eq!(rng.range_with_min(9, 3), 9);
+        assert_eq!(rng.state, DEFAULT_SEED);
+        for _ in 0..1000 {
+            assert!((3..9).contains(&rng.range_with_min(3, 9)));
+        }
+    }
+
+    #[test]
+    fn measure_floor_counts_dead_ends() {
+        let _globals = setup();
+        // The key and the far end of its corridor are dead ends, so are the stairs and the end of the
+        // top corridor
+        let map = b"#######\n#@....#\n#.##.##\n#.#K..#\n#.#####\n#S#\n###\n";
+        let dead_ends = with_timeout("measure_floor", || {
+            let mut dungeon = GenParams::new().new_dungeon();
+            assert!(dungeon.load_map(map).is_ok());
+            measure_floor(&dungeon, 0).dead_ends
+        });
+        assert_eq!(dead_ends, 4);
+    }
+
+    #[test]
+    fn dive_bot_meets_win_rate() {
+        let _globals = setup();
+        let config = BotConfig {
+            strategy: BotStrategy::Dive,
+            class: Class::Fighter,
+            goal_depth: 3,
+            max_turns: DEFAULT_BOT_MAX_TURNS,
+        };
+        let results = bot_results(config, 5);
+        assert_eq!(results.wins + results.deaths + results.gave_up(), 5);
+        assert!(results.meets_win_rate(60), "the dive bot won {} of 5 runs", results.wins);
+    }
+
+    #[test]
+    fn bot_that_gives_up_fails_win_rate() {
+        let _globals = setup();
+        let config = BotConfig {
+            strategy: BotStrategy::Explore,
+            class: Class::Fighter,
+            goal_depth: DEFAULT_BOT_GOAL_DEPTH,
+            max_turns: 1,
+        };
+        let results = bot_results(config, 2);
+        assert_eq!(results.gave_up(), 2);
+        assert!(results.meets_win_rate(0));
+        assert!(!results.meets_win_rate(1));
+    }
+
+    #[test]
+    fn bot_runs_are_repeatable() {
+        let _globals = setup();
+        let config = BotConfig {
+            strategy: BotStrategy::Explore,
+            class: Class::Mage,
+            goal_depth: DEFAULT_BOT_GOAL_DEPTH,
+            max_turns: DEFAULT_BOT_MAX_TURNS,
+        };
+        // The path search statics carry over from one run to the next
+        let first = bot_results(config, 2);
+        let second = bot_results(config, 2);
+        let summary = |results: &BotResults| (results.wins, results.deaths, results.total_depth, results.total_turns);
+        assert_eq!(summary(&first), summary(&second));
+    }
+
+    #[test]
+    fn monster_flags_are_parsed() {
+        let flags = with_timeout("parse_defs", || {
+            let mut defs = NO_DEFS;
+            assert!(parse_defs(&mut defs, DEFS_TEXT).is_ok());
+            let rat = defs.monsters[..defs.num_monsters].iter().position(|def| def.id == "rat").unwrap();
+            assert_eq!(defs.monsters[rat].flags, 0);
+            assert!(parse_defs(&mut defs, "[monster rat]\nflags = stationary awake\n").is_ok());
+            let bad_flag = parse_defs(&mut defs, "[monster rat]\nflags = flying\n");
+            assert!(matches!(bad_flag, Err(DefsError::BadValue { line: 2, .. })));
+            defs.monsters[rat].flags
+        });
+        assert_eq!(flags, MONSTER_FLAG_AWAKE | MONSTER_FLAG_STATIONARY);
+    }
+
+    #[test]
+    fn redefined_scripts_give_back_their_ops() {
+        with_timeout("parse_defs", || {
+            let mut defs = NO_DEFS;
+            assert!(parse_defs(&mut defs, "[script a]\n1 if 2 then\n[script b]\n1 if 3 else 4 then\n").is_ok());
+            let b_len = defs.scripts[1].len;
+            // More redefinitions than the pool has room for if the old ops were kept
+            for _ in 0..MAX_SCRIPT_OPS {
+                assert!(parse_defs(&mut defs, "[script a]\n1 if 5 then\n").is_ok());
+            }
+            let (a, b) = (defs.scripts[0], defs.scripts[1]);
+            assert_eq!((b.start, b.len), (0, b_len));
+            assert_eq!((a.start, a.len), (b_len, 3));
+            assert_eq!(defs.num_ops, b_len + a.len);
+            for script in [a, b] {
+                for op in defs.ops[script.start..script.start + script.len].iter() {
+                    if let Op::Jump(target) | Op::JumpIfFalse(target) = op {
+                        assert!((script.start..=script.start + script.len).contains(target));
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn scripts_stay_out_of_the_map_margin() {
+        let _globals = setup();
+        with_timeout("run_word", || {
+            let mut dungeon = GenParams::new().generate();
+            let mut player = Player::new(DEFAULT_SEED, Class::Fighter, Race::Human, b"Tester");
+            (player.x, player.y) = (dungeon.spawn_x as usize, dungeon.spawn_y as usize);
+            let mut rng = XorshiftRng::new(DEFAULT_SEED);
+            let mut messages = TurnMessages::new();
+            let rat = Value::Text(monster_defs()[0].id);
+            let mut run = |word, args: &[Value], player: &mut Player, dungeon: &mut Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>| {
+                let mut stack = ScriptStack::new();
+                for arg in args {
+                    stack.push(*arg).unwrap();
+                }
+                run_word(word, &mut stack, player, dungeon, &mut rng, &mut messages).unwrap();
+            };
+            let (margin_x, margin_y) = (WINDOW_WIDTH as i64, WINDOW_HEIGHT as i64);
+            let (width, height) = (LEVEL_WIDTH as i64, LEVEL_HEIGHT as i64);
+            let edges = [
+                (0, 0), (1, 1), (margin_x, margin_y + 5), (width - margin_x, margin_y + 5),
+                (margin_x + 5, margin_y), (margin_x + 5, height - margin_y), (width - 1, height - 1),
+            ];
+            for (x, y) in edges {
+                let (spawn_x, spawn_y) = (player.x, player.y);
+                let num_monsters = dungeon.num_monsters;
+                run(Word::SetTile, &[Value::Number(x), Value::Number(y), Value::Text(".")], &mut player, &mut dungeon);
+                run(Word::Teleport, &[Value::Number(x), Value::Number(y)], &mut player, &mut dungeon);
+                run(Word::Spawn, &[rat, Value::Number(x), Value::Number(y)], &mut player, &mut dungeon);
+                assert_eq!(dungeon.check_collision(x as u64, y as u64), WALL_CHAR, "set_tile at ({x}, {y})");
+                assert_eq!((player.x, player.y), (spawn_x, spawn_y), "teleport to ({x}, {y})");
+                assert_eq!(dungeon.num_monsters, num_monsters, "spawn at ({x}, {y})");
+            }
+            // The first tile past the margin is fair game
+            let (x, y) = (margin_x + 1, margin_y + 1);
+            run(Word::SetTile, &[Value::Number(x), Value::Number(y), Value::Text(".")], &mut player, &mut dungeon);
+            run(Word::Teleport, &[Value::Number(x), Value::Number(y)], &mut player, &mut dungeon);
+            assert_eq!((player.x as i64, player.y as i64), (x, y));
+        });
+    }
+
+    // A fresh floor with the player standing on its spawn
+    fn new_run() -> (Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>, Player) {
+        let dungeon = GenParams::new().generate();
+        let mut player = Player::new(DEFAULT_SEED, Class::Fighter, Race::Human, b"Tester");
+        (player.x, player.y) = (dungeon.spawn_x as usize, dungeon.spawn_y as usize);
+        (dungeon, player)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let file = format!("{}/rusty-dungeon-{}-{name}.bin", std::env::temp_dir().display(), std::process::id());
+        let mut path = PathBuf::new();
+        assert!(path.push(file.as_bytes()));
+        path
+    }
+
+    fn save_and_load(path: &PathBuf, dungeon: &Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>, player: &Player)
+        -> core::result::Result<(Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>, Player), SaveError>
+    {
+        let run = RunInfo { seed: DEFAULT_SEED, turn: 1, difficulty: Difficulty::Normal, time_mode: TimeMode::TurnBased };
+        let rng = XorshiftRng::new(DEFAULT_SEED);
+        assert!(save_game(path, &run, 0, dungeon, player, &rng, &MessageLog::new()).is_ok());
+        let mut loaded_dungeon = GenParams::new().new_dungeon();
+        let mut loaded_player = Player::new(0, Class::Mage, Race::Human, b"");
+        let mut loaded_rng = XorshiftRng::new(0);
+        let mut log = MessageLog::new();
+        load_game(path, &mut loaded_dungeon, &mut loaded_player, &mut loaded_rng, &mut log)?;
+        Ok((loaded_dungeon, loaded_player))
+    }
+
+    #[test]
+    fn saves_load_back() {
+        let _globals = setup();
+        with_timeout("save_game", || {
+            let path = temp_path("round-trip");
+            let (dungeon, player) = new_run();
+            let loaded = save_and_load(&path, &dungeon, &player);
+            let _ = std::fs::remove_file(path.as_str());
+            let Ok((loaded_dungeon, loaded_player)) = loaded else {
+                panic!("the save didn't load");
+            };
+            assert_eq!((loaded_player.x, loaded_player.y), (player.x, player.y));
+            assert_eq!(loaded_player.name[..loaded_player.name_len], player.name[..player.name_len]);
+            assert_eq!(loaded_dungeon.map, dungeon.map);
+            assert_eq!(loaded_dungeon.depth, dungeon.depth);
+            assert_eq!((loaded_dungeon.key_x, loaded_dungeon.key_y), (dungeon.key_x, dungeon.key_y));
+            assert_eq!(loaded_dungeon.num_items, dungeon.num_items);
+            assert_eq!(loaded_dungeon.num_monsters, dungeon.num_monsters);
+        });
+    }
+
+    // Breaks something about a run before it's saved
+    type Tamper = fn(&mut Dungeon<LEVEL_WIDTH, LEVEL_HEIGHT>, &mut Player);
+
+    #[test]
+    fn saves_with_positions_off_the_floor_are_corrupt() {
+        let _globals = setup();
+        with_timeout("load_game", || {
+            let tampering: [(&str, Tamper); 8] = [
+                ("player", |_, player| player.x = 1),
+                ("floor item", |dungeon, _| assert!(dungeon.add_floor_item(1, 1, Item::new(ItemKind::Gold, 1)))),
+                ("monster", |dungeon, _| assert!(dungeon.add_monster(Monster::new(MonsterKind(0), LEVEL_WIDTH as u64, 1)))),
+                ("trap", |dungeon, _| {
+                    dungeon.traps[dungeon.num_traps] = Trap { x: 1, y: u64::MAX, kind: TrapKind::Spike, revealed: false };
+                    dungeon.num_traps += 1;
+                }),
+                ("spawn", |dungeon, _| dungeon.spawn_x = 0),
+                ("stairs", |dungeon, _| dungeon.stairs_y = LEVEL_HEIGHT as u64 - 1),
+                ("key", |dungeon, _| dungeon.key_x = WINDOW_WIDTH as u64),
+                ("depth", |dungeon, _| dungeon.depth = 0),
+            ];
+            let path = temp_path("tampered");
+            for (what, tamper) in tampering {
+                let (mut dungeon, mut player) = new_run();
+                tamper(&mut dungeon, &mut player);
+                let loaded = save_and_load(&path, &dungeon, &player);
+                assert!(matches!(loaded, Err(SaveError::Corrupt)), "a save with a bad {what} loaded");
+            }
+            let _ = std::fs::remove_file(path.as_str());
+        });
+    }
+
+    #[test]
+    fn high_scores_append_past_a_torn_record() {
+        let _globals = setup();
+        with_timeout("record_high_score", || {
+            let path = temp_path("scores");
+            let (dungeon, player) = new_run();
+            let entry = HighScore::new(&player, Difficulty::Normal, DEFAULT_SEED, dungeon.depth, 1);
+            let mut record = [0u8; HIGH_SCORE_RECORD_SIZE];
+            entry.write(&mut record);
+            let mut torn = record.to_vec();
+            torn.extend_from_slice(&record[..HIGH_SCORE_RECORD_SIZE / 2]);
+            std::fs::write(path.as_str(), &torn).unwrap();
+            let recorded = record_high_score(&path, &entry, &mut HighScoreTable::new());
+            let mut table = HighScoreTable::new();
+            let read = read_high_scores(&path, &mut table);
+            let file_len = std::fs::metadata(path.as_str()).map(|metadata| metadata.len());
+            let _ = std::fs::remove_file(path.as_str());
+            assert!(recorded.is_ok() && read.is_ok());
+            assert_eq!(file_len.unwrap(), 2 * HIGH_SCORE_RECORD_SIZE as u64);
+            assert_eq!(table.total_runs, 2);
+        });
+    }
+}